@@ -0,0 +1,14 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // use a vendored protoc, so building does not depend on one being installed
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+
+    tonic_prost_build::configure().compile_protos(
+        &[
+            "proto/envoy/service/ratelimit/v3/rls.proto",
+            "proto/pyre/v1/pyre.proto",
+        ],
+        &["proto"],
+    )?;
+
+    Ok(())
+}
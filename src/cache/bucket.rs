@@ -0,0 +1,509 @@
+use super::{Limit, Limiter, Status};
+
+/// TokenBucket holds up to Limit::capacity tokens, refilled continuously at count tokens per
+/// window. Every allowed hit spends a token, so idle keys save up capacity for bursts while
+/// sustained throughput is capped at the refill rate.
+#[derive(Debug)]
+pub struct TokenBucket {
+    tokens: f64,
+    // when tokens was last refilled, in milliseconds
+    updated: u64,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u64, ts: u64) -> Self {
+        Self {
+            tokens: capacity as f64,
+            updated: ts,
+        }
+    }
+
+    fn available(&self, limit: &Limit, ts: u64) -> f64 {
+        let capacity = limit.capacity() as f64;
+        if limit.window == 0 {
+            return capacity;
+        }
+
+        let elapsed = ts.saturating_sub(self.updated) as f64;
+        let refill = elapsed * limit.count as f64 / limit.window as f64;
+        (self.tokens + refill).min(capacity)
+    }
+
+    // how long until the bucket holds tokens, in milliseconds
+    fn refill(limit: &Limit, tokens: f64) -> u64 {
+        (tokens.max(0.0) * limit.window as f64 / limit.count as f64).ceil() as u64
+    }
+
+    fn status(&self, limit: &Limit, ts: u64, tokens: f64, cost: u64, allowed: bool) -> Status {
+        let missing = limit.capacity() as f64 - tokens;
+
+        Status {
+            allowed,
+            count: missing.ceil() as u64,
+            remaining: tokens.floor() as u64,
+            reset: self.updated.max(ts).saturating_add(TokenBucket::refill(limit, missing)),
+            retry_after: match allowed {
+                true => 0,
+                false => TokenBucket::refill(limit, cost as f64 - tokens),
+            },
+            ..Default::default()
+        }
+    }
+}
+
+impl Limiter for TokenBucket {
+    fn create(limit: &Limit) -> Self {
+        TokenBucket::new(limit.capacity(), 0)
+    }
+
+    fn hit(&mut self, limit: &Limit, ts: u64, cost: u64) -> Status {
+        self.tokens = self.available(limit, ts);
+        self.updated = self.updated.max(ts);
+
+        let allowed = self.tokens >= cost as f64;
+        if allowed {
+            self.tokens -= cost as f64;
+        }
+
+        self.status(limit, ts, self.tokens, cost, allowed)
+    }
+
+    fn peek(&self, limit: &Limit, ts: u64, cost: u64) -> Status {
+        let tokens = self.available(limit, ts);
+        self.status(limit, ts, tokens, cost, tokens >= cost as f64)
+    }
+
+    fn refund(&mut self, limit: &Limit, ts: u64, cost: u64) {
+        self.tokens = (self.available(limit, ts) + cost as f64).min(limit.capacity() as f64);
+        self.updated = self.updated.max(ts);
+    }
+
+    // a bucket idle for the whole TTL is assumed to have refilled, and is dropped so a new full
+    // one is created on the next hit
+    fn expire(&mut self, ts: u64, ttl: u64) -> bool {
+        self.updated.saturating_add(ttl) < ts
+    }
+}
+
+/// LeakyBucket shapes traffic rather than policing it: hits are queued and drain at count hits
+/// per window, and each allowed hit is told how long to wait for its turn. Hits are only denied
+/// when their delay would exceed Limit::queue, so bursts are smoothed out instead of dropped.
+#[derive(Debug)]
+pub struct LeakyBucket {
+    // when the queue has drained, in microseconds so that intervals under a millisecond are not
+    // truncated
+    next: u64,
+}
+
+impl LeakyBucket {
+    pub fn new(next: u64) -> Self {
+        Self { next }
+    }
+
+    // time between hits leaving the queue, in microseconds
+    fn interval(limit: &Limit) -> u64 {
+        match limit.count {
+            0 => u64::MAX,
+            c => (limit.window * 1000 / c).max(1),
+        }
+    }
+
+    // the delay a hit at now would have, if one is allowed at all
+    fn delay(&self, limit: &Limit, now: u64) -> Option<u64> {
+        let delay = self.next.saturating_sub(now);
+        match limit.count > 0 && delay <= limit.queue() * 1000 {
+            true => Some(delay),
+            false => None,
+        }
+    }
+
+    fn status(&self, limit: &Limit, now: u64, delay: Option<u64>) -> Status {
+        let interval = LeakyBucket::interval(limit);
+        let queued = self.next.saturating_sub(now);
+        let remaining = match (limit.queue() * 1000).checked_sub(queued) {
+            Some(d) if limit.count > 0 => d / interval + 1,
+            _ => 0,
+        };
+
+        Status {
+            allowed: delay.is_some(),
+            count: queued.div_ceil(interval),
+            remaining,
+            reset: self.next.max(now).div_ceil(1000),
+            delay: delay.map(|d| d.div_ceil(1000)),
+            retry_after: match delay {
+                Some(_) => 0,
+                None => queued.saturating_sub(limit.queue() * 1000).div_ceil(1000),
+            },
+            ..Default::default()
+        }
+    }
+}
+
+impl Limiter for LeakyBucket {
+    fn create(_limit: &Limit) -> Self {
+        LeakyBucket::new(0)
+    }
+
+    fn hit(&mut self, limit: &Limit, ts: u64, cost: u64) -> Status {
+        let now = ts * 1000;
+        let delay = self.delay(limit, now);
+        if let Some(d) = delay {
+            self.next = (now + d).saturating_add(LeakyBucket::interval(limit).saturating_mul(cost));
+        }
+
+        self.status(limit, now, delay)
+    }
+
+    // every hit waits for the queue ahead of it, regardless of its own cost
+    fn peek(&self, limit: &Limit, ts: u64, _cost: u64) -> Status {
+        let now = ts * 1000;
+        self.status(limit, now, self.delay(limit, now))
+    }
+
+    // the queue can only drain as far as now, as hits that already left cannot be refunded
+    fn refund(&mut self, limit: &Limit, ts: u64, cost: u64) {
+        let refund = LeakyBucket::interval(limit).saturating_mul(cost);
+        self.next = self.next.saturating_sub(refund).max(ts * 1000);
+    }
+
+    // an empty queue is indistinguishable from a new one, so it can be dropped regardless of TTL
+    fn expire(&mut self, ts: u64, _ttl: u64) -> bool {
+        self.next <= ts * 1000
+    }
+}
+
+#[cfg(test)]
+mod bucket_tests {
+
+    use super::*;
+
+    const LIMIT: Limit = Limit {
+        count: 10,
+        window: 1000,
+        burst: 0,
+        max_delay: 0,
+        windows: Vec::new(),
+    };
+
+    #[test]
+    fn test_create() {
+        let bucket = TokenBucket::create(&LIMIT);
+        assert_eq!(bucket.tokens, 10.0);
+
+        let bucket = TokenBucket::create(&Limit { burst: 50, ..LIMIT });
+        assert_eq!(bucket.tokens, 50.0);
+    }
+
+    // testcase-based rather than macro-based to simplify state across cases
+    #[test]
+    fn test_hit() {
+        struct TestCase {
+            name: &'static str,
+            ts: u64,
+            hits: u64,
+            allowed: bool,
+            count: u64,
+            remaining: u64,
+            reset: u64,
+            retry_after: u64,
+        }
+
+        let limit = Limit { burst: 5, ..LIMIT };
+        let mut bucket = TokenBucket::create(&limit);
+        let testcases = vec![
+            TestCase {
+                name: "spend part of burst",
+                ts: 1000,
+                hits: 3,
+                allowed: true,
+                count: 3,
+                remaining: 2,
+                reset: 1300,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "spend rest of burst",
+                ts: 1000,
+                hits: 2,
+                allowed: true,
+                count: 5,
+                remaining: 0,
+                reset: 1500,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "empty bucket",
+                ts: 1050,
+                hits: 1,
+                allowed: false,
+                count: 5,
+                remaining: 0,
+                reset: 1500,
+                retry_after: 50,
+            },
+            TestCase {
+                name: "refilled one token",
+                ts: 1100,
+                hits: 1,
+                allowed: true,
+                count: 5,
+                remaining: 0,
+                reset: 1600,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "refill capped at capacity",
+                ts: 60_000,
+                hits: 1,
+                allowed: true,
+                count: 1,
+                remaining: 4,
+                reset: 60_100,
+                retry_after: 0,
+            },
+        ];
+
+        for tc in testcases {
+            let mut status = Status::default();
+            for _ in 0..tc.hits {
+                status = bucket.hit(&limit, tc.ts, 1);
+            }
+
+            assert_eq!(
+                status,
+                Status {
+                    allowed: tc.allowed,
+                    count: tc.count,
+                    remaining: tc.remaining,
+                    reset: tc.reset,
+                    retry_after: tc.retry_after,
+                    ..Default::default()
+                },
+                "status did not match for {}",
+                tc.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_cost() {
+        let mut bucket = TokenBucket::create(&LIMIT);
+
+        assert_eq!(bucket.hit(&LIMIT, 1000, 4).remaining, 6, "spent several tokens");
+        assert!(!bucket.hit(&LIMIT, 1000, 7).allowed, "cost over remaining tokens");
+        assert!(!bucket.peek(&LIMIT, 1000, 7).allowed, "peek cost over remaining tokens");
+        assert_eq!(bucket.hit(&LIMIT, 1000, 6).remaining, 0, "spent remaining tokens");
+    }
+
+    #[test]
+    fn test_refund() {
+        let mut bucket = TokenBucket::create(&LIMIT);
+        bucket.hit(&LIMIT, 1000, 10);
+
+        bucket.refund(&LIMIT, 1000, 4);
+        assert_eq!(bucket.peek(&LIMIT, 1000, 1).remaining, 4, "refunded tokens");
+
+        bucket.refund(&LIMIT, 1000, 20);
+        assert_eq!(bucket.peek(&LIMIT, 1000, 1).remaining, 10, "refund capped at capacity");
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut bucket = TokenBucket::create(&LIMIT);
+        for _ in 0..10 {
+            bucket.hit(&LIMIT, 1000, 1);
+        }
+
+        assert!(!bucket.peek(&LIMIT, 1000, 1).allowed, "empty bucket");
+        assert!(bucket.peek(&LIMIT, 1100, 1).allowed, "refilled bucket");
+        assert_eq!(bucket.tokens, 0.0, "peek spent a token");
+    }
+
+    macro_rules! bucket_expire_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                fn $name() {
+                    let (updated, now, ttl, expired) = $value;
+
+                    let mut bucket = TokenBucket::new(10, updated);
+                    assert_eq!(bucket.expire(now, ttl), expired);
+                }
+            )*
+        }
+    }
+
+    bucket_expire_tests! {
+        bucket_expire_idle: (10_000, 50_000, 30_000, true),
+        bucket_expire_recent: (30_000, 50_000, 30_000, false),
+        bucket_expire_edge: (20_000, 50_000, 30_000, false),
+    }
+}
+
+#[cfg(test)]
+mod leaky_bucket_tests {
+
+    use super::*;
+
+    const LIMIT: Limit = Limit {
+        count: 10,
+        window: 1000,
+        burst: 0,
+        max_delay: 250,
+        windows: Vec::new(),
+    };
+
+    // testcase-based rather than macro-based to simplify state across cases
+    #[test]
+    fn test_hit() {
+        struct TestCase {
+            name: &'static str,
+            ts: u64,
+            count: u64,
+            remaining: u64,
+            reset: u64,
+            retry_after: u64,
+            delay: Option<u64>,
+        }
+
+        let mut bucket = LeakyBucket::create(&LIMIT);
+        let testcases = vec![
+            TestCase {
+                name: "empty queue",
+                ts: 1000,
+                count: 1,
+                remaining: 2,
+                reset: 1100,
+                retry_after: 0,
+                delay: Some(0),
+            },
+            TestCase {
+                name: "queued behind one hit",
+                ts: 1000,
+                count: 2,
+                remaining: 1,
+                reset: 1200,
+                retry_after: 0,
+                delay: Some(100),
+            },
+            TestCase {
+                name: "queued behind two hits",
+                ts: 1000,
+                count: 3,
+                remaining: 0,
+                reset: 1300,
+                retry_after: 0,
+                delay: Some(200),
+            },
+            TestCase {
+                name: "delay would exceed max",
+                ts: 1000,
+                count: 3,
+                remaining: 0,
+                reset: 1300,
+                retry_after: 50,
+                delay: None,
+            },
+            TestCase {
+                name: "queue partly drained",
+                ts: 1150,
+                count: 3,
+                remaining: 1,
+                reset: 1400,
+                retry_after: 0,
+                delay: Some(150),
+            },
+            TestCase {
+                name: "queue drained",
+                ts: 5000,
+                count: 1,
+                remaining: 2,
+                reset: 5100,
+                retry_after: 0,
+                delay: Some(0),
+            },
+        ];
+
+        for tc in testcases {
+            let status = bucket.hit(&LIMIT, tc.ts, 1);
+            assert_eq!(
+                status,
+                Status {
+                    allowed: tc.delay.is_some(),
+                    count: tc.count,
+                    remaining: tc.remaining,
+                    reset: tc.reset,
+                    retry_after: tc.retry_after,
+                    delay: tc.delay,
+                    ..Default::default()
+                },
+                "status did not match for {}",
+                tc.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_cost() {
+        let mut bucket = LeakyBucket::create(&LIMIT);
+
+        assert_eq!(bucket.hit(&LIMIT, 1000, 3).delay, Some(0), "empty queue");
+        assert_eq!(bucket.hit(&LIMIT, 1000, 1).delay, None, "queued behind cost");
+        assert_eq!(bucket.hit(&LIMIT, 1100, 1).delay, Some(200), "queue partly drained");
+    }
+
+    #[test]
+    fn test_refund() {
+        let mut bucket = LeakyBucket::create(&LIMIT);
+        bucket.hit(&LIMIT, 1000, 3);
+
+        bucket.refund(&LIMIT, 1000, 2);
+        assert_eq!(bucket.peek(&LIMIT, 1000, 1).delay, Some(100), "refunded queue");
+
+        bucket.refund(&LIMIT, 1000, 5);
+        assert_eq!(bucket.next, 1_000_000, "refund past now");
+    }
+
+    #[test]
+    fn test_default_max_delay() {
+        let limit = Limit { max_delay: 0, ..LIMIT };
+        let mut bucket = LeakyBucket::create(&limit);
+        for _ in 0..11 {
+            assert!(bucket.hit(&limit, 1000, 1).allowed, "queued within a window");
+        }
+
+        assert_eq!(bucket.hit(&limit, 1000, 1).delay, None, "queued past a window");
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut bucket = LeakyBucket::create(&LIMIT);
+        bucket.hit(&LIMIT, 1000, 1);
+        bucket.hit(&LIMIT, 1000, 1);
+
+        assert_eq!(bucket.peek(&LIMIT, 1000, 1).delay, Some(200), "queued hits");
+        assert_eq!(bucket.peek(&LIMIT, 1300, 1).delay, Some(0), "drained queue");
+        assert_eq!(bucket.next, 1_200_000, "peek queued a hit");
+    }
+
+    macro_rules! leaky_bucket_expire_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                fn $name() {
+                    let (next, now, expired) = $value;
+
+                    let mut bucket = LeakyBucket::new(next);
+                    assert_eq!(bucket.expire(now, 30_000), expired);
+                }
+            )*
+        }
+    }
+
+    leaky_bucket_expire_tests! {
+        leaky_bucket_expire_drained: (40_000_000, 50_000, true),
+        leaky_bucket_expire_queued: (50_100_000, 50_000, false),
+        leaky_bucket_expire_edge: (50_000_000, 50_000, true),
+    }
+}
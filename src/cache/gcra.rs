@@ -0,0 +1,270 @@
+use super::{Limit, Limiter, Status};
+
+/// Gcra implements the generic cell rate algorithm, keeping only the theoretical arrival time
+/// (TAT) of the next hit. Hits are spaced an emission interval of window / count apart, and up to
+/// Limit::capacity hits can arrive ahead of schedule. This gives smooth, exact enforcement with a
+/// single u64 per key.
+#[derive(Debug)]
+pub struct Gcra {
+    // theoretical arrival time, in microseconds so that emission intervals under a millisecond
+    // are not truncated
+    tat: u64,
+}
+
+impl Gcra {
+    pub fn new(tat: u64) -> Self {
+        Self { tat }
+    }
+
+    // emission interval and burst tolerance, in microseconds
+    fn interval(limit: &Limit) -> (u64, u64) {
+        let interval = match limit.count {
+            0 => u64::MAX,
+            c => (limit.window * 1000 / c).max(1),
+        };
+
+        (
+            interval,
+            interval.saturating_mul(limit.capacity().saturating_sub(1)),
+        )
+    }
+
+    // how long until a hit is allowed, in microseconds - the last of the cost intervals it spends
+    // has to arrive within the burst tolerance
+    fn wait(limit: &Limit, tat: u64, now: u64, cost: u64) -> u64 {
+        let (interval, tolerance) = Gcra::interval(limit);
+        let spent = interval.saturating_mul(cost.saturating_sub(1));
+
+        (tat.max(now) - now).saturating_add(spent).saturating_sub(tolerance)
+    }
+
+    fn status(&self, limit: &Limit, now: u64, wait: u64) -> Status {
+        let (interval, _) = Gcra::interval(limit);
+        let count = self.tat.saturating_sub(now).div_ceil(interval);
+
+        Status {
+            allowed: limit.count > 0 && wait == 0,
+            count,
+            remaining: limit.capacity().saturating_sub(count),
+            reset: self.tat.max(now).div_ceil(1000),
+            retry_after: wait.div_ceil(1000),
+            ..Default::default()
+        }
+    }
+}
+
+impl Limiter for Gcra {
+    fn create(_limit: &Limit) -> Self {
+        Gcra::new(0)
+    }
+
+    fn hit(&mut self, limit: &Limit, ts: u64, cost: u64) -> Status {
+        let now = ts * 1000;
+        let wait = Gcra::wait(limit, self.tat, now, cost);
+        if limit.count > 0 && wait == 0 {
+            let (interval, _) = Gcra::interval(limit);
+            self.tat = self.tat.max(now).saturating_add(interval.saturating_mul(cost));
+        }
+
+        self.status(limit, now, wait)
+    }
+
+    fn peek(&self, limit: &Limit, ts: u64, cost: u64) -> Status {
+        let now = ts * 1000;
+        self.status(limit, now, Gcra::wait(limit, self.tat, now, cost))
+    }
+
+    // the TAT is never moved before now, which is where a key with no hits counted is
+    fn refund(&mut self, limit: &Limit, ts: u64, cost: u64) {
+        let (interval, _) = Gcra::interval(limit);
+        self.tat = self.tat.saturating_sub(interval.saturating_mul(cost)).max(ts * 1000);
+    }
+
+    // once the TAT has passed, the key is indistinguishable from a new one, so it can be dropped
+    // regardless of TTL
+    fn expire(&mut self, ts: u64, _ttl: u64) -> bool {
+        self.tat <= ts * 1000
+    }
+}
+
+#[cfg(test)]
+mod gcra_tests {
+
+    use super::*;
+
+    const LIMIT: Limit = Limit {
+        count: 10,
+        window: 1000,
+        burst: 0,
+        max_delay: 0,
+        windows: Vec::new(),
+    };
+
+    #[test]
+    fn test_interval() {
+        assert_eq!(Gcra::interval(&LIMIT), (100_000, 900_000));
+        assert_eq!(
+            Gcra::interval(&Limit { burst: 1, ..LIMIT }),
+            (100_000, 0)
+        );
+        assert_eq!(
+            Gcra::interval(&Limit {
+                count: 3_000_000,
+                ..LIMIT
+            }),
+            (1, 2_999_999)
+        );
+    }
+
+    // testcase-based rather than macro-based to simplify state across cases
+    #[test]
+    fn test_hit() {
+        struct TestCase {
+            name: &'static str,
+            ts: u64,
+            hits: u64,
+            allowed: bool,
+            count: u64,
+            remaining: u64,
+            reset: u64,
+            retry_after: u64,
+        }
+
+        let limit = Limit { burst: 3, ..LIMIT };
+        let mut gcra = Gcra::create(&limit);
+        let testcases = vec![
+            TestCase {
+                name: "first hit",
+                ts: 1000,
+                hits: 1,
+                allowed: true,
+                count: 1,
+                remaining: 2,
+                reset: 1100,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "spend rest of burst",
+                ts: 1000,
+                hits: 2,
+                allowed: true,
+                count: 3,
+                remaining: 0,
+                reset: 1300,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "burst exhausted",
+                ts: 1050,
+                hits: 1,
+                allowed: false,
+                count: 3,
+                remaining: 0,
+                reset: 1300,
+                retry_after: 50,
+            },
+            TestCase {
+                name: "one emission interval later",
+                ts: 1100,
+                hits: 1,
+                allowed: true,
+                count: 3,
+                remaining: 0,
+                reset: 1400,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "long after TAT",
+                ts: 10_000,
+                hits: 1,
+                allowed: true,
+                count: 1,
+                remaining: 2,
+                reset: 10_100,
+                retry_after: 0,
+            },
+        ];
+
+        for tc in testcases {
+            let mut status = Status::default();
+            for _ in 0..tc.hits {
+                status = gcra.hit(&limit, tc.ts, 1);
+            }
+
+            assert_eq!(
+                status,
+                Status {
+                    allowed: tc.allowed,
+                    count: tc.count,
+                    remaining: tc.remaining,
+                    reset: tc.reset,
+                    retry_after: tc.retry_after,
+                    ..Default::default()
+                },
+                "status did not match for {}",
+                tc.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_cost() {
+        let mut gcra = Gcra::create(&LIMIT);
+
+        assert_eq!(gcra.hit(&LIMIT, 1000, 4).remaining, 6, "spent several intervals");
+        assert!(!gcra.hit(&LIMIT, 1000, 7).allowed, "cost over burst tolerance");
+        assert!(!gcra.peek(&LIMIT, 1000, 7).allowed, "peek cost over burst tolerance");
+        assert_eq!(gcra.hit(&LIMIT, 1000, 6).remaining, 0, "spent remaining intervals");
+    }
+
+    #[test]
+    fn test_refund() {
+        let mut gcra = Gcra::create(&LIMIT);
+        gcra.hit(&LIMIT, 1000, 10);
+
+        gcra.refund(&LIMIT, 1000, 4);
+        assert_eq!(gcra.peek(&LIMIT, 1000, 1).remaining, 4, "refunded intervals");
+
+        gcra.refund(&LIMIT, 1000, 20);
+        assert_eq!(gcra.tat, 1_000_000, "refund past now");
+    }
+
+    #[test]
+    fn test_peek() {
+        let limit = Limit { burst: 1, ..LIMIT };
+        let mut gcra = Gcra::create(&limit);
+        gcra.hit(&limit, 1000, 1);
+
+        assert!(!gcra.peek(&limit, 1050, 1).allowed, "within interval");
+        assert!(gcra.peek(&limit, 1100, 1).allowed, "after interval");
+        assert_eq!(gcra.tat, 1_100_000, "peek moved TAT");
+    }
+
+    #[test]
+    fn test_zero_count() {
+        let limit = Limit { count: 0, ..LIMIT };
+        let mut gcra = Gcra::create(&limit);
+        assert!(!gcra.hit(&limit, 1000, 1).allowed);
+        assert!(!gcra.peek(&limit, 1000, 1).allowed);
+    }
+
+    macro_rules! gcra_expire_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                fn $name() {
+                    let (tat, now, expired) = $value;
+
+                    let mut gcra = Gcra::new(tat);
+                    assert_eq!(gcra.expire(now, 30_000), expired);
+                }
+            )*
+        }
+    }
+
+    gcra_expire_tests! {
+        gcra_expire_passed: (10_000_000, 50_000, true),
+        gcra_expire_edge: (50_000_000, 50_000, true),
+        gcra_expire_pending: (50_100_000, 50_000, false),
+    }
+}
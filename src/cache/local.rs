@@ -1,19 +1,25 @@
-use super::CacheError;
+use super::{Cache, CacheError, Limit, Limiter, Status};
 use std::collections::{BTreeMap, HashMap};
 use std::ops::Index;
-use std::sync::{atomic::AtomicU64, atomic::Ordering::Relaxed, Arc, Mutex};
+#[cfg(target_os = "macos")]
+use std::sync::Mutex;
+#[cfg(not(target_os = "macos"))]
+use std::sync::RwLock;
+use std::sync::{atomic::AtomicU64, atomic::Ordering::Relaxed, Arc};
 use std::time;
-use tokio;
 
 pub const DEFAULT_PARTITIONS: u32 = 1024;
 pub const DEFAULT_TTL: u64 = 300;
 pub const DEFAULT_SWEEP: u64 = 60;
+// how often the clock is refreshed, in milliseconds
+pub const CLOCK_RESOLUTION: u64 = 10;
 
 #[derive(Debug)]
-pub struct Local {
+pub struct Local<V: Limiter> {
     partition_count: u32,
-    ttl: u64,
-    sweep: u64,
+    // seconds, which can be updated in place on reload
+    ttl: AtomicU64,
+    sweep: AtomicU64,
     /*  we want to use a Mutex for better overall performance on OS X - this is due to
         platform-specific differences in how pthread_rwlock works, which is used internally
         (see https://stdrs.dev/nightly/x86_64-apple-darwin/std/sys/unix/locks/pthread_rwlock/struct.AllocatedRwLock.html).
@@ -22,16 +28,16 @@ pub struct Local {
         Linux and other *nix platforms work better with a pthread_rwlock.
     */
     #[cfg(target_os = "macos")]
-    partitions: Vec<Mutex<KeyMap>>,
+    partitions: Vec<Mutex<KeyMap<V>>>,
     #[cfg(not(target_os = "macos"))]
-    partitions: Vec<RwLock<KeyMap>>,
+    partitions: Vec<RwLock<KeyMap<V>>>,
+    // milliseconds since UNIX 0, refreshed every CLOCK_RESOLUTION
     clock: AtomicU64,
 }
 
-#[derive(Default, Debug)]
-pub struct KeyMap {
-    window: u64,
-    ttls: HashMap<String, TTLValues>,
+#[derive(Debug)]
+pub struct KeyMap<V> {
+    ttls: HashMap<String, V>,
 }
 
 pub struct Key<'a> {
@@ -39,12 +45,23 @@ pub struct Key<'a> {
     ts: u64,
 }
 
+/// TTLValues counts hits in buckets as wide as the window a key was created with. The width is fixed
+/// from then on, so a changed window or override only applies to existing keys once they expire.
 #[derive(Debug)]
 pub struct TTLValues {
     window: u64,
     vals: BTreeMap<u64, u64>,
 }
 
+/// FixedWindow counts hits in windows aligned to UNIX 0, so windows of a minute, hour or day line
+/// up with UTC boundaries and reset at a known time. Denied hits are not counted.
+#[derive(Debug, Default)]
+pub struct FixedWindow {
+    // start of the current window, in milliseconds
+    start: u64,
+    count: u64,
+}
+
 impl std::fmt::Display for CacheError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "error internal to local cache: {}", self.msg)
@@ -74,19 +91,62 @@ impl TTLValues {
         self.vals.iter().fold(0, |accum, (_, v)| accum + *v)
     }
 
-    pub fn inc(&mut self, val: u64) -> u64 {
+    // count is the hits in buckets whose window has not ended by ts, as older buckets linger until
+    // the LRU drops them
+    fn count(&self, ts: u64) -> u64 {
+        self.vals
+            .iter()
+            .filter(|(k, _)| *k + self.window > ts)
+            .fold(0, |accum, (_, v)| accum + *v)
+    }
+
+    pub fn inc(&mut self, val: u64, cost: u64) -> u64 {
         let bucket = self.find_bucket(val);
-        let updated = self.get_inner(bucket) + 1;
+        let updated = self.get_inner(bucket) + cost;
 
         self.vals.insert(bucket, updated);
         updated
     }
 
-    pub fn inc_and_get(&mut self, val: u64) -> u64 {
-        self.inc(val);
+    // dec takes cost off the newest buckets first, as refunds are for recent hits
+    pub fn dec(&mut self, cost: u64) -> u64 {
+        let mut left = cost;
+        for v in self.vals.values_mut().rev() {
+            let taken = left.min(*v);
+            *v -= taken;
+            left -= taken;
+            if left == 0 {
+                break;
+            }
+        }
+
         self.get()
     }
 
+    // the end of the latest bucket, or ts if it has already ended
+    fn reset(&self, ts: u64) -> u64 {
+        match self.vals.keys().next_back() {
+            Some(k) => (k + self.window).max(ts),
+            None => ts,
+        }
+    }
+
+    // only the latest bucket can still be counting, so a denied key is retried once it ends
+    fn status(&self, limit: &Limit, ts: u64, count: u64, allowed: bool) -> Status {
+        let reset = self.reset(ts);
+        Status {
+            allowed,
+            count,
+            remaining: limit.count.saturating_sub(count),
+            reset,
+            retry_after: match allowed {
+                true => 0,
+                false => reset.saturating_sub(ts),
+            },
+            ..Default::default()
+        }
+    }
+
     pub fn new(window: u64) -> Self {
         Self {
             window,
@@ -129,6 +189,33 @@ impl Default for TTLValues {
     }
 }
 
+// TTLValues buckets by the millisecond, so windows shorter than a second still count
+impl Limiter for TTLValues {
+    fn create(limit: &Limit) -> Self {
+        TTLValues::new(limit.window)
+    }
+
+    fn hit(&mut self, limit: &Limit, ts: u64, cost: u64) -> Status {
+        self.inc(ts, cost);
+        let count = self.count(ts);
+        self.status(limit, ts, count, count <= limit.count)
+    }
+
+    fn peek(&self, limit: &Limit, ts: u64, cost: u64) -> Status {
+        let count = self.count(ts);
+        self.status(limit, ts, count, count + cost <= limit.count)
+    }
+
+    fn refund(&mut self, _limit: &Limit, _ts: u64, cost: u64) {
+        self.dec(cost);
+    }
+
+    fn expire(&mut self, ts: u64, ttl: u64) -> bool {
+        self.lru(ts.saturating_sub(ttl));
+        self.vals.is_empty()
+    }
+}
+
 #[cfg(test)]
 mod ttlvalues_tests {
 
@@ -146,8 +233,8 @@ mod ttlvalues_tests {
     #[test]
     fn test_get_inner() {
         let mut val = TTLValues::default();
-        val.inc(1000);
-        val.inc(1010);
+        val.inc(1000, 1);
+        val.inc(1010, 1);
         assert_eq!(val.get_inner(1000), 2, "actual bucket");
         assert_eq!(val.get_inner(1050), 2, "within bucket");
         assert_eq!(val.get_inner(1100), 0, "outside bucket");
@@ -156,9 +243,60 @@ mod ttlvalues_tests {
     #[test]
     fn test_inc_and_get() {
         let mut val = TTLValues::default();
-        val.inc(1000);
-        assert_eq!(val.inc_and_get(1000), 2);
-        assert_eq!(val.inc_and_get(2000), 3)
+        val.inc(1000, 1);
+        val.inc(1000, 1);
+        assert_eq!(val.get(), 2);
+        val.inc(2000, 1);
+        assert_eq!(val.get(), 3)
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let limit = Limit {
+            count: 2,
+            window: 1_000,
+            ..Default::default()
+        };
+        let mut val = TTLValues::create(&limit);
+        val.hit(&limit, 10_000, 2);
+
+        let denied = val.hit(&limit, 10_200, 1);
+        assert!(!denied.allowed, "over the limit");
+        assert_eq!((denied.reset, denied.retry_after), (11_000, 800));
+        assert!(!val.hit(&limit, 10_900, 1).allowed, "allowed before retry_after");
+
+        // the LRU has not dropped the old bucket, but it no longer counts
+        let retried = val.hit(&limit, 10_200 + denied.retry_after, 1);
+        assert!(retried.allowed, "denied after waiting retry_after");
+        assert_eq!(retried.count, 1);
+        assert_eq!(val.peek(&limit, 12_500, 1).count, 0, "ended bucket counted");
+    }
+
+    #[test]
+    fn test_sub_second_window() {
+        let limit = Limit {
+            count: 2,
+            window: 500,
+            ..Default::default()
+        };
+        let mut val = TTLValues::create(&limit);
+
+        assert!(val.hit(&limit, 10_000, 2).allowed, "under the limit");
+        let denied = val.hit(&limit, 10_100, 1);
+        assert!(!denied.allowed, "over the limit");
+        assert_eq!((denied.reset, denied.retry_after), (10_500, 400));
+        assert!(val.hit(&limit, 10_500, 1).allowed, "denied in the next window");
+    }
+
+    #[test]
+    fn test_dec() {
+        let mut val = TTLValues::new(1000);
+        val.inc(1000, 3);
+        val.inc(2000, 2);
+
+        assert_eq!(val.dec(3), 2, "spilled into older bucket");
+        assert_eq!(val.get_inner(1000), 2, "older bucket");
+        assert_eq!(val.dec(5), 0, "below zero");
     }
 
     // testcase-based rather than macro-based to simplify state across cases
@@ -195,7 +333,7 @@ mod ttlvalues_tests {
         ];
 
         for tc in testcases {
-            val.inc(tc.inc);
+            val.inc(tc.inc, 1);
             let actual = val.get();
             assert_eq!(
                 actual, tc.val,
@@ -244,7 +382,7 @@ mod ttlvalues_tests {
         ];
 
         for tc in testcases {
-            val.inc(tc.inc);
+            val.inc(tc.inc, 1);
             let actual = val.get_inner(tc.get);
             assert_eq!(
                 actual, tc.val,
@@ -263,7 +401,7 @@ mod ttlvalues_tests {
 
                     let mut ttl_val = TTLValues::new(5);
                     for v in vals {
-                        ttl_val.inc(v);
+                        ttl_val.inc(v, 1);
                     }
 
                     ttl_val.lru(ttl);
@@ -281,38 +419,286 @@ mod ttlvalues_tests {
     }
 }
 
-impl KeyMap {
-    pub fn new(window: u64) -> KeyMap {
+impl FixedWindow {
+    pub fn new() -> Self {
+        Self { start: 0, count: 0 }
+    }
+
+    // the window start and count as of ts, without updating state
+    fn current(&self, limit: &Limit, ts: u64) -> (u64, u64) {
+        let start = ts - ts % limit.window.max(1);
+        match start > self.start {
+            true => (start, 0),
+            false => (self.start, self.count),
+        }
+    }
+
+    fn status(limit: &Limit, ts: u64, start: u64, count: u64, allowed: bool) -> Status {
+        let reset = start + limit.window.max(1);
+        Status {
+            allowed,
+            count,
+            remaining: limit.count.saturating_sub(count),
+            reset,
+            retry_after: match allowed {
+                true => 0,
+                false => reset - ts,
+            },
+            ..Default::default()
+        }
+    }
+}
+
+impl Limiter for FixedWindow {
+    fn create(_limit: &Limit) -> Self {
+        FixedWindow::new()
+    }
+
+    fn hit(&mut self, limit: &Limit, ts: u64, cost: u64) -> Status {
+        (self.start, self.count) = self.current(limit, ts);
+
+        let allowed = self.count + cost <= limit.count;
+        if allowed {
+            self.count += cost;
+        }
+
+        FixedWindow::status(limit, ts, self.start, self.count, allowed)
+    }
+
+    fn peek(&self, limit: &Limit, ts: u64, cost: u64) -> Status {
+        let (start, count) = self.current(limit, ts);
+        FixedWindow::status(limit, ts, start, count, count + cost <= limit.count)
+    }
+
+    // hits from earlier windows no longer count, so there is nothing to refund for them
+    fn refund(&mut self, limit: &Limit, ts: u64, cost: u64) {
+        (self.start, self.count) = self.current(limit, ts);
+        self.count = self.count.saturating_sub(cost);
+    }
+
+    // exact as long as the TTL covers the window, as the count is reset by then
+    fn expire(&mut self, ts: u64, ttl: u64) -> bool {
+        self.start.saturating_add(ttl) < ts
+    }
+}
+
+#[cfg(test)]
+mod fixedwindow_tests {
+
+    use super::*;
+
+    const LIMIT: Limit = Limit {
+        count: 3,
+        window: 60_000,
+        burst: 0,
+        max_delay: 0,
+        windows: Vec::new(),
+    };
+
+    // testcase-based rather than macro-based to simplify state across cases
+    #[test]
+    fn test_hit() {
+        struct TestCase {
+            name: &'static str,
+            ts: u64,
+            hits: u64,
+            allowed: bool,
+            count: u64,
+            reset: u64,
+            retry_after: u64,
+        }
+
+        let mut window = FixedWindow::create(&LIMIT);
+        let testcases = vec![
+            TestCase {
+                name: "first hit",
+                ts: 125_000,
+                hits: 1,
+                allowed: true,
+                count: 1,
+                reset: 180_000,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "fill window",
+                ts: 170_000,
+                hits: 2,
+                allowed: true,
+                count: 3,
+                reset: 180_000,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "limited until window boundary",
+                ts: 179_999,
+                hits: 1,
+                allowed: false,
+                count: 3,
+                reset: 180_000,
+                retry_after: 1,
+            },
+            TestCase {
+                name: "new window at boundary",
+                ts: 180_000,
+                hits: 1,
+                allowed: true,
+                count: 1,
+                reset: 240_000,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "skipped windows",
+                ts: 600_001,
+                hits: 1,
+                allowed: true,
+                count: 1,
+                reset: 660_000,
+                retry_after: 0,
+            },
+        ];
+
+        for tc in testcases {
+            let mut status = Status::default();
+            for _ in 0..tc.hits {
+                status = window.hit(&LIMIT, tc.ts, 1);
+            }
+
+            assert_eq!(
+                status,
+                Status {
+                    allowed: tc.allowed,
+                    count: tc.count,
+                    remaining: LIMIT.count - tc.count,
+                    reset: tc.reset,
+                    retry_after: tc.retry_after,
+                    ..Default::default()
+                },
+                "status did not match for {}",
+                tc.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_refund() {
+        let mut window = FixedWindow::create(&LIMIT);
+        window.hit(&LIMIT, 125_000, 3);
+
+        window.refund(&LIMIT, 130_000, 2);
+        assert_eq!(window.count, 1, "refund in window");
+
+        window.refund(&LIMIT, 180_000, 1);
+        assert_eq!((window.start, window.count), (180_000, 0), "refund in next window");
+    }
+
+    #[test]
+    fn test_calendar_day() {
+        let limit = Limit {
+            count: 1000,
+            window: 86_400_000,
+            burst: 0,
+            max_delay: 0,
+            windows: Vec::new(),
+        };
+
+        // 2023-01-01T13:00:00Z resets at 2023-01-02T00:00:00Z
+        let mut window = FixedWindow::create(&limit);
+        assert_eq!(window.hit(&limit, 1_672_578_000_000, 1).reset, 1_672_617_600_000);
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut window = FixedWindow::create(&LIMIT);
+        for _ in 0..3 {
+            window.hit(&LIMIT, 125_000, 1);
+        }
+
+        assert!(!window.peek(&LIMIT, 179_999, 1).allowed, "full window");
+        assert!(window.peek(&LIMIT, 180_000, 1).allowed, "next window");
+        assert_eq!(window.count, 3, "peek rolled window");
+    }
+
+    macro_rules! fixedwindow_expire_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                fn $name() {
+                    let (ts, now, expired) = $value;
+
+                    let mut window = FixedWindow::new();
+                    window.hit(&LIMIT, ts, 1);
+                    assert_eq!(window.expire(now, 60_000), expired);
+                }
+            )*
+        }
+    }
+
+    fixedwindow_expire_tests! {
+        fixedwindow_expire_idle: (10_000, 70_000, true),
+        fixedwindow_expire_recent: (65_000, 70_000, false),
+        fixedwindow_expire_edge: (10_000, 60_000, false),
+    }
+}
+
+impl<V: Limiter> KeyMap<V> {
+    pub fn new() -> KeyMap<V> {
         KeyMap {
-            window,
             ttls: HashMap::new(),
         }
     }
 
-    pub fn get_or_create(&mut self, key: Key, inc: bool) -> u64 {
+    pub fn get(&self, key: Key, limit: &Limit, cost: u64) -> Status {
+        match self.ttls.get(key.k) {
+            Some(val) => val.peek(limit, key.ts, cost),
+            None => V::create(limit).peek(limit, key.ts, cost),
+        }
+    }
+
+    pub fn get_or_create(&mut self, key: Key, limit: &Limit, cost: u64, inc: bool) -> Status {
         match self.ttls.get_mut(key.k) {
             Some(val) => match inc {
-                true => val.inc_and_get(key.ts),
-                false => val.get(),
+                true => val.hit(limit, key.ts, cost),
+                false => val.peek(limit, key.ts, cost),
             },
             None => match inc {
                 true => {
-                    let mut val = TTLValues::new(self.window);
-                    let state = val.inc_and_get(key.ts);
+                    let mut val = V::create(limit);
+                    let state = val.hit(limit, key.ts, cost);
                     self.ttls.insert(key.k.to_string(), val);
 
                     state
                 }
-                false => 0,
+                false => V::create(limit).peek(limit, key.ts, cost),
             },
         }
     }
 
-    fn lru(&mut self, now: u64) {
-        self.ttls.retain(|_, v| {
-            v.lru(now);
-            !v.vals.is_empty()
-        });
+    // refunding a key that does not exist has nothing to give back, so it is not created
+    pub fn refund(&mut self, key: Key, limit: &Limit, cost: u64) -> Status {
+        match self.ttls.get_mut(key.k) {
+            Some(val) => {
+                val.refund(limit, key.ts, cost);
+                val.peek(limit, key.ts, 1)
+            }
+            None => V::create(limit).peek(limit, key.ts, 1),
+        }
+    }
+
+    pub fn release(&mut self, key: &str, lease: u64) -> bool {
+        match self.ttls.get_mut(key) {
+            Some(val) => val.release(lease),
+            None => false,
+        }
+    }
+
+    fn lru(&mut self, now: u64, ttl: u64) {
+        self.ttls.retain(|_, v| !v.expire(now, ttl));
+    }
+}
+
+impl<V: Limiter> Default for KeyMap<V> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -331,13 +717,20 @@ mod keymap_tests {
             val: u64,
         }
 
-        let mut km = KeyMap::new(60);
+        let mut km = KeyMap::<TTLValues>::new();
+        let limit = Limit {
+            count: 100,
+            window: 60_000,
+            burst: 0,
+            max_delay: 0,
+            windows: Vec::new(),
+        };
 
         let testcases = vec![
             TestCase {
                 key: Key {
                     k: "foo",
-                    ts: 10000,
+                    ts: 10_000_000,
                 },
                 create: true,
                 val: 1,
@@ -346,7 +739,7 @@ mod keymap_tests {
             TestCase {
                 key: Key {
                     k: "foo",
-                    ts: 10005,
+                    ts: 10_005_000,
                 },
                 create: true,
                 val: 2,
@@ -355,7 +748,7 @@ mod keymap_tests {
             TestCase {
                 key: Key {
                     k: "foo",
-                    ts: 10006,
+                    ts: 10_006_000,
                 },
                 create: false,
                 val: 2,
@@ -364,25 +757,25 @@ mod keymap_tests {
             TestCase {
                 key: Key {
                     k: "foo",
-                    ts: 10151,
+                    ts: 10_151_000,
                 },
                 create: true,
-                val: 3,
+                val: 1,
                 name: "foo in new window",
             },
             TestCase {
                 key: Key {
                     k: "foo",
-                    ts: 10200,
+                    ts: 10_200_000,
                 },
                 create: false,
-                val: 3,
+                val: 1,
                 name: "foo in new window, no update",
             },
             TestCase {
                 key: Key {
                     k: "bar",
-                    ts: 10100,
+                    ts: 10_100_000,
                 },
                 create: false,
                 val: 0,
@@ -391,7 +784,7 @@ mod keymap_tests {
             TestCase {
                 key: Key {
                     k: "bar",
-                    ts: 10100,
+                    ts: 10_100_000,
                 },
                 create: true,
                 val: 1,
@@ -400,7 +793,7 @@ mod keymap_tests {
         ];
 
         for tc in testcases {
-            let val = km.get_or_create(tc.key, tc.create);
+            let val = km.get_or_create(tc.key, &limit, 1, tc.create).count;
             assert_eq!(
                 val, tc.val,
                 "val {} does not match expected val for case '{}'",
@@ -415,15 +808,22 @@ mod keymap_tests {
                 #[test]
                 fn $name() {
                     let (vals, len) = $value;
-                    let mut km = KeyMap::new(30);
+                    let mut km = KeyMap::<TTLValues>::new();
+                    let limit = Limit {
+                        count: 100,
+                        window: 30_000,
+                        burst: 0,
+                        max_delay: 0,
+                        windows: Vec::new(),
+                    };
 
                     for (k, v) in vals {
                         for vv in v {
-                            km.get_or_create(Key { k, ts: vv }, true);
+                            km.get_or_create(Key { k, ts: vv * 1000 }, &limit, 1, true);
                         }
                     }
 
-                    km.lru(30);
+                    km.lru(60_000, 30_000);
                     assert_eq!(
                         km.ttls.len(),
                         len,
@@ -462,54 +862,142 @@ mod keymap_tests {
     }
 }
 
-impl Local {
-    pub fn ttl(&self) -> u64 {
-        self.ttl
-    }
-
+impl<V: Limiter> Local<V> {
     #[cfg(target_os = "macos")]
-    pub fn new(partition_count: u32, ttl: u64, window: u64, sweep: u64) -> Self {
+    pub fn new(partition_count: u32, ttl: u64, sweep: u64) -> Self {
         Self {
             partition_count,
             partitions: {
                 let mut v = Vec::with_capacity(partition_count as usize);
-                (0..partition_count as usize).for_each(|_| v.push(Mutex::new(KeyMap::new(window))));
+                (0..partition_count as usize).for_each(|_| v.push(Mutex::new(KeyMap::new())));
                 v
             },
-            clock: AtomicU64::new(
-                time::SystemTime::now()
-                    .duration_since(time::UNIX_EPOCH)
-                    .expect("can't get duration since UNIX 0 - this is a bug in the code")
-                    .as_secs(),
-            ),
-            ttl,
-            sweep,
+            clock: AtomicU64::new(now()),
+            ttl: AtomicU64::new(ttl),
+            sweep: AtomicU64::new(sweep),
         }
     }
 
     #[cfg(not(target_os = "macos"))]
-    pub fn new(partition_count: u32, ttl: u64, window: u64, sweep: u64) -> Self {
+    pub fn new(partition_count: u32, ttl: u64, sweep: u64) -> Self {
         Self {
             partition_count,
             partitions: {
                 let mut v = Vec::with_capacity(partition_count as usize);
-                (0..partition_count as usize)
-                    .for_each(|_| v.push(RwLock::new(KeyMap::new(window))));
+                (0..partition_count as usize).for_each(|_| v.push(RwLock::new(KeyMap::new())));
                 v
             },
-            clock: AtomicU64::new(
-                time::SystemTime::now()
-                    .duration_since(time::UNIX_EPOCH)
-                    .expect("can't get duration since UNIX 0 - this is a bug in the code")
-                    .as_secs(),
-            ),
-            ttl,
-            sweep,
+            clock: AtomicU64::new(now()),
+            ttl: AtomicU64::new(ttl),
+            sweep: AtomicU64::new(sweep),
         }
     }
 
+    // the LRU and clock tasks only hold weak references, so they stop once the cache is dropped,
+    // such as when its collection is removed on reload
+
+    pub fn start_lru(self: &Arc<Local<V>>) {
+        let weak = Arc::downgrade(self);
+
+        tokio::spawn(async move {
+            let Some(mut sweep) = weak.upgrade().map(|l| l.sweep.load(Relaxed)) else {
+                return;
+            };
+            let mut ticker = tokio::time::interval(std::time::Duration::from_secs(sweep));
+            loop {
+                ticker.tick().await;
+                let Some(local) = weak.upgrade() else {
+                    return;
+                };
+                local.lru();
+
+                // an updated sweep takes over from the next tick
+                let updated = local.sweep.load(Relaxed);
+                if updated != sweep {
+                    sweep = updated;
+                    let period = std::time::Duration::from_secs(sweep);
+                    ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+                }
+            }
+        });
+    }
+
+    pub fn start_clock(self: &Arc<Local<V>>) {
+        let weak = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticker =
+                tokio::time::interval(std::time::Duration::from_millis(CLOCK_RESOLUTION));
+            loop {
+                ticker.tick().await;
+                match weak.upgrade() {
+                    Some(local) => local.clock.store(now(), Relaxed),
+                    None => return,
+                }
+            }
+        });
+    }
+
     #[cfg(target_os = "macos")]
-    pub fn get_or_create(&self, key: &str, create: bool) -> Result<u64, CacheError> {
+    fn write(&self, key: &str) -> Result<std::sync::MutexGuard<'_, KeyMap<V>>, CacheError> {
+        let partition = twox_hash::xxh3::hash64(key.as_bytes()) as u32 % self.partition_count;
+        self.partitions
+            .index(partition as usize)
+            .lock()
+            .map_err(|e| CacheError {
+                msg: format!("failed to get partition lock: {}", e),
+            })
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    fn write(&self, key: &str) -> Result<std::sync::RwLockWriteGuard<'_, KeyMap<V>>, CacheError> {
+        let partition = twox_hash::xxh3::hash64(key.as_bytes()) as u32 % self.partition_count;
+        self.partitions
+            .index(partition as usize)
+            .write()
+            .map_err(|e| CacheError {
+                msg: format!("failed to get partition write lock: {}", e),
+            })
+    }
+
+    #[cfg(target_os = "macos")]
+    fn lru(&self) {
+        for partition in self.partitions.iter() {
+            let now = self.clock.load(Relaxed);
+            if let Ok(mut p) = partition.lock() {
+                p.lru(now, self.ttl.load(Relaxed) * 1000);
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    fn lru(&self) {
+        for partition in self.partitions.iter() {
+            let now = self.clock.load(Relaxed);
+            if let Ok(mut p) = partition.write() {
+                p.lru(now, self.ttl.load(Relaxed) * 1000);
+            }
+        }
+    }
+}
+
+impl<V: Limiter> Cache for Local<V> {
+    fn ttl(&self) -> u64 {
+        self.ttl.load(Relaxed)
+    }
+
+    fn update(&self, ttl: u64, sweep: u64) {
+        self.ttl.store(ttl, Relaxed);
+        self.sweep.store(sweep, Relaxed);
+    }
+
+    #[cfg(target_os = "macos")]
+    fn get_or_create(
+        &self,
+        key: &str,
+        limit: &Limit,
+        cost: u64,
+        create: bool,
+    ) -> Result<Status, CacheError> {
         let partition = twox_hash::xxh3::hash64(key.as_bytes()) as u32 % self.partition_count;
         let inner = self.partitions.index(partition as usize);
 
@@ -527,6 +1015,8 @@ impl Local {
                 k: key,
                 ts: self.clock.load(Relaxed),
             },
+            limit,
+            cost,
             create,
         );
 
@@ -534,13 +1024,24 @@ impl Local {
     }
 
     #[cfg(not(target_os = "macos"))]
-    pub fn get_or_create(&self, key: &str, create: bool) -> Result<u64, CacheError> {
+    fn get_or_create(
+        &self,
+        key: &str,
+        limit: &Limit,
+        cost: u64,
+        create: bool,
+    ) -> Result<Status, CacheError> {
         let partition = twox_hash::xxh3::hash64(key.as_bytes()) as u32 % self.partition_count;
         let inner = self.partitions.index(partition as usize);
+        let key = Key {
+            k: key,
+            ts: self.clock.load(Relaxed),
+        };
 
-        let mut lock = match create {
+        // only creating needs the write lock - reads can share the partition
+        let val = match create {
             true => match inner.write() {
-                Ok(l) => l,
+                Ok(mut l) => l.get_or_create(key, limit, cost, create),
                 Err(e) => {
                     return Err(CacheError {
                         msg: format!("failed to get partition write lock: {}", e),
@@ -548,7 +1049,7 @@ impl Local {
                 }
             },
             false => match inner.read() {
-                Ok(l) => l,
+                Ok(l) => l.get(key, limit, cost),
                 Err(e) => {
                     return Err(CacheError {
                         msg: format!("failed to get partition read lock: {}", e),
@@ -557,57 +1058,20 @@ impl Local {
             },
         };
 
-        let val = lock.get_or_create(
-            Key {
-                k: key,
-                ts: self.clock.load(Relaxed),
-            },
-            create,
-        );
-
         Ok(val)
     }
 
-    pub fn start_lru(self: &Arc<Local>) {
-        let clone = self.clone();
-
-        tokio::spawn(async move {
-            let mut ticker = tokio::time::interval(std::time::Duration::from_secs(clone.sweep));
-            loop {
-                ticker.tick().await;
-                clone.lru();
-            }
-        });
+    fn refund(&self, key: &str, limit: &Limit, cost: u64) -> Result<Status, CacheError> {
+        let ts = self.clock.load(Relaxed);
+        Ok(self.write(key)?.refund(Key { k: key, ts }, limit, cost))
     }
 
-    pub fn start_clock(self: &Arc<Local>) {
-        let clone = self.clone();
-        tokio::spawn(async move {
-            let mut ticker = tokio::time::interval(std::time::Duration::from_secs(1));
-            loop {
-                ticker.tick().await;
-                clone.clock.store(
-                    time::SystemTime::now()
-                        .duration_since(time::UNIX_EPOCH)
-                        .expect("can't get duration since UNIX 0 - this is a bug in the code")
-                        .as_secs(),
-                    Relaxed,
-                );
-            }
-        });
-    }
-
-    fn lru(&self) {
-        for partition in self.partitions.iter() {
-            let now = self.clock.load(Relaxed) - self.ttl as u64;
-            if let Ok(mut p) = partition.lock() {
-                p.lru(now);
-            }
-        }
+    fn release(&self, key: &str, lease: u64) -> Result<bool, CacheError> {
+        Ok(self.write(key)?.release(key, lease))
     }
 }
 
-impl Default for Local {
+impl<V: Limiter> Default for Local<V> {
     #[cfg(target_os = "macos")]
     fn default() -> Self {
         Self {
@@ -618,14 +1082,9 @@ impl Default for Local {
                     .for_each(|_| v.push(Mutex::new(KeyMap::default())));
                 v
             },
-            clock: AtomicU64::new(
-                time::SystemTime::now()
-                    .duration_since(time::UNIX_EPOCH)
-                    .expect("can't get duration since UNIX 0 - this is a bug in the code")
-                    .as_secs(),
-            ),
-            ttl: DEFAULT_TTL,
-            sweep: DEFAULT_SWEEP,
+            clock: AtomicU64::new(now()),
+            ttl: AtomicU64::new(DEFAULT_TTL),
+            sweep: AtomicU64::new(DEFAULT_SWEEP),
         }
     }
 
@@ -639,41 +1098,54 @@ impl Default for Local {
                     .for_each(|_| v.push(RwLock::new(KeyMap::default())));
                 v
             },
-            clock: AtomicU64::new(
-                time::SystemTime::now()
-                    .duration_since(time::UNIX_EPOCH)
-                    .expect("can't get duration since UNIX 0 - this is a bug in the code")
-                    .as_secs(),
-            ),
-            ttl: DEFAULT_TTL,
-            sweep: DEFAULT_SWEEP,
+            clock: AtomicU64::new(now()),
+            ttl: AtomicU64::new(DEFAULT_TTL),
+            sweep: AtomicU64::new(DEFAULT_SWEEP),
         }
     }
 }
 
+/// now is the current time in milliseconds since UNIX 0.
+pub fn now() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .expect("can't get duration since UNIX 0 - this is a bug in the code")
+        .as_millis() as u64
+}
+
 #[cfg(test)]
 mod local_tests {
 
     // extern crate test;
 
     use super::*;
-    use rand::Rng;
     use std::sync::Arc;
 
+    const LIMIT: Limit = Limit {
+        count: 100,
+        window: 5_000,
+        burst: 0,
+        max_delay: 0,
+        windows: Vec::new(),
+    };
+
     #[test]
     fn test_new_local() {
-        let local = Local::new(5, 30, DEFAULT_SWEEP, DEFAULT_SWEEP);
+        let local = Local::<TTLValues>::new(5, 30, DEFAULT_SWEEP);
         assert_eq!(local.partition_count, 5);
-        assert_eq!(local.ttl, 30);
+        assert_eq!(local.ttl(), 30);
+
+        local.update(60, 5);
+        assert_eq!((local.ttl(), local.sweep.load(Relaxed)), (60, 5), "not updated in place");
 
-        let local = Local::default();
+        let local = Local::<TTLValues>::default();
         assert_eq!(local.partition_count, DEFAULT_PARTITIONS);
-        assert_eq!(local.ttl, DEFAULT_TTL);
+        assert_eq!(local.ttl(), DEFAULT_TTL);
     }
 
     #[tokio::test]
     async fn test_start_clock() {
-        let local = std::sync::Arc::new(Local::new(2, 30, 5, 1));
+        let local = std::sync::Arc::new(Local::<TTLValues>::new(2, 30, 1));
         Local::start_clock(&local);
         let mut running_time = local.clock.load(Relaxed);
 
@@ -681,18 +1153,33 @@ mod local_tests {
             tokio::time::sleep(std::time::Duration::from_secs(1)).await;
             let curr = local.clock.load(Relaxed);
             // tokio::time::sleep should only ever sleep longer than 1 second, as the executor will put it back to sleep if the
-            // Instant we are waiting for hasn't elapsed. As such, for some starting time edge cases that can lead to us going
-            // from, say, time 19 to time 21 for a 1 second sleep, so we allow it to be 1 or 2 ahead of start time
+            // Instant we are waiting for hasn't elapsed. The clock only moves every CLOCK_RESOLUTION though, and its task can
+            // be scheduled late, so we allow it to trail the actual time by a few ticks
             assert!(
-                curr == running_time || curr == running_time + 1,
+                curr + 10 * CLOCK_RESOLUTION >= running_time + 1000
+                    && curr + 10 * CLOCK_RESOLUTION >= now()
+                    && curr <= running_time + 2000,
                 "expected {}, got {}",
-                running_time,
+                running_time + 1000,
                 curr
             );
-            running_time += 1;
+            running_time = curr;
         }
     }
 
+    #[tokio::test]
+    async fn test_tasks_stop_when_dropped() {
+        let local = std::sync::Arc::new(Local::<TTLValues>::new(2, 30, 1));
+        local.start_lru();
+        local.start_clock();
+        let weak = Arc::downgrade(&local);
+
+        drop(local);
+        // the tasks only hold the cache while they tick
+        tokio::time::sleep(std::time::Duration::from_millis(5 * CLOCK_RESOLUTION)).await;
+        assert!(weak.upgrade().is_none(), "cache kept alive by its tasks");
+    }
+
     // test_start_lru combines coverage for lru and start_lru
     #[tokio::test]
     async fn test_start_lru() {
@@ -724,28 +1211,32 @@ mod local_tests {
         ];
 
         for tc in testcases {
-            let local = std::sync::Arc::new(Local::new(2, TTL, 5, 1));
+            let local = std::sync::Arc::new(Local::<TTLValues>::new(2, TTL, 1));
 
             for (k, v) in tc.vals {
                 for e in v {
-                    local.clock.store(e, Relaxed);
+                    local.clock.store(e * 1000, Relaxed);
                     local
-                        .get_or_create(k, true)
-                        .expect(format!("failed to set values for {}", tc.name).as_str());
+                        .get_or_create(k, &LIMIT, 1, true)
+                        .unwrap_or_else(|_| panic!("failed to set values for {}", tc.name));
                 }
             }
 
-            local.clock.store(HARDCODED_TIME, Relaxed);
+            local.clock.store(HARDCODED_TIME * 1000, Relaxed);
             local.start_lru();
             tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
 
+            // the hits kept, whether or not their windows have ended
             for (k, v) in tc.expected {
                 let val = local
-                    .get_or_create(k, false)
-                    .ok();
+                    .write(k)
+                    .expect("unable to get partition")
+                    .ttls
+                    .get(k)
+                    .map(TTLValues::get);
                 assert_eq!(
-                    v.or(Some(0)).unwrap(),
-                    val.or(Some(0)).unwrap(),
+                    v,
+                    val,
                     "expected {:?}, got {:?} for key {} for '{}'",
                     v,
                     val,
@@ -792,25 +1283,25 @@ mod local_tests {
             },
         ];
 
-        let local = Local::new(10, 30, DEFAULT_SWEEP, DEFAULT_SWEEP);
+        let local = Local::<TTLValues>::new(10, 30, DEFAULT_SWEEP);
         for tc in testcases {
-            let val = local.get_or_create(tc.key, tc.create);
-            let inner = val.unwrap();
+            let val = local.get_or_create(tc.key, &LIMIT, 1, tc.create);
+            let inner = val.unwrap().count;
             assert_eq!(inner, tc.val, "incorrect value {} for {}", inner, tc.name);
         }
     }
 
     #[test]
     fn test_get_or_create_concurrent() {
-        let local = Arc::new(Local::new(10, 30, DEFAULT_SWEEP, DEFAULT_SWEEP));
+        let local = Arc::new(Local::<TTLValues>::new(10, 30, DEFAULT_SWEEP));
 
         let mut threads = Vec::new();
         for _ in 0..10 {
             let lp = local.clone();
             let t = std::thread::spawn(move || {
                 // let mut l = lp.lock().expect("unable to get Local lock");
-                if let Err(e) = lp.get_or_create("foo", true) {
-                    panic!("failed to get get_or_create: {}", e.to_string());
+                if let Err(e) = lp.get_or_create("foo", &LIMIT, 1, true) {
+                    panic!("failed to get get_or_create: {}", e);
                 }
             });
             threads.push(t);
@@ -821,8 +1312,9 @@ mod local_tests {
         }
 
         let val = local
-            .get_or_create("foo", false)
-            .expect("failed to get Local lock");
+            .get_or_create("foo", &LIMIT, 1, false)
+            .expect("failed to get Local lock")
+            .count;
 
         assert_eq!(val, 10);
     }
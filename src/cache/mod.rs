@@ -1,6 +1,199 @@
+pub mod bucket;
+pub mod gcra;
 pub mod local;
+pub mod semaphore;
+pub mod sliding;
+pub mod windows;
+
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct CacheError {
     msg: String,
-}
\ No newline at end of file
+}
+
+/// Algorithm selects the per-key state a collection uses to track hits.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// Counter for windows starting at a key's first hit - see local::TTLValues.
+    #[default]
+    Counter,
+    /// Token bucket refilled at count tokens per window - see bucket::TokenBucket.
+    TokenBucket,
+    /// Generic cell rate algorithm, storing a single timestamp per key - see gcra::Gcra.
+    Gcra,
+    /// Exact log of hit timestamps over the window - see sliding::SlidingLog.
+    SlidingLog,
+    /// Previous and current window counters, weighted by overlap - see sliding::SlidingWindow.
+    SlidingWindow,
+    /// Counter for windows aligned to UNIX 0 - see local::FixedWindow.
+    FixedWindow,
+    /// Leases on in-flight work that time out after the window - see semaphore::Semaphore.
+    Concurrency,
+    /// Queue draining at count hits per window, delaying hits instead of denying them - see
+    /// bucket::LeakyBucket.
+    LeakyBucket,
+}
+
+/// Limit is the rate enforced for a collection. It is passed in on every call rather than stored
+/// per key, so keys only hold the state their algorithm needs.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Limit {
+    pub count: u64,
+    /// window in milliseconds
+    pub window: u64,
+    /// how many hits can be spent at once by algorithms that allow bursts - 0 means count
+    pub burst: u64,
+    /// longest a hit can be delayed by algorithms that shape traffic, in milliseconds - 0 means
+    /// window
+    pub max_delay: u64,
+    /// further count and window (in milliseconds) pairs enforced alongside count and window, such
+    /// as an hourly quota on top of a per-second rate - only windows::Windows reads these
+    pub windows: Vec<(u64, u64)>,
+}
+
+/// Status is the state of a key after a hit, or before one if nothing was recorded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub allowed: bool,
+    /// hits currently counted against the limit
+    pub count: u64,
+    /// hits left before the limit is reached
+    pub remaining: u64,
+    /// when the key is back to its full limit, in milliseconds since UNIX 0
+    pub reset: u64,
+    /// lease to release once in-flight work is done, for algorithms that track it
+    pub lease: Option<u64>,
+    /// how long to wait before proceeding, in milliseconds, for algorithms that shape traffic
+    pub delay: Option<u64>,
+    /// how long until a denied hit of the same cost would be allowed, in milliseconds - 0 if it
+    /// was allowed
+    pub retry_after: u64,
+    /// which of the limit's windows the status is for, where 0 is count and window themselves
+    pub window: usize,
+}
+
+/// Limiter is the per-key state of a rate limiting algorithm. All timestamps are milliseconds
+/// since UNIX 0.
+pub trait Limiter: std::fmt::Debug + Send + Sync + 'static {
+    fn create(limit: &Limit) -> Self;
+
+    /// hit records a hit costing cost at ts, returning whether it was allowed.
+    fn hit(&mut self, limit: &Limit, ts: u64, cost: u64) -> Status;
+
+    /// peek returns whether a hit costing cost at ts would be allowed, without recording it.
+    fn peek(&self, limit: &Limit, ts: u64, cost: u64) -> Status;
+
+    /// refund gives back cost from hits that were allowed but never served. Refunds never take a
+    /// key below no hits counted.
+    fn refund(&mut self, limit: &Limit, ts: u64, cost: u64);
+
+    /// release frees a lease taken by hit, returning false if the lease is unknown or has already
+    /// timed out. Only algorithms that track in-flight work have leases.
+    fn release(&mut self, _lease: u64) -> bool {
+        false
+    }
+
+    /// expire drops anything older than ttl, returning true if the key can be removed.
+    fn expire(&mut self, ts: u64, ttl: u64) -> bool;
+}
+
+/// Cache is a collection of keys, regardless of the Limiter backing them.
+pub trait Cache: std::fmt::Debug + Send + Sync {
+    fn get_or_create(
+        &self,
+        key: &str,
+        limit: &Limit,
+        cost: u64,
+        create: bool,
+    ) -> Result<Status, CacheError>;
+
+    fn refund(&self, key: &str, limit: &Limit, cost: u64) -> Result<Status, CacheError>;
+
+    fn release(&self, key: &str, lease: u64) -> Result<bool, CacheError>;
+
+    fn ttl(&self) -> u64;
+
+    /// update changes how long keys are kept after their last hit and how often expired keys are
+    /// dropped, both in seconds, keeping the keys already counted.
+    fn update(&self, ttl: u64, sweep: u64);
+}
+
+impl Limit {
+    pub fn capacity(&self) -> u64 {
+        match self.burst {
+            0 => self.count,
+            b => b,
+        }
+    }
+
+    pub fn queue(&self) -> u64 {
+        match self.max_delay {
+            0 => self.window,
+            d => d,
+        }
+    }
+
+    /// nth is the limit for window n, as reported by Status.window. Further windows have no burst
+    /// or max delay of their own.
+    pub fn nth(&self, n: usize) -> Limit {
+        match n.checked_sub(1).and_then(|i| self.windows.get(i)) {
+            Some((count, window)) => Limit {
+                count: *count,
+                window: *window,
+                ..Default::default()
+            },
+            None => Limit {
+                windows: Vec::new(),
+                ..*self
+            },
+        }
+    }
+}
+
+/// start creates a cache for the given algorithm, and starts its LRU and clock tasks. Collections
+/// with several windows wrap the algorithm in windows::Windows.
+pub fn start(
+    algorithm: Algorithm,
+    windowed: bool,
+    partition_count: u32,
+    ttl: u64,
+    sweep: u64,
+) -> Arc<dyn Cache> {
+    match algorithm {
+        Algorithm::Counter => start_local::<local::TTLValues>(windowed, partition_count, ttl, sweep),
+        Algorithm::TokenBucket => {
+            start_local::<bucket::TokenBucket>(windowed, partition_count, ttl, sweep)
+        }
+        Algorithm::Gcra => start_local::<gcra::Gcra>(windowed, partition_count, ttl, sweep),
+        Algorithm::SlidingLog => {
+            start_local::<sliding::SlidingLog>(windowed, partition_count, ttl, sweep)
+        }
+        Algorithm::SlidingWindow => {
+            start_local::<sliding::SlidingWindow>(windowed, partition_count, ttl, sweep)
+        }
+        Algorithm::FixedWindow => {
+            start_local::<local::FixedWindow>(windowed, partition_count, ttl, sweep)
+        }
+        Algorithm::Concurrency => {
+            start_local::<semaphore::Semaphore>(windowed, partition_count, ttl, sweep)
+        }
+        Algorithm::LeakyBucket => {
+            start_local::<bucket::LeakyBucket>(windowed, partition_count, ttl, sweep)
+        }
+    }
+}
+
+fn start_local<V: Limiter>(windowed: bool, partition_count: u32, ttl: u64, sweep: u64) -> Arc<dyn Cache> {
+    match windowed {
+        true => start_partitions::<windows::Windows<V>>(partition_count, ttl, sweep),
+        false => start_partitions::<V>(partition_count, ttl, sweep),
+    }
+}
+
+fn start_partitions<V: Limiter>(partition_count: u32, ttl: u64, sweep: u64) -> Arc<dyn Cache> {
+    let local = Arc::new(local::Local::<V>::new(partition_count, ttl, sweep));
+    local.start_lru();
+    local.start_clock();
+    local
+}
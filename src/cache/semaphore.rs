@@ -0,0 +1,263 @@
+use super::{Limit, Limiter, Status};
+use rand::Rng;
+
+// lease IDs fit in 53 bits, so JSON clients can hold them as numbers without losing precision
+const MAX_LEASE: u64 = 1 << 53;
+
+/// Semaphore limits how much work a key has in flight, rather than its rate. Each allowed hit takes
+/// a lease for its cost that is held until it is released, or until it times out after the window
+/// so that crashed clients do not hold slots forever.
+#[derive(Debug, Default)]
+pub struct Semaphore {
+    // lease IDs, when they expire in milliseconds, and their costs
+    leases: Vec<(u64, u64, u64)>,
+}
+
+impl Semaphore {
+    pub fn new() -> Self {
+        Self { leases: Vec::new() }
+    }
+
+    fn active(&self, ts: u64) -> u64 {
+        self.leases.iter().filter(|(_, e, _)| *e > ts).map(|(_, _, c)| c).sum()
+    }
+
+    fn lease(&self) -> u64 {
+        let mut rng = rand::thread_rng();
+        loop {
+            let lease = rng.gen_range(0..MAX_LEASE);
+            if self.leases.iter().all(|(l, _, _)| *l != lease) {
+                return lease;
+            }
+        }
+    }
+
+    // how long until enough leases time out for a hit to be allowed, if none are released first
+    fn wait(&self, limit: &Limit, ts: u64, cost: u64) -> u64 {
+        let mut needed = (self.active(ts) + cost).saturating_sub(limit.count);
+        let mut leases = self
+            .leases
+            .iter()
+            .filter(|(_, e, _)| *e > ts)
+            .map(|(_, e, c)| (*e, *c))
+            .collect::<Vec<_>>();
+        leases.sort_unstable();
+
+        for (e, c) in leases {
+            needed = needed.saturating_sub(c);
+            if needed == 0 {
+                return e - ts;
+            }
+        }
+
+        0
+    }
+
+    fn status(&self, limit: &Limit, ts: u64, cost: u64, allowed: bool, lease: Option<u64>) -> Status {
+        let count = self.active(ts);
+
+        Status {
+            allowed,
+            count,
+            remaining: limit.count.saturating_sub(count),
+            // every lease has timed out by the time the newest one does
+            reset: self.leases.iter().map(|(_, e, _)| *e).max().unwrap_or(ts).max(ts),
+            lease,
+            retry_after: match allowed {
+                true => 0,
+                false => self.wait(limit, ts, cost),
+            },
+            ..Default::default()
+        }
+    }
+}
+
+impl Limiter for Semaphore {
+    fn create(_limit: &Limit) -> Self {
+        Semaphore::new()
+    }
+
+    fn hit(&mut self, limit: &Limit, ts: u64, cost: u64) -> Status {
+        self.leases.retain(|(_, e, _)| *e > ts);
+
+        let allowed = self.active(ts) + cost <= limit.count;
+        let lease = match allowed {
+            true => {
+                let lease = self.lease();
+                self.leases.push((lease, ts + limit.window, cost));
+                Some(lease)
+            }
+            false => None,
+        };
+
+        self.status(limit, ts, cost, allowed, lease)
+    }
+
+    fn peek(&self, limit: &Limit, ts: u64, cost: u64) -> Status {
+        self.status(limit, ts, cost, self.active(ts) + cost <= limit.count, None)
+    }
+
+    // leases are given back by releasing them, so there is nothing to refund
+    fn refund(&mut self, _limit: &Limit, _ts: u64, _cost: u64) {}
+
+    fn release(&mut self, lease: u64) -> bool {
+        let len = self.leases.len();
+        self.leases.retain(|(l, _, _)| *l != lease);
+
+        self.leases.len() < len
+    }
+
+    // leases are reaped as soon as they time out, regardless of TTL
+    fn expire(&mut self, ts: u64, _ttl: u64) -> bool {
+        self.leases.retain(|(_, e, _)| *e > ts);
+        self.leases.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod semaphore_tests {
+
+    use super::*;
+
+    const LIMIT: Limit = Limit {
+        count: 2,
+        window: 30_000,
+        burst: 0,
+        max_delay: 0,
+        windows: Vec::new(),
+    };
+
+    // testcase-based rather than macro-based to simplify state across cases
+    #[test]
+    fn test_hit_and_release() {
+        struct TestCase {
+            name: &'static str,
+            ts: u64,
+            release: bool,
+            allowed: bool,
+            count: u64,
+            reset: u64,
+        }
+
+        let mut sem = Semaphore::create(&LIMIT);
+        let mut leases = Vec::new();
+        let testcases = vec![
+            TestCase {
+                name: "first lease",
+                ts: 10_000,
+                release: false,
+                allowed: true,
+                count: 1,
+                reset: 40_000,
+            },
+            TestCase {
+                name: "second lease",
+                ts: 15_000,
+                release: false,
+                allowed: true,
+                count: 2,
+                reset: 45_000,
+            },
+            TestCase {
+                name: "all leases held",
+                ts: 20_000,
+                release: false,
+                allowed: false,
+                count: 2,
+                reset: 45_000,
+            },
+            TestCase {
+                name: "lease released",
+                ts: 20_000,
+                release: true,
+                allowed: true,
+                count: 2,
+                reset: 50_000,
+            },
+            TestCase {
+                name: "leases timed out",
+                ts: 90_000,
+                release: false,
+                allowed: true,
+                count: 1,
+                reset: 120_000,
+            },
+        ];
+
+        for tc in testcases {
+            if tc.release {
+                let lease = leases.pop().expect("no lease to release");
+                assert!(sem.release(lease), "lease not released for {}", tc.name);
+            }
+
+            let status = sem.hit(&LIMIT, tc.ts, 1);
+            assert_eq!(
+                (status.allowed, status.count, status.reset),
+                (tc.allowed, tc.count, tc.reset),
+                "status did not match for {}",
+                tc.name
+            );
+            assert_eq!(status.lease.is_some(), tc.allowed, "lease did not match for {}", tc.name);
+            leases.extend(status.lease);
+        }
+    }
+
+    #[test]
+    fn test_release_unknown() {
+        let mut sem = Semaphore::create(&LIMIT);
+        let lease = sem.hit(&LIMIT, 10_000, 1).lease.expect("no lease");
+
+        assert!(!sem.release(lease + 1), "released unknown lease");
+        assert!(sem.release(lease), "lease not released");
+        assert!(!sem.release(lease), "lease released twice");
+    }
+
+    #[test]
+    fn test_cost() {
+        let mut sem = Semaphore::create(&LIMIT);
+        let lease = sem.hit(&LIMIT, 10_000, 2).lease.expect("no lease for cost of limit");
+
+        let status = sem.hit(&LIMIT, 10_000, 1);
+        assert!(!status.allowed, "all leases held by one cost");
+        assert_eq!(status.retry_after, 30_000, "retry once lease times out");
+        assert!(sem.release(lease), "lease not released");
+        assert_eq!(sem.hit(&LIMIT, 10_000, 1).count, 1, "cost released with lease");
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut sem = Semaphore::create(&LIMIT);
+        sem.hit(&LIMIT, 10_000, 1);
+        sem.hit(&LIMIT, 10_000, 1);
+
+        assert!(!sem.peek(&LIMIT, 20_000, 1).allowed, "all leases held");
+        assert!(sem.peek(&LIMIT, 40_000, 1).allowed, "leases timed out");
+        assert_eq!(sem.peek(&LIMIT, 20_000, 1).lease, None, "peek took a lease");
+        assert_eq!(sem.leases.len(), 2, "peek dropped leases");
+    }
+
+    macro_rules! semaphore_expire_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                fn $name() {
+                    let (vals, now, len, expired) = $value;
+
+                    let mut sem = Semaphore::new();
+                    for v in vals {
+                        sem.hit(&Limit { count: 10, ..LIMIT }, v, 1);
+                    }
+
+                    assert_eq!(sem.expire(now, 60_000), expired);
+                    assert_eq!(sem.leases.len(), len);
+                }
+            )*
+        }
+    }
+
+    semaphore_expire_tests! {
+        semaphore_expire_empty: (vec![], 50_000, 0, true),
+        semaphore_expire_some: (vec![10_000, 25_000, 40_000], 50_000, 2, false),
+        semaphore_expire_all: (vec![10_000, 15_000, 20_000], 50_000, 0, true),
+    }
+}
@@ -0,0 +1,601 @@
+use super::{Limit, Limiter, Status};
+use std::collections::VecDeque;
+
+/// SlidingLog records the timestamp and cost of every allowed hit, giving an exact count of hits
+/// in the window ending now. Denied hits are not recorded, so a key recovers as soon as its oldest
+/// allowed hit leaves the window.
+#[derive(Debug, Default)]
+pub struct SlidingLog {
+    // timestamps in milliseconds and their costs, oldest first
+    log: VecDeque<(u64, u64)>,
+}
+
+impl SlidingLog {
+    pub fn new() -> Self {
+        Self {
+            log: VecDeque::new(),
+        }
+    }
+
+    // index of the first hit still within the window ending at ts
+    fn start(&self, limit: &Limit, ts: u64) -> usize {
+        self.log.partition_point(|(e, _)| e + limit.window <= ts)
+    }
+
+    // cost of the hits from start onwards
+    fn count(&self, start: usize) -> u64 {
+        self.log.range(start..).map(|(_, c)| c).sum()
+    }
+
+    // how long until enough hits leave the window for a hit to be allowed
+    fn wait(&self, limit: &Limit, ts: u64, count: u64, cost: u64) -> u64 {
+        let mut needed = (count + cost).saturating_sub(limit.count);
+        for (e, c) in self.log.range(self.start(limit, ts)..) {
+            needed = needed.saturating_sub(*c);
+            if needed == 0 {
+                return e + limit.window - ts;
+            }
+        }
+
+        0
+    }
+
+    fn status(&self, limit: &Limit, ts: u64, count: u64, cost: u64, allowed: bool) -> Status {
+        // the newest hit is the last to leave the window
+        let reset = match self.log.back() {
+            Some((e, _)) if e + limit.window > ts => e + limit.window,
+            _ => ts,
+        };
+
+        Status {
+            allowed,
+            count,
+            remaining: limit.count.saturating_sub(count),
+            reset,
+            retry_after: match allowed {
+                true => 0,
+                false => self.wait(limit, ts, count, cost),
+            },
+            ..Default::default()
+        }
+    }
+}
+
+impl Limiter for SlidingLog {
+    fn create(_limit: &Limit) -> Self {
+        SlidingLog::new()
+    }
+
+    fn hit(&mut self, limit: &Limit, ts: u64, cost: u64) -> Status {
+        let start = self.start(limit, ts);
+        self.log.drain(..start);
+
+        let mut count = self.count(0);
+        let allowed = count + cost <= limit.count;
+        if allowed {
+            self.log.push_back((ts, cost));
+            count += cost;
+        }
+
+        self.status(limit, ts, count, cost, allowed)
+    }
+
+    fn peek(&self, limit: &Limit, ts: u64, cost: u64) -> Status {
+        let count = self.count(self.start(limit, ts));
+        self.status(limit, ts, count, cost, count + cost <= limit.count)
+    }
+
+    // refunds take cost off the newest hits first, dropping any left with no cost
+    fn refund(&mut self, _limit: &Limit, _ts: u64, cost: u64) {
+        let mut left = cost;
+        while let Some((_, c)) = self.log.back_mut() {
+            let taken = left.min(*c);
+            *c -= taken;
+            left -= taken;
+            if *c == 0 {
+                self.log.pop_back();
+            }
+            if left == 0 {
+                break;
+            }
+        }
+    }
+
+    fn expire(&mut self, ts: u64, ttl: u64) -> bool {
+        let cutoff = ts.saturating_sub(ttl);
+        let start = self.log.partition_point(|(e, _)| *e < cutoff);
+        self.log.drain(..start);
+
+        self.log.is_empty()
+    }
+}
+
+/// SlidingWindow approximates a sliding window with two fixed, epoch-aligned windows: hits in the
+/// current window count in full, and hits in the previous window are weighted by how much of it
+/// still overlaps the sliding window ending now. Like SlidingLog, denied hits are not recorded.
+#[derive(Debug, Default)]
+pub struct SlidingWindow {
+    // start of the current window, in milliseconds
+    start: u64,
+    prev: u64,
+    curr: u64,
+}
+
+impl SlidingWindow {
+    pub fn new() -> Self {
+        Self {
+            start: 0,
+            prev: 0,
+            curr: 0,
+        }
+    }
+
+    // the window start and counts as of ts, without updating state
+    fn rolled(&self, limit: &Limit, ts: u64) -> (u64, u64, u64) {
+        let window = limit.window.max(1);
+        let start = ts - ts % window;
+
+        match start.saturating_sub(self.start) {
+            0 => (self.start, self.prev, self.curr),
+            d if d == window => (start, self.curr, 0),
+            _ => (start, 0, 0),
+        }
+    }
+
+    // hits counted against the limit at ts, with the previous window's share rounded up. If the
+    // clock has stepped back before the window's start, the previous window counts in full.
+    fn count(limit: &Limit, ts: u64, start: u64, prev: u64, curr: u64) -> u64 {
+        let window = limit.window.max(1);
+        let overlap = window.saturating_sub(ts.saturating_sub(start));
+
+        (prev * overlap).div_ceil(window) + curr
+    }
+
+    // how long until the previous window's weight has dropped far enough for a hit to be allowed,
+    // moving on to the next window if the current one alone is over the limit
+    fn wait(limit: &Limit, ts: u64, (start, prev, curr): (u64, u64, u64), cost: u64) -> u64 {
+        let window = limit.window.max(1);
+        let (start, prev, curr) = match curr + cost > limit.count {
+            true => (start + window, curr, 0),
+            false => (start, prev, curr),
+        };
+        if prev == 0 {
+            return start.saturating_sub(ts);
+        }
+
+        // the weighted previous window has to drop to the headroom left by the current one
+        let headroom = limit.count.saturating_sub(curr + cost);
+        let elapsed = window.saturating_sub(headroom * window / prev);
+        (start + elapsed).saturating_sub(ts)
+    }
+
+    fn status(
+        limit: &Limit,
+        ts: u64,
+        (start, prev, curr): (u64, u64, u64),
+        cost: u64,
+        allowed: bool,
+    ) -> Status {
+        let count = SlidingWindow::count(limit, ts, start, prev, curr);
+        // hits in the current window keep counting until the end of the next one
+        let reset = match (prev, curr) {
+            (_, c) if c > 0 => start + 2 * limit.window,
+            (p, _) if p > 0 => start + limit.window,
+            _ => ts,
+        };
+
+        Status {
+            allowed,
+            count,
+            remaining: limit.count.saturating_sub(count),
+            reset,
+            retry_after: match allowed {
+                true => 0,
+                false => SlidingWindow::wait(limit, ts, (start, prev, curr), cost),
+            },
+            ..Default::default()
+        }
+    }
+}
+
+impl Limiter for SlidingWindow {
+    fn create(_limit: &Limit) -> Self {
+        SlidingWindow::new()
+    }
+
+    fn hit(&mut self, limit: &Limit, ts: u64, cost: u64) -> Status {
+        (self.start, self.prev, self.curr) = self.rolled(limit, ts);
+
+        let count = SlidingWindow::count(limit, ts, self.start, self.prev, self.curr);
+        let allowed = count + cost <= limit.count;
+        if allowed {
+            self.curr += cost;
+        }
+
+        SlidingWindow::status(limit, ts, (self.start, self.prev, self.curr), cost, allowed)
+    }
+
+    fn peek(&self, limit: &Limit, ts: u64, cost: u64) -> Status {
+        let rolled = self.rolled(limit, ts);
+        let count = SlidingWindow::count(limit, ts, rolled.0, rolled.1, rolled.2);
+        let allowed = count + cost <= limit.count;
+
+        SlidingWindow::status(limit, ts, rolled, cost, allowed)
+    }
+
+    // refunds come out of the current window first, then the previous one
+    fn refund(&mut self, limit: &Limit, ts: u64, cost: u64) {
+        (self.start, self.prev, self.curr) = self.rolled(limit, ts);
+
+        let taken = cost.min(self.curr);
+        self.curr -= taken;
+        self.prev = self.prev.saturating_sub(cost - taken);
+    }
+
+    // keys are dropped once the current window started a TTL ago - this is exact as long as the
+    // TTL covers two windows, as neither counter has any weight left by then
+    fn expire(&mut self, ts: u64, ttl: u64) -> bool {
+        self.start.saturating_add(ttl) < ts
+    }
+}
+
+#[cfg(test)]
+mod sliding_log_tests {
+
+    use super::*;
+
+    const LIMIT: Limit = Limit {
+        count: 3,
+        window: 1000,
+        burst: 0,
+        max_delay: 0,
+        windows: Vec::new(),
+    };
+
+    // testcase-based rather than macro-based to simplify state across cases
+    #[test]
+    fn test_hit() {
+        struct TestCase {
+            name: &'static str,
+            ts: u64,
+            allowed: bool,
+            count: u64,
+            remaining: u64,
+            reset: u64,
+            retry_after: u64,
+        }
+
+        let mut log = SlidingLog::create(&LIMIT);
+        let testcases = vec![
+            TestCase {
+                name: "first hit",
+                ts: 1000,
+                allowed: true,
+                count: 1,
+                remaining: 2,
+                reset: 2000,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "second hit",
+                ts: 1400,
+                allowed: true,
+                count: 2,
+                remaining: 1,
+                reset: 2400,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "third hit",
+                ts: 1900,
+                allowed: true,
+                count: 3,
+                remaining: 0,
+                reset: 2900,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "limited just before first hit leaves window",
+                ts: 1999,
+                allowed: false,
+                count: 3,
+                remaining: 0,
+                reset: 2900,
+                retry_after: 1,
+            },
+            TestCase {
+                name: "first hit left window",
+                ts: 2000,
+                allowed: true,
+                count: 3,
+                remaining: 0,
+                reset: 3000,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "two hits left window",
+                ts: 2950,
+                allowed: true,
+                count: 2,
+                remaining: 1,
+                reset: 3950,
+                retry_after: 0,
+            },
+        ];
+
+        for tc in testcases {
+            let status = log.hit(&LIMIT, tc.ts, 1);
+            assert_eq!(
+                status,
+                Status {
+                    allowed: tc.allowed,
+                    count: tc.count,
+                    remaining: tc.remaining,
+                    reset: tc.reset,
+                    retry_after: tc.retry_after,
+                    ..Default::default()
+                },
+                "status did not match for {}",
+                tc.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_cost() {
+        let mut log = SlidingLog::create(&LIMIT);
+
+        assert_eq!(log.hit(&LIMIT, 1000, 2).remaining, 1, "first hit");
+        let status = log.hit(&LIMIT, 1100, 2);
+        assert!(!status.allowed, "cost over remaining");
+        assert_eq!(status.retry_after, 900, "retry once first hit leaves window");
+        assert_eq!(log.hit(&LIMIT, 1100, 1).remaining, 0, "cost of remaining");
+        assert_eq!(log.hit(&LIMIT, 2000, 2).count, 3, "first hit left window");
+        assert_eq!(log.log.len(), 2, "denied hit recorded");
+    }
+
+    #[test]
+    fn test_refund() {
+        let mut log = SlidingLog::create(&LIMIT);
+        log.hit(&LIMIT, 1000, 2);
+        log.hit(&LIMIT, 1100, 1);
+
+        log.refund(&LIMIT, 1200, 2);
+        assert_eq!(log.log, VecDeque::from([(1000, 1)]), "refund from newest hits");
+
+        log.refund(&LIMIT, 1200, 5);
+        assert!(log.log.is_empty(), "refund past no hits");
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut log = SlidingLog::create(&LIMIT);
+        for ts in [1000, 1100, 1200] {
+            log.hit(&LIMIT, ts, 1);
+        }
+
+        assert!(!log.peek(&LIMIT, 1500, 1).allowed, "full window");
+        assert_eq!(log.peek(&LIMIT, 2150, 1).remaining, 2, "partial window");
+        assert_eq!(log.log.len(), 3, "peek dropped hits");
+    }
+
+    macro_rules! sliding_log_expire_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                fn $name() {
+                    let (vals, now, len, expired) = $value;
+
+                    let limit = Limit { window: 60_000, ..LIMIT };
+                    let mut log = SlidingLog::new();
+                    for v in vals {
+                        log.hit(&limit, v, 1);
+                    }
+
+                    assert_eq!(log.expire(now, 30_000), expired);
+                    assert_eq!(log.log.len(), len);
+                }
+            )*
+        }
+    }
+
+    sliding_log_expire_tests! {
+        sliding_log_expire_empty: (vec![], 50_000, 0, true),
+        sliding_log_expire_some: (vec![10_000, 25_000, 40_000], 50_000, 2, false),
+        sliding_log_expire_all: (vec![10_000, 15_000, 19_999], 50_000, 0, true),
+        sliding_log_expire_none: (vec![20_000, 25_000, 40_000], 50_000, 3, false),
+    }
+}
+
+#[cfg(test)]
+mod sliding_window_tests {
+
+    use super::*;
+
+    const LIMIT: Limit = Limit {
+        count: 10,
+        window: 1000,
+        burst: 0,
+        max_delay: 0,
+        windows: Vec::new(),
+    };
+
+    #[test]
+    fn test_rolled() {
+        let mut window = SlidingWindow::new();
+        window.hit(&LIMIT, 10_500, 1);
+        window.hit(&LIMIT, 10_600, 1);
+
+        assert_eq!(window.rolled(&LIMIT, 10_999), (10_000, 0, 2), "same window");
+        assert_eq!(window.rolled(&LIMIT, 11_000), (11_000, 2, 0), "next window");
+        assert_eq!(window.rolled(&LIMIT, 12_000), (12_000, 0, 0), "two windows later");
+    }
+
+    // testcase-based rather than macro-based to simplify state across cases
+    #[test]
+    fn test_hit() {
+        struct TestCase {
+            name: &'static str,
+            ts: u64,
+            hits: u64,
+            allowed: bool,
+            count: u64,
+            remaining: u64,
+            reset: u64,
+            retry_after: u64,
+        }
+
+        let mut window = SlidingWindow::create(&LIMIT);
+        let testcases = vec![
+            TestCase {
+                name: "fill first window",
+                ts: 10_200,
+                hits: 8,
+                allowed: true,
+                count: 8,
+                remaining: 2,
+                reset: 12_000,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "limited in first window",
+                ts: 10_900,
+                hits: 3,
+                allowed: false,
+                count: 10,
+                remaining: 0,
+                reset: 12_000,
+                retry_after: 200,
+            },
+            TestCase {
+                name: "previous window weighted at 3/4",
+                ts: 11_250,
+                hits: 2,
+                allowed: true,
+                count: 10,
+                remaining: 0,
+                reset: 13_000,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "limited by weighted previous window",
+                ts: 11_250,
+                hits: 1,
+                allowed: false,
+                count: 10,
+                remaining: 0,
+                reset: 13_000,
+                retry_after: 50,
+            },
+            TestCase {
+                name: "previous window weighted at 1/10",
+                ts: 11_900,
+                hits: 1,
+                allowed: true,
+                count: 4,
+                remaining: 6,
+                reset: 13_000,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "previous window weighted at 9/10",
+                ts: 12_100,
+                hits: 1,
+                allowed: true,
+                count: 4,
+                remaining: 6,
+                reset: 14_000,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "skipped a window",
+                ts: 14_000,
+                hits: 1,
+                allowed: true,
+                count: 1,
+                remaining: 9,
+                reset: 16_000,
+                retry_after: 0,
+            },
+        ];
+
+        for tc in testcases {
+            let mut status = Status::default();
+            for _ in 0..tc.hits {
+                status = window.hit(&LIMIT, tc.ts, 1);
+            }
+
+            assert_eq!(
+                status,
+                Status {
+                    allowed: tc.allowed,
+                    count: tc.count,
+                    remaining: tc.remaining,
+                    reset: tc.reset,
+                    retry_after: tc.retry_after,
+                    ..Default::default()
+                },
+                "status did not match for {}",
+                tc.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_refund() {
+        let mut window = SlidingWindow::create(&LIMIT);
+        window.hit(&LIMIT, 10_500, 4);
+        window.hit(&LIMIT, 11_100, 2);
+
+        window.refund(&LIMIT, 11_200, 3);
+        assert_eq!((window.prev, window.curr), (3, 0), "refund spilled into previous window");
+
+        window.refund(&LIMIT, 11_200, 5);
+        assert_eq!((window.prev, window.curr), (0, 0), "refund past no hits");
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut window = SlidingWindow::create(&LIMIT);
+        for _ in 0..10 {
+            window.hit(&LIMIT, 10_000, 1);
+        }
+
+        assert!(!window.peek(&LIMIT, 10_500, 1).allowed, "full window");
+        assert_eq!(window.peek(&LIMIT, 11_500, 1).remaining, 5, "half weighted window");
+        assert_eq!(window.curr, 10, "peek rolled window");
+    }
+
+    #[test]
+    fn test_clock_stepped_back() {
+        let mut window = SlidingWindow::create(&LIMIT);
+        window.hit(&LIMIT, 10_500, 4);
+        window.hit(&LIMIT, 11_100, 2);
+
+        let status = window.hit(&LIMIT, 10_900, 1);
+        assert!(status.allowed, "denied after the clock stepped back");
+        assert_eq!((status.count, window.start), (7, 11_000), "counts lost after the clock stepped back");
+        assert_eq!(window.peek(&LIMIT, 9_000, 1).count, 7, "peek before the previous window");
+    }
+
+    macro_rules! sliding_window_expire_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                fn $name() {
+                    let (ts, now, expired) = $value;
+
+                    let mut window = SlidingWindow::new();
+                    window.hit(&LIMIT, ts, 1);
+                    assert_eq!(window.expire(now, 30_000), expired);
+                }
+            )*
+        }
+    }
+
+    sliding_window_expire_tests! {
+        sliding_window_expire_idle: (10_500, 50_000, true),
+        sliding_window_expire_recent: (40_500, 50_000, false),
+        sliding_window_expire_edge: (20_500, 50_000, false),
+    }
+}
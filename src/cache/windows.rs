@@ -0,0 +1,216 @@
+use super::{Limit, Limiter, Status};
+
+/// Windows tracks a key against a limit and each of its further windows at once, such as 10 hits a
+/// second and 500 an hour, keeping separate state for each. Hits are only recorded if every window
+/// allows them, so even counters do not count denied hits, and the status is for the tightest
+/// window.
+#[derive(Debug)]
+pub struct Windows<V> {
+    vals: Vec<V>,
+}
+
+impl<V: Limiter> Windows<V> {
+    // tightest is the status of the window that denied a hit, picking the longest wait if several
+    // did, or else the window with the fewest hits remaining
+    fn tightest(statuses: impl Iterator<Item = Status>) -> Status {
+        statuses
+            .enumerate()
+            .map(|(window, s)| Status { window, ..s })
+            .min_by_key(|s| match s.allowed {
+                true => (true, 0, s.remaining),
+                false => (false, u64::MAX - s.retry_after, 0),
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl<V: Limiter> Limiter for Windows<V> {
+    fn create(limit: &Limit) -> Self {
+        Windows {
+            vals: (0..=limit.windows.len()).map(|i| V::create(&limit.nth(i))).collect(),
+        }
+    }
+
+    fn hit(&mut self, limit: &Limit, ts: u64, cost: u64) -> Status {
+        let peeked = self.peek(limit, ts, cost);
+        if !peeked.allowed {
+            return peeked;
+        }
+
+        Windows::<V>::tightest(
+            self.vals
+                .iter_mut()
+                .enumerate()
+                .map(|(i, v)| v.hit(&limit.nth(i), ts, cost)),
+        )
+    }
+
+    fn peek(&self, limit: &Limit, ts: u64, cost: u64) -> Status {
+        Windows::<V>::tightest(
+            self.vals
+                .iter()
+                .enumerate()
+                .map(|(i, v)| v.peek(&limit.nth(i), ts, cost)),
+        )
+    }
+
+    fn refund(&mut self, limit: &Limit, ts: u64, cost: u64) {
+        for (i, v) in self.vals.iter_mut().enumerate() {
+            v.refund(&limit.nth(i), ts, cost);
+        }
+    }
+
+    fn release(&mut self, lease: u64) -> bool {
+        self.vals.iter_mut().any(|v| v.release(lease))
+    }
+
+    // every window has to expire, and each drops what it can even if an earlier one has not
+    fn expire(&mut self, ts: u64, ttl: u64) -> bool {
+        let mut expired = true;
+        for v in self.vals.iter_mut() {
+            expired &= v.expire(ts, ttl);
+        }
+
+        expired
+    }
+}
+
+#[cfg(test)]
+mod windows_tests {
+
+    use super::*;
+    use crate::cache::{local::TTLValues, sliding::SlidingLog};
+
+    // 2 hits a second, and 3 a minute
+    fn limit() -> Limit {
+        Limit {
+            count: 2,
+            window: 1_000,
+            burst: 0,
+            max_delay: 0,
+            windows: vec![(3, 60_000)],
+        }
+    }
+
+    // testcase-based rather than macro-based to simplify state across cases
+    #[test]
+    fn test_hit() {
+        struct TestCase {
+            name: &'static str,
+            ts: u64,
+            allowed: bool,
+            window: usize,
+            remaining: u64,
+            retry_after: u64,
+        }
+
+        let limit = limit();
+        let mut windows = Windows::<SlidingLog>::create(&limit);
+        let testcases = vec![
+            TestCase {
+                name: "first hit",
+                ts: 10_000,
+                allowed: true,
+                window: 0,
+                remaining: 1,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "second limit spent",
+                ts: 10_100,
+                allowed: true,
+                window: 0,
+                remaining: 0,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "denied by second",
+                ts: 10_200,
+                allowed: false,
+                window: 0,
+                remaining: 0,
+                retry_after: 800,
+            },
+            TestCase {
+                name: "minute limit tightest",
+                ts: 11_500,
+                allowed: true,
+                window: 1,
+                remaining: 0,
+                retry_after: 0,
+            },
+            TestCase {
+                name: "denied by minute",
+                ts: 13_000,
+                allowed: false,
+                window: 1,
+                remaining: 0,
+                retry_after: 57_000,
+            },
+            TestCase {
+                name: "minute over",
+                ts: 71_000,
+                allowed: true,
+                window: 0,
+                remaining: 1,
+                retry_after: 0,
+            },
+        ];
+
+        for tc in testcases {
+            let status = windows.hit(&limit, tc.ts, 1);
+            assert_eq!(
+                (status.allowed, status.window, status.remaining, status.retry_after),
+                (tc.allowed, tc.window, tc.remaining, tc.retry_after),
+                "status did not match for {}",
+                tc.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_denied_not_recorded() {
+        let limit = limit();
+        let mut windows = Windows::<SlidingLog>::create(&limit);
+        windows.hit(&limit, 10_000, 2);
+
+        // denied by the second, so the minute should not count it either
+        assert!(!windows.hit(&limit, 10_500, 1).allowed, "over the second limit");
+        assert!(windows.hit(&limit, 11_500, 1).allowed, "denied hit counted");
+    }
+
+    // counters keep buckets for as long as the longest window, which the shorter one must not count
+    #[test]
+    fn test_longer_window_does_not_tighten() {
+        let limit = Limit {
+            count: 10,
+            window: 1_000,
+            windows: vec![(500, 3_600_000)],
+            ..Default::default()
+        };
+        let mut windows = Windows::<TTLValues>::create(&limit);
+        windows.hit(&limit, 10_000, 10);
+        assert!(!windows.hit(&limit, 10_500, 1).allowed, "over the second limit");
+
+        let status = windows.hit(&limit, 15_000, 10);
+        assert!(status.allowed, "earlier seconds counted against the second limit");
+        assert_eq!((status.window, status.remaining), (0, 0));
+        assert_eq!(
+            windows.vals[1].peek(&limit.nth(1), 16_000, 1).remaining,
+            480,
+            "hour did not count both seconds"
+        );
+    }
+
+    #[test]
+    fn test_refund_and_expire() {
+        let limit = limit();
+        let mut windows = Windows::<SlidingLog>::create(&limit);
+        windows.hit(&limit, 10_000, 2);
+        windows.refund(&limit, 10_000, 1);
+
+        assert!(windows.hit(&limit, 11_500, 2).allowed, "refund not given to every window");
+        assert!(!windows.expire(30_000, 30_000), "expired with minute window in use");
+        assert!(windows.expire(80_000, 30_000), "not expired after every window");
+    }
+}
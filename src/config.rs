@@ -1,10 +1,44 @@
-use std::{collections::HashMap};
+use std::{collections::{BTreeMap, HashMap}, net::SocketAddr, os::unix::{fs::{DirBuilderExt, PermissionsExt}, net::UnixListener}, path::Path};
 use derive_more::{Error, Display};
+use serde::{Deserialize, Serialize};
+use crate::{auth::KeySource, cache::{local, Algorithm, Limit}};
 
 const NAME_SEPARATOR: &str = "=";
 const VAL_DURATION_SEPARATOR: &str = ":";
 const RATE_SEPARTOR: &str = ",";
+const REJECT_FLAG: &str = "reject";
+const HEADER_KEY_PREFIX: &str = "header/";
+const LAYER_PREFIX: &str = "layer/";
+const LAYER_SEPARATOR: &str = "/";
+const WINDOW_PREFIX: &str = "window/";
+const OVERRIDE_PREFIX: &str = "override/";
+const TTL_PREFIX: &str = "ttl/";
+const PARTITIONS_PREFIX: &str = "partitions/";
+const SWEEP_PREFIX: &str = "sweep/";
 pub const HARDCODED_TTL: u64 = 30;
+// reads the config from a YAML or JSON file rather than a config string
+const CONFIG_FLAG: &str = "--config";
+// these replace the config's listen settings, and --listen can be given several times
+const LISTEN_FLAG: &str = "--listen";
+const GRPC_LISTEN_FLAG: &str = "--grpc-listen";
+const SOCKET_MODE_FLAG: &str = "--socket-mode";
+const WORKERS_FLAG: &str = "--workers";
+const BACKLOG_FLAG: &str = "--backlog";
+// flags that take a value, and what the value is
+const VALUE_FLAGS: [(&str, &str); 6] = [
+    (CONFIG_FLAG, "path"),
+    (LISTEN_FLAG, "listener"),
+    (GRPC_LISTEN_FLAG, "listener"),
+    (SOCKET_MODE_FLAG, "mode"),
+    (WORKERS_FLAG, "count"),
+    (BACKLOG_FLAG, "count"),
+];
+// listeners starting with this are Unix domain socket paths rather than TCP addresses
+const UNIX_PREFIX: &str = "unix:";
+// turns the gRPC services off rather than naming a listener
+const NO_LISTENER: &str = "none";
+// the admin API's bearer token, which takes precedence over the config's
+const ADMIN_TOKEN_ENV: &str = "PYRE_ADMIN_TOKEN";
 
 #[derive(Error, Display, Debug, PartialEq)]
 pub struct ConfigError{
@@ -15,13 +49,387 @@ pub struct ConfigError{
 pub struct Config {
     pub configs: HashMap<String, RateConfig>,
     pub ttl_seconds: u64,
+    pub partitions: u32,
+    pub sweep_seconds: u64,
+    pub listen: Listen,
+    // bearer token for the admin API, which is disabled without one
+    pub admin_token: Option<String>,
 }
 
-#[derive(PartialEq, Debug)]
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            configs: HashMap::new(),
+            ttl_seconds: HARDCODED_TTL,
+            partitions: local::DEFAULT_PARTITIONS,
+            sweep_seconds: local::DEFAULT_SWEEP,
+            listen: Listen::default(),
+            admin_token: None,
+        }
+    }
+}
+
+/// Listen is where the REST API and the gRPC services are served, and how the REST API's server
+/// runs.
+#[derive(PartialEq, Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Listen {
+    #[serde(deserialize_with = "deserialize_listeners")]
+    pub http: Vec<Listener>,
+    // the gRPC services are not served without one
+    #[serde(deserialize_with = "deserialize_grpc_listener")]
+    pub grpc: Option<Listener>,
+    // permissions for Unix domain sockets, given in octal such as 660
+    #[serde(deserialize_with = "deserialize_socket_mode")]
+    pub socket_mode: Option<u32>,
+    // worker threads for the REST API, defaulting to one per CPU core
+    pub workers: Option<usize>,
+    // how many connections TCP listeners queue up before refusing more
+    pub backlog: Option<u32>,
+}
+
+impl Default for Listen {
+    fn default() -> Self {
+        Listen {
+            http: vec![Listener::Tcp(SocketAddr::from(([0, 0, 0, 0], 8080)))],
+            grpc: Some(Listener::Tcp(SocketAddr::from(([0, 0, 0, 0], 8081)))),
+            socket_mode: None,
+            workers: None,
+            backlog: None,
+        }
+    }
+}
+
+/// Listener is an address the REST API is served on, either TCP or a Unix domain socket, which
+/// skips the network stack for clients on the same host.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Listener {
+    Tcp(SocketAddr),
+    Unix(std::path::PathBuf),
+}
+
+impl TryFrom<&str> for Listener {
+    type Error = ConfigError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.strip_prefix(UNIX_PREFIX) {
+            Some("") => Err(ConfigError{msg: format!("no path in listener: {}", value)}),
+            Some(path) => Ok(Listener::Unix(path.into())),
+            None => value
+                .parse::<SocketAddr>()
+                .map(Listener::Tcp)
+                .map_err(|e| ConfigError{msg: e.to_string()}),
+        }
+    }
+}
+
+impl std::fmt::Display for Listener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listener::Tcp(addr) => write!(f, "{}", addr),
+            Listener::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+impl Listener {
+    /// bind_unix binds a Unix domain socket at the path, replacing any file there. With a mode, the
+    /// socket is bound in a directory only its owner can enter and only moved to the path once it
+    /// has the mode, so it is never reachable with the permissions the umask gives it.
+    pub fn bind_unix(path: &Path, mode: Option<u32>) -> std::io::Result<UnixListener> {
+        let Some(mode) = mode else {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => return UnixListener::bind(path),
+            }
+        };
+
+        let name = path.file_name().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("no file name in socket path {}", path.display()),
+            )
+        })?;
+        let dir = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+        std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+        let bound = dir.join(name);
+        let listener = UnixListener::bind(&bound).and_then(|l| {
+            std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(mode))?;
+            std::fs::rename(&bound, path)?;
+            Ok(l)
+        });
+        std::fs::remove_dir_all(&dir)?;
+
+        listener
+    }
+}
+
+// deserialize_listeners reads either a single listener or a list of them
+fn deserialize_listeners<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Vec<Listener>, D::Error> {
+    struct Listeners;
+
+    impl<'de> serde::de::Visitor<'de> for Listeners {
+        type Value = Vec<Listener>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "a listener or a list of listeners")
+        }
+
+        fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            Listener::try_from(value).map(|l| vec![l]).map_err(E::custom)
+        }
+
+        fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut listeners = Vec::new();
+            while let Some(value) = seq.next_element::<String>()? {
+                listeners.push(Listener::try_from(value.as_str()).map_err(serde::de::Error::custom)?);
+            }
+
+            Ok(listeners)
+        }
+    }
+
+    d.deserialize_any(Listeners)
+}
+
+// deserialize_grpc_listener reads the gRPC services' listener, or none to not serve them
+fn deserialize_grpc_listener<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<Listener>, D::Error> {
+    struct GrpcListener;
+
+    impl<'de> serde::de::Visitor<'de> for GrpcListener {
+        type Value = Option<Listener>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "a listener or {}", NO_LISTENER)
+        }
+
+        fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            parse_grpc_listener(value).map_err(E::custom)
+        }
+    }
+
+    d.deserialize_str(GrpcListener)
+}
+
+fn parse_grpc_listener(value: &str) -> Result<Option<Listener>, ConfigError> {
+    match value {
+        NO_LISTENER => Ok(None),
+        _ => Listener::try_from(value).map(Some),
+    }
+}
+
+// deserialize_socket_mode reads socket permissions as octal, even when written as a number such as
+// 0660, which YAML and JSON read as decimal
+fn deserialize_socket_mode<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<u32>, D::Error> {
+    struct Mode;
+
+    impl<'de> serde::de::Visitor<'de> for Mode {
+        type Value = Option<u32>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "octal permissions")
+        }
+
+        fn visit_u64<E: serde::de::Error>(self, value: u64) -> Result<Self::Value, E> {
+            self.visit_str(&value.to_string())
+        }
+
+        fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            parse_socket_mode(value).map(Some).map_err(E::custom)
+        }
+    }
+
+    d.deserialize_any(Mode)
+}
+
+// parse_socket_mode parses file permissions given in octal
+fn parse_socket_mode(value: &str) -> Result<u32, ConfigError> {
+    u32::from_str_radix(value, 8)
+        .ok()
+        .filter(|m| *m <= 0o777)
+        .ok_or(ConfigError{msg: format!("invalid socket mode {}, expected octal permissions such as 660", value)})
+}
+
+// Args are the command line's flags and their values, and the arguments around them
+struct Args<'a> {
+    flags: Vec<(&'a str, &'a str)>,
+    rest: Vec<&'a str>,
+}
+
+impl<'a> Args<'a> {
+    fn parse(args: &'a [String]) -> Result<Args<'a>, ConfigError> {
+        let mut parsed = Args { flags: Vec::new(), rest: Vec::new() };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match VALUE_FLAGS.iter().find(|(flag, _)| flag == arg) {
+                Some((flag, value)) => {
+                    let given = args
+                        .next()
+                        .ok_or(ConfigError{msg: format!("missing {} after {}", value, flag)})?;
+                    parsed.flags.push((flag, given.as_str()));
+                }
+                None => parsed.rest.push(arg.as_str()),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    // all are the values of every use of the flag, in order
+    fn all(&self, flag: &'a str) -> impl Iterator<Item = &'a str> + '_ {
+        self.flags.iter().filter(move |(f, _)| *f == flag).map(|(_, v)| *v)
+    }
+
+    // last is the value of the flag's last use, which wins over earlier ones
+    fn last(&self, flag: &'a str) -> Option<&'a str> {
+        self.all(flag).last()
+    }
+
+    // number parses the value of a flag that takes a count of at least 1
+    fn number<T: std::str::FromStr + Default + PartialEq>(&self, flag: &'a str) -> Result<Option<T>, ConfigError>
+    where
+        T::Err: std::fmt::Display,
+    {
+        let Some(value) = self.last(flag) else {
+            return Ok(None);
+        };
+        let n = value
+            .parse::<T>()
+            .map_err(|e| ConfigError{msg: format!("parse {}: {}", flag, e)})?;
+        if n == T::default() {
+            return Err(ConfigError{msg: format!("{} must be at least 1", flag)});
+        }
+
+        Ok(Some(n))
+    }
+}
+
+impl Listen {
+    // apply_args replaces the listen settings with any given on the command line
+    fn apply_args(&mut self, args: &Args) -> Result<(), ConfigError> {
+        let listeners = args
+            .all(LISTEN_FLAG)
+            .map(|l| Listener::try_from(l).map_err(|e| ConfigError{msg: format!("parse {}: {}", LISTEN_FLAG, e)}))
+            .collect::<Result<Vec<_>, ConfigError>>()?;
+        if !listeners.is_empty() {
+            self.http = listeners;
+        }
+        if let Some(grpc) = args.last(GRPC_LISTEN_FLAG) {
+            self.grpc = parse_grpc_listener(grpc)
+                .map_err(|e| ConfigError{msg: format!("parse {}: {}", GRPC_LISTEN_FLAG, e)})?;
+        }
+        if let Some(mode) = args.last(SOCKET_MODE_FLAG) {
+            self.socket_mode = Some(parse_socket_mode(mode)?);
+        }
+        self.workers = args.number(WORKERS_FLAG)?.or(self.workers);
+        self.backlog = args.number(BACKLOG_FLAG)?.or(self.backlog);
+
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Debug, Default, Clone)]
 pub struct RateConfig {
     pub name: String,
     pub count: u64,
     pub window: std::time::Duration,
+    pub algorithm: Algorithm,
+    pub burst: u64,
+    pub max_delay: std::time::Duration,
+    // deny with an HTTP 429 rather than a 200 with allowed set to false
+    pub reject: bool,
+    // where forward-auth requests find their key
+    pub key: KeySource,
+    // other collections every hit is also counted against
+    pub layers: Vec<Layer>,
+    // further counts and windows enforced alongside count and window
+    pub windows: Vec<(u64, std::time::Duration)>,
+    // counts and windows for particular keys, or key prefixes ending in overrides::PREFIX_WILDCARD
+    pub overrides: HashMap<String, Override>,
+    // how long keys are kept after their last hit, which must cover the longest window
+    pub ttl: Option<std::time::Duration>,
+    // how many locks the cache is split across
+    pub partitions: Option<u32>,
+    // how often expired keys are dropped
+    pub sweep: Option<std::time::Duration>,
+}
+
+/// CacheSettings are how a collection's cache is split up and how long it keeps keys for.
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+pub struct CacheSettings {
+    pub partitions: u32,
+    pub ttl_seconds: u64,
+    pub sweep_seconds: u64,
+}
+
+/// Override replaces a collection's count and window for a key, or every key with a prefix.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Override {
+    pub count: u64,
+    pub window: std::time::Duration,
+}
+
+/// Layer is another collection that every hit on a collection is also counted against, such as a
+/// per-tenant limit on top of a per-key one. The layer's key is the hit's key, or with a separator,
+/// the part of it before the first separator.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Layer {
+    pub collection: String,
+    pub separator: Option<String>,
+}
+
+impl Layer {
+    pub fn key<'a>(&self, key: &'a str) -> &'a str {
+        match &self.separator {
+            Some(s) => key.split_once(s.as_str()).map(|(k, _)| k).unwrap_or(key),
+            None => key,
+        }
+    }
+}
+
+impl RateConfig {
+    pub fn limit(&self) -> Limit {
+        Limit {
+            count: self.count,
+            window: self.window.as_millis() as u64,
+            burst: self.burst,
+            max_delay: self.max_delay.as_millis() as u64,
+            windows: self
+                .windows
+                .iter()
+                .map(|(count, window)| (*count, window.as_millis() as u64))
+                .collect(),
+        }
+    }
+
+    /// longest_window is the longest of the collection's windows, including its overrides'.
+    pub fn longest_window(&self) -> std::time::Duration {
+        self.windows
+            .iter()
+            .map(|(_, w)| *w)
+            .chain(self.overrides.values().map(|o| o.window))
+            .fold(self.window, std::cmp::max)
+    }
+
+    /// cache are the settings for the collection's cache, falling back to the config's. Without a
+    /// ttl of its own, the config's is raised to the longest window so no window loses history.
+    pub fn cache(&self, defaults: CacheSettings) -> CacheSettings {
+        let ttl = self.ttl.unwrap_or(self.longest_window());
+        CacheSettings {
+            partitions: self.partitions.unwrap_or(defaults.partitions),
+            ttl_seconds: match self.ttl {
+                Some(_) => seconds(ttl),
+                None => seconds(ttl).max(defaults.ttl_seconds),
+            },
+            sweep_seconds: self.sweep.map(|s| s.as_secs()).unwrap_or(defaults.sweep_seconds),
+        }
+    }
+}
+
+// seconds rounds a time period up to whole seconds, which is what caches keep keys by
+fn seconds(d: std::time::Duration) -> u64 {
+    d.as_millis().div_ceil(1000) as u64
 }
 
 impl TryFrom<String> for Config {
@@ -38,14 +446,273 @@ impl TryFrom<String> for Config {
             .into_iter()
             .map(|e| (e.name.clone(), e))
             .collect();
+        validate_layers(&rates)?;
 
         Ok(Config {
             configs: rates,
             ttl_seconds: HARDCODED_TTL,
+            ..Default::default()
         })
     }
 }
 
+impl Config {
+    /// cache_defaults are the cache settings for collections that do not set their own.
+    pub fn cache_defaults(&self) -> CacheSettings {
+        CacheSettings {
+            partitions: self.partitions,
+            ttl_seconds: self.ttl_seconds,
+            sweep_seconds: self.sweep_seconds,
+        }
+    }
+
+    /// from_args builds the config from the command line, reading the file after CONFIG_FLAG if
+    /// it is given, or else parsing the last argument that is not a flag as a config string. Listen
+    /// flags replace the config's listen settings, and the admin token can also come from the
+    /// environment.
+    pub fn from_args(args: &[String]) -> Result<Config, ConfigError> {
+        let args = Args::parse(args)?;
+        let mut cfg: Config = match args.last(CONFIG_FLAG) {
+            Some(path) => Config::from_file(path)?,
+            None => args
+                .rest
+                .last()
+                .map(|c| c.to_string())
+                .ok_or(ConfigError{msg: "missing a config string".to_string()})?
+                .try_into()?,
+        };
+        cfg.listen.apply_args(&args)?;
+        if let Some(token) = std::env::var(ADMIN_TOKEN_ENV).ok().filter(|t| !t.is_empty()) {
+            cfg.admin_token = Some(token);
+        }
+
+        Ok(cfg)
+    }
+
+    /// path is the config file given after CONFIG_FLAG, if any.
+    pub fn path(args: &[String]) -> Result<Option<&str>, ConfigError> {
+        Ok(Args::parse(args)?.last(CONFIG_FLAG))
+    }
+
+    /// from_file reads a config file, as JSON if it has a .json extension and as YAML otherwise.
+    pub fn from_file(path: &str) -> Result<Config, ConfigError> {
+        let raw = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError{msg: format!("read config file {}: {}", path, e)})?;
+        let json = std::path::Path::new(path).extension().is_some_and(|e| e == "json");
+
+        parse_file(&raw, json).map_err(|e| ConfigError{msg: format!("config file {}: {}", path, e)})
+    }
+}
+
+// FileConfig is the schema of a config file, which is checked field by field on the way to a
+// Config so errors can name the field at fault
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    #[serde(default)]
+    listen: Listen,
+    ttl: Option<String>,
+    partitions: Option<u32>,
+    sweep: Option<String>,
+    admin_token: Option<String>,
+    collections: BTreeMap<String, FileCollection>,
+}
+
+/// FileCollection is a collection in a config file, with the same options as a config string. The
+/// admin API takes and returns collections in the same shape.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct FileCollection {
+    count: u64,
+    window: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    algorithm: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    burst: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_delay: Option<String>,
+    #[serde(default)]
+    reject: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    layers: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    windows: Vec<FileWindow>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    overrides: BTreeMap<String, FileWindow>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    partitions: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sweep: Option<String>,
+}
+
+// FileWindow is a count and time period, for further windows and overrides
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileWindow {
+    count: u64,
+    window: String,
+}
+
+// parse_file parses and validates the contents of a config file
+fn parse_file(raw: &str, json: bool) -> Result<Config, ConfigError> {
+    let file = match json {
+        true => serde_json::from_str::<FileConfig>(raw).map_err(|e| e.to_string()),
+        false => serde_yaml::from_str::<FileConfig>(raw).map_err(|e| e.to_string()),
+    }
+    .map_err(|msg| ConfigError{msg})?;
+
+    file.try_into()
+}
+
+// field_error is an error for a config file field
+fn field_error(field: &str, e: impl std::fmt::Display) -> ConfigError {
+    ConfigError{msg: format!("{}: {}", field, e)}
+}
+
+// parse_field_duration parses a time period from a config file field
+fn parse_field_duration(field: &str, value: &str) -> Result<std::time::Duration, ConfigError> {
+    parse_duration::parse(value).map_err(|e| field_error(field, e))
+}
+
+impl TryFrom<FileConfig> for Config {
+    type Error = ConfigError;
+
+    fn try_from(file: FileConfig) -> Result<Self, Self::Error> {
+        let defaults = Config::default();
+        let ttl_seconds = match file.ttl {
+            Some(t) => parse_field_duration("ttl", &t)?.as_secs(),
+            None => defaults.ttl_seconds,
+        };
+        let sweep_seconds = match file.sweep {
+            Some(s) => parse_field_duration("sweep", &s)?.as_secs(),
+            None => defaults.sweep_seconds,
+        };
+        let partitions = file.partitions.unwrap_or(defaults.partitions);
+        for (field, value) in [("ttl", ttl_seconds), ("sweep", sweep_seconds)] {
+            if value == 0 {
+                return Err(field_error(field, "must be at least 1 second"));
+            }
+        }
+        if partitions == 0 {
+            return Err(field_error("partitions", "must be at least 1"));
+        }
+        if file.listen.http.is_empty() {
+            return Err(field_error("listen.http", "must have at least one listener"));
+        }
+        if file.listen.workers == Some(0) {
+            return Err(field_error("listen.workers", "must be at least 1"));
+        }
+        if file.collections.is_empty() {
+            return Err(field_error("collections", "must have at least one collection"));
+        }
+
+        let configs = file
+            .collections
+            .into_iter()
+            .map(|(name, c)| c.into_rate(name).map(|r| (r.name.clone(), r)))
+            .collect::<Result<HashMap<_, _>, ConfigError>>()?;
+        validate_layers(&configs)?;
+
+        Ok(Config {
+            configs,
+            ttl_seconds,
+            partitions,
+            sweep_seconds,
+            listen: file.listen,
+            admin_token: file.admin_token.filter(|t| !t.is_empty()),
+        })
+    }
+}
+
+impl FileCollection {
+    /// into_rate checks the collection, naming the field at fault if it is invalid.
+    pub fn into_rate(self, name: String) -> Result<RateConfig, ConfigError> {
+        let field = |f: &str| format!("collections.{}.{}", name, f);
+
+        let window = parse_field_duration(&field("window"), &self.window)?;
+        let algorithm = self
+            .algorithm
+            .as_deref()
+            .map(Algorithm::try_from)
+            .transpose()
+            .map_err(|e| field_error(&field("algorithm"), e))?
+            .unwrap_or_default();
+        let burst = match (algorithm, self.burst) {
+            (_, None) => 0,
+            (Algorithm::TokenBucket | Algorithm::Gcra, Some(b)) => b,
+            (a, Some(_)) => return Err(field_error(&field("burst"), format!("{:?} does not take a burst", a))),
+        };
+        let max_delay = match (algorithm, self.max_delay) {
+            (_, None) => std::time::Duration::ZERO,
+            (Algorithm::LeakyBucket, Some(d)) => parse_field_duration(&field("max_delay"), &d)?,
+            (a, Some(_)) => {
+                return Err(field_error(&field("max_delay"), format!("{:?} does not take a max delay", a)))
+            }
+        };
+        let key = self
+            .key
+            .as_deref()
+            .map(KeySource::try_from)
+            .transpose()
+            .map_err(|e| field_error(&field("key"), e))?
+            .unwrap_or_default();
+        let layers = self
+            .layers
+            .iter()
+            .enumerate()
+            .map(|(i, l)| Layer::try_from(l.as_str()).map_err(|e| field_error(&field(&format!("layers[{}]", i)), e)))
+            .collect::<Result<Vec<_>, ConfigError>>()?;
+        let windows = self
+            .windows
+            .iter()
+            .enumerate()
+            .map(|(i, w)| {
+                parse_field_duration(&field(&format!("windows[{}].window", i)), &w.window).map(|d| (w.count, d))
+            })
+            .collect::<Result<Vec<_>, ConfigError>>()?;
+        check_windows(algorithm, &windows).map_err(|e| field_error(&field("windows"), e))?;
+        let overrides = self
+            .overrides
+            .iter()
+            .map(|(k, o)| {
+                let field = field(&format!("overrides.{}", k));
+                if o.count == 0 {
+                    return Err(field_error(&format!("{}.count", field), "must be at least 1"));
+                }
+                let window = parse_field_duration(&format!("{}.window", field), &o.window)?;
+
+                Ok((k.clone(), Override { count: o.count, window }))
+            })
+            .collect::<Result<HashMap<_, _>, ConfigError>>()?;
+        let ttl = self.ttl.map(|t| parse_field_duration(&field("ttl"), &t)).transpose()?;
+        let sweep = self.sweep.map(|s| parse_field_duration(&field("sweep"), &s)).transpose()?;
+
+        let rate = RateConfig {
+            name,
+            count: self.count,
+            window,
+            algorithm,
+            burst,
+            max_delay,
+            reject: self.reject,
+            key,
+            layers,
+            windows,
+            overrides,
+            ttl,
+            partitions: self.partitions,
+            sweep,
+        };
+        check_cache(&rate).map_err(|e| field_error(&format!("collections.{}", rate.name), e))?;
+
+        Ok(rate)
+    }
+}
+
 impl TryFrom<&str> for RateConfig {
     type Error =  ConfigError;
 
@@ -61,26 +728,320 @@ impl TryFrom<&str> for RateConfig {
             .to_string();
 
         let mut rate_split = rate.split(VAL_DURATION_SEPARATOR).collect::<Vec<&str>>();
+        // anything after count and window configures the algorithm, apart from flags which can be
+        // anywhere among the options
+        let mut options = rate_split.split_off(rate_split.len().min(2));
+        let reject = options.contains(&REJECT_FLAG);
+        options.retain(|e| *e != REJECT_FLAG);
+        let layers = options
+            .iter()
+            .filter(|e| e.starts_with(LAYER_PREFIX))
+            .map(|e| Layer::try_from(*e))
+            .collect::<Result<Vec<Layer>, ConfigError>>()?;
+        options.retain(|e| !e.starts_with(LAYER_PREFIX));
+        let windows = options
+            .iter()
+            .filter_map(|e| e.strip_prefix(WINDOW_PREFIX))
+            .map(parse_window)
+            .collect::<Result<Vec<_>, ConfigError>>()?;
+        options.retain(|e| !e.starts_with(WINDOW_PREFIX));
+        let overrides = options
+            .iter()
+            .filter_map(|e| e.strip_prefix(OVERRIDE_PREFIX))
+            .map(parse_override)
+            .collect::<Result<HashMap<_, _>, ConfigError>>()?;
+        options.retain(|e| !e.starts_with(OVERRIDE_PREFIX));
+        let ttl = take_option(&mut options, TTL_PREFIX)
+            .map(|e| parse_duration::parse(e).map_err(|e| ConfigError{msg: format!("parse ttl: {}", e)}))
+            .transpose()?;
+        let partitions = take_option(&mut options, PARTITIONS_PREFIX)
+            .map(|e| e.parse::<u32>().map_err(|e| ConfigError{msg: format!("parse partitions: {}", e)}))
+            .transpose()?;
+        let sweep = take_option(&mut options, SWEEP_PREFIX)
+            .map(|e| parse_duration::parse(e).map_err(|e| ConfigError{msg: format!("parse sweep: {}", e)}))
+            .transpose()?;
+        let key = options
+            .iter()
+            .find_map(|e| KeySource::try_from(*e).ok())
+            .unwrap_or_default();
+        options.retain(|e| KeySource::try_from(*e).is_err());
+        let mut options = options.into_iter();
+        let algorithm = options
+            .next()
+            .map(Algorithm::try_from)
+            .transpose()?
+            .unwrap_or_default();
+        // shaping algorithms take a max delay, and bursting algorithms a burst
+        let (mut burst, mut max_delay) = (0, std::time::Duration::ZERO);
+        match (algorithm, options.next()) {
+            (_, None) => (),
+            (Algorithm::LeakyBucket, Some(e)) => {
+                max_delay = parse_duration::parse(e)
+                    .map_err(|e| ConfigError{msg: format!("parse max delay: {}", e)})?;
+            }
+            (Algorithm::TokenBucket | Algorithm::Gcra, Some(e)) => {
+                burst = e
+                    .parse::<u64>()
+                    .map_err(|e| ConfigError{msg: format!("parse burst: {}", e)})?;
+            }
+            (_, Some(e)) => {
+                return Err(ConfigError{msg: format!("unexpected option in rate: {}", e)});
+            }
+        }
+        if let Some(extra) = options.next() {
+            return Err(ConfigError{msg: format!("unexpected option in rate: {}", extra)});
+        }
+        check_windows(algorithm, &windows)?;
+
         let window_raw = rate_split
         .pop()
         .ok_or(ConfigError{msg: "no window in rate".to_string()})?;
         let window = parse_duration::parse(window_raw)
-        .map_err(|e| ConfigError{msg: format!("parse window: {}", e.to_string())})?;
+        .map_err(|e| ConfigError{msg: format!("parse window: {}", e)})?;
 
         let count = rate_split
             .pop()
             .ok_or(ConfigError{msg: "no count in rate".to_string()})?
             .parse::<u64>()
-            .map_err(|e| ConfigError{msg: format!("parse rate count: {}", e.to_string())})?;
+            .map_err(|e| ConfigError{msg: format!("parse rate count: {}", e)})?;
 
-        Ok(RateConfig {
+        let rate = RateConfig {
             name,
             count,
             window,
+            algorithm,
+            burst,
+            max_delay,
+            reject,
+            key,
+            layers,
+            windows,
+            overrides,
+            ttl,
+            partitions,
+            sweep,
+        };
+        check_cache(&rate)?;
+
+        Ok(rate)
+    }
+}
+
+// take_option removes the first option with the prefix, returning its value
+fn take_option<'a>(options: &mut Vec<&'a str>, prefix: &str) -> Option<&'a str> {
+    let i = options.iter().position(|e| e.starts_with(prefix))?;
+
+    options.remove(i).strip_prefix(prefix)
+}
+
+// check_cache rejects cache settings that would cut a window's history short or never sweep
+fn check_cache(cfg: &RateConfig) -> Result<(), ConfigError> {
+    if cfg.partitions == Some(0) {
+        return Err(ConfigError{msg: "partitions must be at least 1".to_string()});
+    }
+    for (option, value) in [("ttl", cfg.ttl), ("sweep", cfg.sweep)] {
+        if value.is_some_and(|v| v.as_secs() == 0) {
+            return Err(ConfigError{msg: format!("{} must be at least 1 second", option)});
+        }
+    }
+    if let Some(ttl) = cfg.ttl.filter(|t| *t < cfg.longest_window()) {
+        return Err(ConfigError{
+            msg: format!("ttl {:?} is shorter than the longest window, {:?}", ttl, cfg.longest_window()),
+        });
+    }
+
+    Ok(())
+}
+
+// parse_override parses an override from an override/key/count/time period option
+fn parse_override(value: &str) -> Result<(String, Override), ConfigError> {
+    let (key, window) = value
+        .split_once(LAYER_SEPARATOR)
+        .ok_or(ConfigError{msg: format!("no count in override: {}", value)})?;
+    if key.is_empty() {
+        return Err(ConfigError{msg: format!("no key in override: {}", value)});
+    }
+    let (count, window) = parse_window(window)?;
+    if count == 0 {
+        return Err(ConfigError{msg: format!("override count must be at least 1: {}", value)});
+    }
+
+    Ok((key.to_string(), Override { count, window }))
+}
+
+// file_duration writes a time period as it would be read from a config file
+fn file_duration(d: std::time::Duration) -> String {
+    format!("{}ms", d.as_millis())
+}
+
+impl From<&RateConfig> for FileCollection {
+    fn from(cfg: &RateConfig) -> Self {
+        FileCollection {
+            count: cfg.count,
+            window: file_duration(cfg.window),
+            algorithm: Some(cfg.algorithm.to_string()),
+            burst: (cfg.burst > 0).then_some(cfg.burst),
+            max_delay: (!cfg.max_delay.is_zero()).then(|| file_duration(cfg.max_delay)),
+            reject: cfg.reject,
+            key: Some(cfg.key.to_string()),
+            layers: cfg.layers.iter().map(Layer::to_string).collect(),
+            windows: cfg
+                .windows
+                .iter()
+                .map(|(count, window)| FileWindow { count: *count, window: file_duration(*window) })
+                .collect(),
+            overrides: cfg
+                .overrides
+                .iter()
+                .map(|(k, o)| (k.clone(), FileWindow { count: o.count, window: file_duration(o.window) }))
+                .collect(),
+            ttl: cfg.ttl.map(file_duration),
+            partitions: cfg.partitions,
+            sweep: cfg.sweep.map(file_duration),
+        }
+    }
+}
+
+// check_windows rejects further windows for algorithms that hand out leases and delays, which are
+// per hit, so they cannot be split across windows
+fn check_windows(algorithm: Algorithm, windows: &[(u64, std::time::Duration)]) -> Result<(), ConfigError> {
+    if !windows.is_empty() && [Algorithm::Concurrency, Algorithm::LeakyBucket].contains(&algorithm) {
+        return Err(ConfigError{msg: format!("{:?} does not support several windows", algorithm)});
+    }
+
+    Ok(())
+}
+
+// parse_window parses a further window from a window/count/time period option
+fn parse_window(value: &str) -> Result<(u64, std::time::Duration), ConfigError> {
+    let (count, window) = value
+        .split_once(LAYER_SEPARATOR)
+        .ok_or(ConfigError{msg: format!("no time period in window: {}", value)})?;
+    let count = count
+        .parse::<u64>()
+        .map_err(|e| ConfigError{msg: format!("parse window count: {}", e)})?;
+    let window = parse_duration::parse(window)
+        .map_err(|e| ConfigError{msg: format!("parse window: {}", e)})?;
+
+    Ok((count, window))
+}
+
+impl TryFrom<&str> for Layer {
+    type Error = ConfigError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let layer = value.strip_prefix(LAYER_PREFIX).unwrap_or(value);
+        let (collection, separator) = match layer.split_once(LAYER_SEPARATOR) {
+            Some((_, "")) => return Err(ConfigError{msg: format!("empty separator in layer: {}", value)}),
+            Some((c, s)) => (c, Some(s.to_string())),
+            None => (layer, None),
+        };
+        if collection.is_empty() {
+            return Err(ConfigError{msg: format!("no collection in layer: {}", value)});
+        }
+
+        Ok(Layer {
+            collection: collection.to_string(),
+            separator,
         })
     }
 }
 
+// validate_layers checks that layers are other collections without layers of their own, and that
+// neither side hands out leases, which a layered hit would have no way to release
+pub fn validate_layers(configs: &HashMap<String, RateConfig>) -> Result<(), ConfigError> {
+    for cfg in configs.values().filter(|c| !c.layers.is_empty()) {
+        for layer in cfg.layers.iter() {
+            let layered = configs.get(&layer.collection).ok_or_else(|| ConfigError{
+                msg: format!("unknown layer {} in collection {}", layer.collection, cfg.name),
+            })?;
+            if layered.name == cfg.name || !layered.layers.is_empty() {
+                return Err(ConfigError{
+                    msg: format!("layer {} in collection {} has layers of its own", layer.collection, cfg.name),
+                });
+            }
+            if [cfg.algorithm, layered.algorithm].contains(&Algorithm::Concurrency) {
+                return Err(ConfigError{
+                    msg: format!("layer {} in collection {} uses leases", layer.collection, cfg.name),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl std::fmt::Display for Layer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.separator {
+            Some(s) => write!(f, "{}{}{}", self.collection, LAYER_SEPARATOR, s),
+            None => write!(f, "{}", self.collection),
+        }
+    }
+}
+
+impl TryFrom<&str> for KeySource {
+    type Error = ConfigError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "ip" => Ok(KeySource::Ip),
+            "subject" => Ok(KeySource::Subject),
+            "uri" => Ok(KeySource::Uri),
+            _ => match value.strip_prefix(HEADER_KEY_PREFIX) {
+                Some(h) if !h.is_empty() => Ok(KeySource::Header(h.to_string())),
+                _ => Err(ConfigError{msg: format!("unknown key source: {}", value)}),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for KeySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeySource::Ip => write!(f, "ip"),
+            KeySource::Subject => write!(f, "subject"),
+            KeySource::Uri => write!(f, "uri"),
+            KeySource::Header(h) => write!(f, "{}{}", HEADER_KEY_PREFIX, h),
+        }
+    }
+}
+
+impl TryFrom<&str> for Algorithm {
+    type Error = ConfigError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "counter" => Ok(Algorithm::Counter),
+            "token_bucket" => Ok(Algorithm::TokenBucket),
+            "gcra" => Ok(Algorithm::Gcra),
+            "sliding_log" => Ok(Algorithm::SlidingLog),
+            "sliding_window" => Ok(Algorithm::SlidingWindow),
+            "fixed_window" => Ok(Algorithm::FixedWindow),
+            "concurrency" => Ok(Algorithm::Concurrency),
+            "leaky_bucket" => Ok(Algorithm::LeakyBucket),
+            _ => Err(ConfigError{msg: format!("unknown algorithm: {}", value)}),
+        }
+    }
+}
+
+impl std::fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Algorithm::Counter => "counter",
+            Algorithm::TokenBucket => "token_bucket",
+            Algorithm::Gcra => "gcra",
+            Algorithm::SlidingLog => "sliding_log",
+            Algorithm::SlidingWindow => "sliding_window",
+            Algorithm::FixedWindow => "fixed_window",
+            Algorithm::Concurrency => "concurrency",
+            Algorithm::LeakyBucket => "leaky_bucket",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -108,15 +1069,18 @@ mod tests {
                         name: "foo".to_string(),
                         count: 100,
                         window: std::time::Duration::from_secs(60),
+                        ..Default::default()
                     }),
                     ("bar".to_string(),
                     RateConfig{
                         name: "bar".to_string(),
                         count: 1000,
                         window: std::time::Duration::from_secs(30),
+                        ..Default::default()
                     })
                 ]),
-                ttl_seconds: HARDCODED_TTL
+                ttl_seconds: HARDCODED_TTL,
+                ..Default::default()
             })
         ),
         empty_config: (
@@ -135,5 +1099,665 @@ mod tests {
             "foo=100:50 minuten",
             Err::<Config, ConfigError>(ConfigError{msg: r#"parse window: UnknownUnitError: "minuten" is not a known unit"#.to_string()}),
         ),
+        token_bucket_with_burst: (
+            "foo=10:1 second:token_bucket:50,bar=100:1 minute:counter",
+            Ok(Config{
+                configs: HashMap::from([(
+                    "foo".to_string(),
+                    RateConfig{
+                        name: "foo".to_string(),
+                        count: 10,
+                        window: std::time::Duration::from_secs(1),
+                        algorithm: Algorithm::TokenBucket,
+                        burst: 50,
+                        ..Default::default()
+                    }),
+                    ("bar".to_string(),
+                    RateConfig{
+                        name: "bar".to_string(),
+                        count: 100,
+                        window: std::time::Duration::from_secs(60),
+                        algorithm: Algorithm::Counter,
+                        burst: 0,
+                        ..Default::default()
+                    })
+                ]),
+                ttl_seconds: HARDCODED_TTL,
+                ..Default::default()
+            })
+        ),
+        gcra: (
+            "foo=10:1 second:gcra",
+            Ok(Config{
+                configs: HashMap::from([(
+                    "foo".to_string(),
+                    RateConfig{
+                        name: "foo".to_string(),
+                        count: 10,
+                        window: std::time::Duration::from_secs(1),
+                        algorithm: Algorithm::Gcra,
+                        burst: 0,
+                        ..Default::default()
+                    }),
+                ]),
+                ttl_seconds: HARDCODED_TTL,
+                ..Default::default()
+            })
+        ),
+        unknown_algorithm: (
+            "foo=100:1 minute:bucket",
+            Err::<Config, ConfigError>(ConfigError{msg: "unknown algorithm: bucket".to_string()}),
+        ),
+        bad_burst: (
+            "foo=100:1 minute:token_bucket:lots",
+            Err::<Config, ConfigError>(ConfigError{msg: "parse burst: invalid digit found in string".to_string()}),
+        ),
+        leaky_bucket_with_max_delay: (
+            "foo=10:1 second:leaky_bucket:250ms",
+            Ok(Config{
+                configs: HashMap::from([(
+                    "foo".to_string(),
+                    RateConfig{
+                        name: "foo".to_string(),
+                        count: 10,
+                        window: std::time::Duration::from_secs(1),
+                        algorithm: Algorithm::LeakyBucket,
+                        max_delay: std::time::Duration::from_millis(250),
+                        ..Default::default()
+                    }),
+                ]),
+                ttl_seconds: HARDCODED_TTL,
+                ..Default::default()
+            })
+        ),
+        reject: (
+            "foo=10:1 second:reject,bar=10:1 second:gcra:5:reject",
+            Ok(Config{
+                configs: HashMap::from([(
+                    "foo".to_string(),
+                    RateConfig{
+                        name: "foo".to_string(),
+                        count: 10,
+                        window: std::time::Duration::from_secs(1),
+                        reject: true,
+                        ..Default::default()
+                    }),
+                    ("bar".to_string(),
+                    RateConfig{
+                        name: "bar".to_string(),
+                        count: 10,
+                        window: std::time::Duration::from_secs(1),
+                        algorithm: Algorithm::Gcra,
+                        burst: 5,
+                        reject: true,
+                        ..Default::default()
+                    })
+                ]),
+                ttl_seconds: HARDCODED_TTL,
+                ..Default::default()
+            })
+        ),
+        key_sources: (
+            "foo=10:1 second:header/X-Api-Key,bar=10:1 second:gcra:subject:5",
+            Ok(Config{
+                configs: HashMap::from([(
+                    "foo".to_string(),
+                    RateConfig{
+                        name: "foo".to_string(),
+                        count: 10,
+                        window: std::time::Duration::from_secs(1),
+                        key: KeySource::Header("X-Api-Key".to_string()),
+                        ..Default::default()
+                    }),
+                    ("bar".to_string(),
+                    RateConfig{
+                        name: "bar".to_string(),
+                        count: 10,
+                        window: std::time::Duration::from_secs(1),
+                        algorithm: Algorithm::Gcra,
+                        burst: 5,
+                        key: KeySource::Subject,
+                        ..Default::default()
+                    })
+                ]),
+                ttl_seconds: HARDCODED_TTL,
+                ..Default::default()
+            })
+        ),
+        burst_without_bursts: (
+            "foo=100:1 minute:sliding_log:50",
+            Err::<Config, ConfigError>(ConfigError{msg: "unexpected option in rate: 50".to_string()}),
+        ),
+        layers: (
+            "tenant=1000:1 minute,ip=100:1 minute,api=10:1 second:gcra:layer/tenant/.:5:layer/ip",
+            Ok(Config{
+                configs: HashMap::from([(
+                    "tenant".to_string(),
+                    RateConfig{
+                        name: "tenant".to_string(),
+                        count: 1000,
+                        window: std::time::Duration::from_secs(60),
+                        ..Default::default()
+                    }),
+                    ("ip".to_string(),
+                    RateConfig{
+                        name: "ip".to_string(),
+                        count: 100,
+                        window: std::time::Duration::from_secs(60),
+                        ..Default::default()
+                    }),
+                    ("api".to_string(),
+                    RateConfig{
+                        name: "api".to_string(),
+                        count: 10,
+                        window: std::time::Duration::from_secs(1),
+                        algorithm: Algorithm::Gcra,
+                        burst: 5,
+                        layers: vec![
+                            Layer{collection: "tenant".to_string(), separator: Some(".".to_string())},
+                            Layer{collection: "ip".to_string(), separator: None},
+                        ],
+                        ..Default::default()
+                    })
+                ]),
+                ttl_seconds: HARDCODED_TTL,
+                ..Default::default()
+            })
+        ),
+        unknown_layer: (
+            "api=10:1 second:layer/tenant",
+            Err::<Config, ConfigError>(ConfigError{msg: "unknown layer tenant in collection api".to_string()}),
+        ),
+        self_layer: (
+            "api=10:1 second:layer/api",
+            Err::<Config, ConfigError>(ConfigError{msg: "layer api in collection api has layers of its own".to_string()}),
+        ),
+        nested_layer: (
+            "org=1000:1 minute,tenant=100:1 minute:layer/org,api=10:1 second:layer/tenant",
+            Err::<Config, ConfigError>(ConfigError{msg: "layer tenant in collection api has layers of its own".to_string()}),
+        ),
+        leased_layer: (
+            "tenant=10:1 minute:concurrency,api=10:1 second:layer/tenant",
+            Err::<Config, ConfigError>(ConfigError{msg: "layer tenant in collection api uses leases".to_string()}),
+        ),
+        empty_layer_separator: (
+            "tenant=1000:1 minute,api=10:1 second:layer/tenant/",
+            Err::<Config, ConfigError>(ConfigError{msg: "empty separator in layer: layer/tenant/".to_string()}),
+        ),
+        windows: (
+            "foo=10:1 second:window/500/1 hour:gcra:window/5000/1 day",
+            Ok(Config{
+                configs: HashMap::from([(
+                    "foo".to_string(),
+                    RateConfig{
+                        name: "foo".to_string(),
+                        count: 10,
+                        window: std::time::Duration::from_secs(1),
+                        algorithm: Algorithm::Gcra,
+                        windows: vec![
+                            (500, std::time::Duration::from_secs(3600)),
+                            (5000, std::time::Duration::from_secs(86400)),
+                        ],
+                        ..Default::default()
+                    }),
+                ]),
+                ttl_seconds: HARDCODED_TTL,
+                ..Default::default()
+            })
+        ),
+        window_without_period: (
+            "foo=10:1 second:window/500",
+            Err::<Config, ConfigError>(ConfigError{msg: "no time period in window: 500".to_string()}),
+        ),
+        bad_window_count: (
+            "foo=10:1 second:window/lots/1 hour",
+            Err::<Config, ConfigError>(ConfigError{msg: "parse window count: invalid digit found in string".to_string()}),
+        ),
+        windows_with_leases: (
+            "foo=10:1 second:concurrency:window/500/1 hour",
+            Err::<Config, ConfigError>(ConfigError{msg: "Concurrency does not support several windows".to_string()}),
+        ),
+        overrides: (
+            "foo=10:1 second:override/acme.*/100/1 second:gcra:override/bob/5/1 minute",
+            Ok(Config{
+                configs: HashMap::from([(
+                    "foo".to_string(),
+                    RateConfig{
+                        name: "foo".to_string(),
+                        count: 10,
+                        window: std::time::Duration::from_secs(1),
+                        algorithm: Algorithm::Gcra,
+                        overrides: HashMap::from([
+                            ("acme.*".to_string(), Override{count: 100, window: std::time::Duration::from_secs(1)}),
+                            ("bob".to_string(), Override{count: 5, window: std::time::Duration::from_secs(60)}),
+                        ]),
+                        ..Default::default()
+                    }),
+                ]),
+                ttl_seconds: HARDCODED_TTL,
+                ..Default::default()
+            })
+        ),
+        override_without_count: (
+            "foo=10:1 second:override/acme",
+            Err::<Config, ConfigError>(ConfigError{msg: "no count in override: acme".to_string()}),
+        ),
+        override_zero_count: (
+            "foo=10:1 second:override/bob/0/1 minute",
+            Err::<Config, ConfigError>(ConfigError{msg: "override count must be at least 1: bob/0/1 minute".to_string()}),
+        ),
+        override_without_key: (
+            "foo=10:1 second:override//100/1 second",
+            Err::<Config, ConfigError>(ConfigError{msg: "no key in override: /100/1 second".to_string()}),
+        ),
+        cache_settings: (
+            "foo=10:1 second:ttl/2 minutes:gcra:partitions/64:sweep/30 seconds",
+            Ok(Config{
+                configs: HashMap::from([(
+                    "foo".to_string(),
+                    RateConfig{
+                        name: "foo".to_string(),
+                        count: 10,
+                        window: std::time::Duration::from_secs(1),
+                        algorithm: Algorithm::Gcra,
+                        ttl: Some(std::time::Duration::from_secs(120)),
+                        partitions: Some(64),
+                        sweep: Some(std::time::Duration::from_secs(30)),
+                        ..Default::default()
+                    }),
+                ]),
+                ttl_seconds: HARDCODED_TTL,
+                ..Default::default()
+            })
+        ),
+        ttl_shorter_than_window: (
+            "foo=10:1 hour:ttl/30 seconds",
+            Err::<Config, ConfigError>(ConfigError{msg: "ttl 30s is shorter than the longest window, 3600s".to_string()}),
+        ),
+        ttl_shorter_than_override: (
+            "foo=10:1 second:override/bob/5/1 minute:ttl/30 seconds",
+            Err::<Config, ConfigError>(ConfigError{msg: "ttl 30s is shorter than the longest window, 60s".to_string()}),
+        ),
+        zero_partitions: (
+            "foo=10:1 second:partitions/0",
+            Err::<Config, ConfigError>(ConfigError{msg: "partitions must be at least 1".to_string()}),
+        ),
+        zero_sweep: (
+            "foo=10:1 second:sweep/500ms",
+            Err::<Config, ConfigError>(ConfigError{msg: "sweep must be at least 1 second".to_string()}),
+        ),
+        bad_ttl: (
+            "foo=10:1 second:ttl/forever",
+            Err::<Config, ConfigError>(ConfigError{msg: r#"parse ttl: NoValueFoundError: no value found in the string "forever""#.to_string()}),
+        ),
+        bad_max_delay: (
+            "foo=10:1 second:leaky_bucket:a while",
+            Err::<Config, ConfigError>(ConfigError{msg: r#"parse max delay: NoValueFoundError: no value found in the string "a while""#.to_string()}),
+        ),
+    }
+
+    macro_rules! rate_config_cache_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                fn $name() {
+                    let (input, expected): (&str, (u32, u64, u64)) = $value;
+                    let defaults = CacheSettings { partitions: 1024, ttl_seconds: 30, sweep_seconds: 60 };
+
+                    let cfg = RateConfig::try_from(input).expect("invalid rate config");
+                    let settings = cfg.cache(defaults);
+                    assert_eq!((settings.partitions, settings.ttl_seconds, settings.sweep_seconds), expected);
+                }
+            )*
+        }
+    }
+
+    rate_config_cache_tests! {
+        cache_defaults: ("foo=10:1 second", (1024, 30, 60)),
+        cache_ttl_covers_window: ("foo=10:1 hour", (1024, 3600, 60)),
+        cache_ttl_covers_extra_window: ("foo=10:1 second:window/500/1 day", (1024, 86400, 60)),
+        cache_ttl_covers_override: ("foo=10:1 second:override/acme*/100/2 minutes", (1024, 120, 60)),
+        cache_ttl_rounds_up: ("foo=10:90500ms", (1024, 91, 60)),
+        cache_own_ttl: ("foo=10:1 second:ttl/5 seconds", (1024, 5, 60)),
+        cache_own_settings: ("foo=10:1 second:partitions/8:sweep/10 seconds", (8, 30, 10)),
+    }
+
+    #[test]
+    fn test_parse_file() {
+        let yaml = r#"
+listen:
+  http: 127.0.0.1:9080
+ttl: 2 minutes
+partitions: 64
+sweep: 30 seconds
+admin_token: s3cret
+collections:
+  tenant:
+    count: 1000
+    window: 1 minute
+  api:
+    count: 10
+    window: 1 second
+    algorithm: gcra
+    burst: 5
+    reject: true
+    key: header/x-api-key
+    layers: [tenant/.]
+    windows:
+      - count: 500
+        window: 1 hour
+    overrides:
+      acme.*:
+        count: 100
+        window: 1 second
+    ttl: 2 hours
+    partitions: 16
+"#;
+
+        let expected = Config {
+            configs: HashMap::from([
+                ("tenant".to_string(), RateConfig {
+                    name: "tenant".to_string(),
+                    count: 1000,
+                    window: std::time::Duration::from_secs(60),
+                    ..Default::default()
+                }),
+                ("api".to_string(), RateConfig {
+                    name: "api".to_string(),
+                    count: 10,
+                    window: std::time::Duration::from_secs(1),
+                    algorithm: Algorithm::Gcra,
+                    burst: 5,
+                    reject: true,
+                    key: KeySource::Header("x-api-key".to_string()),
+                    layers: vec![Layer { collection: "tenant".to_string(), separator: Some(".".to_string()) }],
+                    windows: vec![(500, std::time::Duration::from_secs(3600))],
+                    overrides: HashMap::from([
+                        ("acme.*".to_string(), Override { count: 100, window: std::time::Duration::from_secs(1) }),
+                    ]),
+                    ttl: Some(std::time::Duration::from_secs(7200)),
+                    partitions: Some(16),
+                    ..Default::default()
+                }),
+            ]),
+            ttl_seconds: 120,
+            partitions: 64,
+            sweep_seconds: 30,
+            listen: Listen {
+                http: vec![Listener::Tcp("127.0.0.1:9080".parse().expect("invalid address"))],
+                ..Default::default()
+            },
+            admin_token: Some("s3cret".to_string()),
+        };
+        assert_eq!(parse_file(yaml, false), Ok(expected));
+
+        let json = r#"{"collections": {"foo": {"count": 10, "window": "1 second", "algorithm": "sliding_log"}}}"#;
+        assert_eq!(
+            parse_file(json, true),
+            Ok(Config {
+                configs: HashMap::from([("foo".to_string(), RateConfig {
+                    name: "foo".to_string(),
+                    count: 10,
+                    window: std::time::Duration::from_secs(1),
+                    algorithm: Algorithm::SlidingLog,
+                    ..Default::default()
+                })]),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_listen() {
+        let yaml = r#"
+listen:
+  http:
+    - 127.0.0.1:9080
+    - unix:/run/pyre/pyre.sock
+  grpc: unix:/run/pyre/grpc.sock
+  socket_mode: 0660
+  workers: 2
+  backlog: 128
+collections:
+  foo:
+    count: 10
+    window: 1 second
+"#;
+
+        let cfg = parse_file(yaml, false).expect("invalid config");
+        assert_eq!(
+            cfg.listen,
+            Listen {
+                http: vec![
+                    Listener::Tcp("127.0.0.1:9080".parse().expect("invalid address")),
+                    Listener::Unix("/run/pyre/pyre.sock".into()),
+                ],
+                grpc: Some(Listener::Unix("/run/pyre/grpc.sock".into())),
+                socket_mode: Some(0o660),
+                workers: Some(2),
+                backlog: Some(128),
+            }
+        );
+
+        let json = r#"{"listen": {"http": "unix:/run/pyre.sock", "grpc": "none", "socket_mode": "600"}, "collections": {"foo": {"count": 10, "window": "1 second"}}}"#;
+        let cfg = parse_file(json, true).expect("invalid config");
+        assert_eq!(
+            (cfg.listen.http, cfg.listen.grpc, cfg.listen.socket_mode),
+            (vec![Listener::Unix("/run/pyre.sock".into())], None, Some(0o600))
+        );
+    }
+
+    macro_rules! listener_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                fn $name() {
+                    let (input, expected): (&str, Result<Listener, ConfigError>) = $value;
+                    let listener = Listener::try_from(input);
+                    assert_eq!(listener, expected);
+                    if let Ok(l) = listener {
+                        assert_eq!(l.to_string(), input, "listener did not display as parsed");
+                    }
+                }
+            )*
+        }
+    }
+
+    listener_tests! {
+        listener_tcp: ("127.0.0.1:8080", Ok(Listener::Tcp(SocketAddr::from(([127, 0, 0, 1], 8080))))),
+        listener_tcp_v6: ("[::1]:8080", Ok(Listener::Tcp("[::1]:8080".parse().expect("invalid address")))),
+        listener_unix: ("unix:/run/pyre.sock", Ok(Listener::Unix("/run/pyre.sock".into()))),
+        listener_unix_no_path: ("unix:", Err(ConfigError{msg: "no path in listener: unix:".to_string()})),
+        listener_hostname: ("localhost:8080", Err(ConfigError{msg: "invalid socket address syntax".to_string()})),
+    }
+
+    macro_rules! parse_file_error_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                fn $name() {
+                    let (input, expected) = $value;
+                    let err = parse_file(input, false).expect_err("did not error as expected");
+                    assert!(err.msg.contains(expected), "error does not match expected: {}", err.msg);
+                }
+            )*
+        }
+    }
+
+    parse_file_error_tests! {
+        file_unknown_field: (
+            "collections:\n  foo:\n    count: 10\n    window: 1 second\n    limit: 5\n",
+            "collections.foo: unknown field `limit`",
+        ),
+        file_missing_count: ("collections:\n  foo:\n    window: 1 second\n", "collections.foo: missing field `count`"),
+        file_bad_address: (
+            "listen:\n  http: localhost\ncollections:\n  foo:\n    count: 10\n    window: 1 second\n",
+            "listen.http: invalid socket address syntax",
+        ),
+        file_no_collections: ("collections: {}\n", "collections: must have at least one collection"),
+        file_no_listeners: (
+            "listen:\n  http: []\ncollections:\n  foo:\n    count: 10\n    window: 1 second\n",
+            "listen.http: must have at least one listener",
+        ),
+        file_bad_listener: (
+            "listen:\n  http:\n    - 127.0.0.1:8080\n    - 'unix:'\ncollections:\n  foo:\n    count: 10\n    window: 1 second\n",
+            "listen.http: no path in listener: unix:",
+        ),
+        file_bad_grpc_listener: (
+            "listen:\n  grpc: 'unix:'\ncollections:\n  foo:\n    count: 10\n    window: 1 second\n",
+            "listen.grpc: no path in listener: unix:",
+        ),
+        file_bad_socket_mode: (
+            "listen:\n  socket_mode: 0990\ncollections:\n  foo:\n    count: 10\n    window: 1 second\n",
+            "listen.socket_mode: invalid socket mode 0990",
+        ),
+        file_zero_workers: (
+            "listen:\n  workers: 0\ncollections:\n  foo:\n    count: 10\n    window: 1 second\n",
+            "listen.workers: must be at least 1",
+        ),
+        file_zero_partitions: (
+            "partitions: 0\ncollections:\n  foo:\n    count: 10\n    window: 1 second\n",
+            "partitions: must be at least 1",
+        ),
+        file_bad_ttl: (
+            "ttl: soon\ncollections:\n  foo:\n    count: 10\n    window: 1 second\n",
+            "ttl: ",
+        ),
+        file_bad_window: ("collections:\n  foo:\n    count: 10\n    window: often\n", "collections.foo.window: "),
+        file_bad_algorithm: (
+            "collections:\n  foo:\n    count: 10\n    window: 1 second\n    algorithm: fast\n",
+            "collections.foo.algorithm: unknown algorithm: fast",
+        ),
+        file_burst_without_bucket: (
+            "collections:\n  foo:\n    count: 10\n    window: 1 second\n    burst: 5\n",
+            "collections.foo.burst: Counter does not take a burst",
+        ),
+        file_bad_key: (
+            "collections:\n  foo:\n    count: 10\n    window: 1 second\n    key: cookie\n",
+            "collections.foo.key: unknown key source: cookie",
+        ),
+        file_bad_extra_window: (
+            "collections:\n  foo:\n    count: 10\n    window: 1 second\n    windows:\n      - count: 5\n        window: later\n",
+            "collections.foo.windows[0].window: ",
+        ),
+        file_windows_with_leases: (
+            "collections:\n  foo:\n    count: 10\n    window: 1 second\n    algorithm: concurrency\n    windows:\n      - count: 5\n        window: 1 minute\n",
+            "collections.foo.windows: Concurrency does not support several windows",
+        ),
+        file_zero_override: (
+            "collections:\n  foo:\n    count: 10\n    window: 1 second\n    overrides:\n      acme:\n        count: 0\n        window: 1 second\n",
+            "collections.foo.overrides.acme.count: must be at least 1",
+        ),
+        file_ttl_shorter_than_window: (
+            "collections:\n  foo:\n    count: 10\n    window: 1 hour\n    ttl: 30 seconds\n",
+            "collections.foo: ttl 30s is shorter than the longest window, 3600s",
+        ),
+        file_bad_collection_sweep: (
+            "collections:\n  foo:\n    count: 10\n    window: 1 second\n    sweep: often\n",
+            "collections.foo.sweep: ",
+        ),
+        file_unknown_layer: (
+            "collections:\n  foo:\n    count: 10\n    window: 1 second\n    layers: [bar]\n",
+            "unknown layer bar in collection foo",
+        ),
+    }
+
+    #[test]
+    fn test_file_collection_round_trip() {
+        let configs: Config = "tenant=1000:1 minute,api=10:1 second:gcra:5:reject:header/x-api-key:layer/tenant/.:window/500/1 hour:override/acme*/100/1 second:ttl/2 hours:partitions/16:sweep/10 seconds,queue=5:1 second:leaky_bucket:2 seconds:uri"
+            .to_string()
+            .try_into()
+            .expect("invalid config");
+
+        for cfg in configs.configs.values() {
+            assert_eq!(
+                FileCollection::from(cfg).into_rate(cfg.name.clone()).as_ref(),
+                Ok(cfg),
+                "{} changed on the way through a file collection",
+                cfg.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_bind_unix() {
+        let dir = std::env::temp_dir().join(format!("pyre-sockets-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("unable to create socket directory");
+
+        for (name, mode) in [("with-mode.sock", Some(0o600)), ("without-mode.sock", None)] {
+            let path = dir.join(name);
+            std::fs::write(&path, "stale").expect("unable to write stale file");
+
+            let listener = Listener::bind_unix(&path, mode).expect("unable to bind socket");
+            let meta = std::fs::metadata(&path).expect("no socket at path");
+            assert!(std::os::unix::fs::FileTypeExt::is_socket(&meta.file_type()), "{} not replaced by a socket", name);
+            if let Some(mode) = mode {
+                assert_eq!(meta.permissions().mode() & 0o777, mode, "socket does not have its mode");
+            }
+            std::os::unix::net::UnixStream::connect(&path).expect("unable to connect to socket");
+            drop(listener);
+        }
+
+        let left = std::fs::read_dir(&dir)
+            .expect("unable to read socket directory")
+            .map(|e| e.expect("unable to read entry").file_name())
+            .collect::<Vec<_>>();
+        std::fs::remove_dir_all(&dir).expect("unable to remove socket directory");
+        assert_eq!(left.len(), 2, "binding left files behind: {:?}", left);
+    }
+
+    #[test]
+    fn test_from_args() {
+        let path = std::env::temp_dir().join(format!("pyre-config-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"collections": {"foo": {"count": 10, "window": "1 second"}}}"#)
+            .expect("unable to write config file");
+        let path = path.to_str().expect("invalid path").to_string();
+
+        let from_file = Config::from_args(&[CONFIG_FLAG.to_string(), path.clone()]);
+        std::fs::remove_file(&path).expect("unable to remove config file");
+        assert_eq!(from_file, "foo=10:1 second".to_string().try_into(), "file did not match config string");
+
+        assert_eq!(
+            Config::from_args(&["foo=10:1 second".to_string()]),
+            "foo=10:1 second".to_string().try_into(),
+        );
+        assert_eq!(
+            Config::from_args(&[CONFIG_FLAG.to_string()]),
+            Err(ConfigError{msg: "missing path after --config".to_string()}),
+        );
+
+        let args = ["--listen", "127.0.0.1:9080", "foo=10:1 second", "--listen", "unix:/run/pyre.sock", "--grpc-listen", "none", "--socket-mode", "660", "--workers", "4", "--backlog", "128"]
+            .map(String::from);
+        let cfg = Config::from_args(&args).expect("invalid args");
+        assert_eq!(cfg.configs.len(), 1, "config string not found among flags");
+        assert_eq!(
+            cfg.listen,
+            Listen {
+                http: vec![
+                    Listener::Tcp("127.0.0.1:9080".parse().expect("invalid address")),
+                    Listener::Unix("/run/pyre.sock".into()),
+                ],
+                grpc: None,
+                socket_mode: Some(0o660),
+                workers: Some(4),
+                backlog: Some(128),
+            }
+        );
+        for (args, expected) in [
+            (vec!["foo=10:1 second", "--listen"], "missing listener after --listen"),
+            (vec!["--listen", "localhost", "foo=10:1 second"], "parse --listen: invalid socket address syntax"),
+            (vec!["--grpc-listen", "unix:", "foo=10:1 second"], "parse --grpc-listen: no path in listener: unix:"),
+            (vec!["--workers", "0", "foo=10:1 second"], "--workers must be at least 1"),
+            (vec!["--backlog", "lots", "foo=10:1 second"], "parse --backlog: invalid digit found in string"),
+            (vec!["--socket-mode", "rw", "foo=10:1 second"], "invalid socket mode rw, expected octal permissions such as 660"),
+        ] {
+            let args = args.into_iter().map(String::from).collect::<Vec<_>>();
+            assert_eq!(Config::from_args(&args), Err(ConfigError{msg: expected.to_string()}), "args {:?}", args);
+        }
+        assert!(
+            Config::from_args(&[CONFIG_FLAG.to_string(), "/nonexistent/pyre.yaml".to_string()])
+                .is_err_and(|e| e.msg.starts_with("read config file /nonexistent/pyre.yaml")),
+            "missing file did not error"
+        );
     }
 }
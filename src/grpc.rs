@@ -0,0 +1,481 @@
+use crate::{
+    cache,
+    config::RateConfig,
+    rest::{Collections, HTTPError, Handler, Hit},
+};
+use actix_web::{http, web, ResponseError};
+use std::pin::Pin;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{event, instrument, Level};
+
+pub mod pb {
+    tonic::include_proto!("pyre.v1");
+}
+
+use pb::{
+    pyre_server::{Pyre, PyreServer},
+    BatchCheckRequest, BatchCheckResponse, CheckRequest, CheckResponse, ReleaseRequest,
+    ReleaseResponse,
+};
+
+/// Service is the native gRPC API, which shares the REST API's caches and answers with the same
+/// fields, and the same errors as gRPC statuses.
+#[derive(Debug)]
+pub struct Service {
+    handler: web::Data<Handler>,
+}
+
+impl Service {
+    pub fn new(handler: web::Data<Handler>) -> Service {
+        Service { handler }
+    }
+
+    pub fn into_server(self) -> PyreServer<Service> {
+        PyreServer::new(self)
+    }
+}
+
+impl From<HTTPError> for tonic::Status {
+    fn from(e: HTTPError) -> Self {
+        let code = match e.status_code() {
+            http::StatusCode::BAD_REQUEST => tonic::Code::InvalidArgument,
+            _ => tonic::Code::Internal,
+        };
+
+        tonic::Status::new(code, e.to_string())
+    }
+}
+
+#[tonic::async_trait]
+impl Pyre for Service {
+    #[instrument(skip(self))]
+    async fn check(
+        &self,
+        request: tonic::Request<CheckRequest>,
+    ) -> Result<tonic::Response<CheckResponse>, tonic::Status> {
+        check(&self.handler, request.get_ref(), true).map(tonic::Response::new)
+    }
+
+    #[instrument(skip(self))]
+    async fn peek(
+        &self,
+        request: tonic::Request<CheckRequest>,
+    ) -> Result<tonic::Response<CheckResponse>, tonic::Status> {
+        check(&self.handler, request.get_ref(), false).map(tonic::Response::new)
+    }
+
+    #[instrument(skip(self))]
+    async fn refund(
+        &self,
+        request: tonic::Request<CheckRequest>,
+    ) -> Result<tonic::Response<CheckResponse>, tonic::Status> {
+        let req = request.get_ref();
+        let cols = self.handler.collections();
+        let (key, cache, cfg) = lookup(&cols, &req.collection, &req.key)?;
+        if cfg.algorithm == cache::Algorithm::Concurrency {
+            return Err(tonic::Status::invalid_argument(format!(
+                "collection {} uses leases, which are released rather than refunded",
+                cfg.name
+            )));
+        }
+        let hit = self.handler.refund_cost(&cols, cache, cfg, key, cost(req))?;
+
+        Ok(tonic::Response::new(response(&hit)))
+    }
+
+    #[instrument(skip(self))]
+    async fn release(
+        &self,
+        request: tonic::Request<ReleaseRequest>,
+    ) -> Result<tonic::Response<ReleaseResponse>, tonic::Status> {
+        let req = request.get_ref();
+        let cols = self.handler.collections();
+        let (key, cache, cfg) = lookup(&cols, &req.collection, &req.key)?;
+        if cfg.algorithm != cache::Algorithm::Concurrency {
+            return Err(tonic::Status::invalid_argument(format!(
+                "collection {} does not use leases",
+                cfg.name
+            )));
+        }
+
+        let released = cache.release(key, req.lease).map_err(|e| {
+            event!(Level::ERROR, message = "can't release lease", error = %e);
+
+            tonic::Status::internal(format!("failed to release lease: {}", e))
+        })?;
+
+        Ok(tonic::Response::new(ReleaseResponse { released }))
+    }
+
+    #[instrument(skip(self))]
+    async fn batch_check(
+        &self,
+        request: tonic::Request<BatchCheckRequest>,
+    ) -> Result<tonic::Response<BatchCheckResponse>, tonic::Status> {
+        let req = request.get_ref();
+        let cols = self.handler.collections();
+        // keys are checked here, as the batch only finds collections
+        for c in req.checks.iter() {
+            lookup(&cols, &c.collection, &c.key)?;
+        }
+
+        let batch = req
+            .checks
+            .iter()
+            .map(|c| (c.collection.as_str(), c.key.as_str(), cost(c)))
+            .collect::<Vec<_>>();
+        let (hits, denied) = self.handler.batch_hits(&cols, &batch)?;
+
+        Ok(tonic::Response::new(BatchCheckResponse {
+            results: hits.iter().map(response).collect(),
+            allowed: denied.is_none(),
+            denied: denied.map(|d| d as u64),
+        }))
+    }
+
+    type StreamCheckStream = Pin<Box<dyn Stream<Item = Result<CheckResponse, tonic::Status>> + Send>>;
+
+    #[instrument(skip(self))]
+    async fn stream_check(
+        &self,
+        request: tonic::Request<tonic::Streaming<CheckRequest>>,
+    ) -> Result<tonic::Response<Self::StreamCheckStream>, tonic::Status> {
+        let handler = self.handler.clone();
+
+        // tonic ends the stream at the first error, whether from the client or an invalid check
+        let stream = request
+            .into_inner()
+            .map(move |req| req.and_then(|req| check(&handler, &req, true)));
+
+        Ok(tonic::Response::new(Box::pin(stream)))
+    }
+}
+
+// check counts a hit against the key, or only peeks at it if inc is false
+fn check(handler: &Handler, req: &CheckRequest, inc: bool) -> Result<CheckResponse, tonic::Status> {
+    let cols = handler.collections();
+    let (key, cache, cfg) = lookup(&cols, &req.collection, &req.key)?;
+    let hit = handler.hit(&cols, cache, cfg, key, cost(req), inc)?;
+
+    Ok(response(&hit))
+}
+
+// lookup finds the cache and config for the collection, rejecting requests without a key
+fn lookup<'a>(
+    cols: &'a Collections,
+    coll: &str,
+    key: &'a str,
+) -> Result<(&'a str, &'a std::sync::Arc<dyn cache::Cache>, &'a RateConfig), tonic::Status> {
+    if key.is_empty() {
+        tracing::info!("no key in request");
+
+        return Err(tonic::Status::invalid_argument("missing key"));
+    }
+
+    let (cache, cfg) = cols.find(coll)?;
+
+    Ok((key, cache, cfg))
+}
+
+// an unset cost is 0 in proto3, which is treated as the default of 1
+fn cost(req: &CheckRequest) -> u64 {
+    match req.cost {
+        0 => 1,
+        c => c,
+    }
+}
+
+fn response(hit: &Hit) -> CheckResponse {
+    CheckResponse {
+        allowed: hit.val.allowed,
+        limit: hit.limit.nth(hit.val.window).capacity(),
+        count: hit.val.count,
+        remaining: hit.val.remaining,
+        reset_at: hit.val.reset,
+        retry_after_ms: hit.val.retry_after,
+        lease: hit.val.lease,
+        delay_ms: hit.val.delay,
+        layer: hit.layered.then(|| hit.cfg.name.clone()),
+    }
+}
+
+#[cfg(test)]
+mod grpc_tests {
+
+    use super::*;
+    use crate::config;
+    use pb::pyre_client::PyreClient;
+    use std::collections::HashMap;
+    use tonic::transport::Channel;
+
+    // serve starts the service on a random local port, returning a client for it
+    async fn serve(configs: Vec<RateConfig>) -> PyreClient<Channel> {
+        let handler = Handler::new(config::Config {
+            configs: configs.into_iter().map(|c| (c.name.clone(), c)).collect::<HashMap<_, _>>(),
+            ttl_seconds: config::HARDCODED_TTL,
+            ..Default::default()
+        });
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("unable to bind listener");
+        let addr = listener.local_addr().expect("no listener address");
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(Service::new(web::Data::new(handler)).into_server())
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+
+        PyreClient::connect(format!("http://{}", addr))
+            .await
+            .expect("unable to connect to server")
+    }
+
+    fn config(algorithm: cache::Algorithm) -> RateConfig {
+        RateConfig {
+            name: "foo".to_string(),
+            count: 2,
+            window: std::time::Duration::from_secs(60),
+            algorithm,
+            ..Default::default()
+        }
+    }
+
+    fn request(key: &str, cost: u64) -> CheckRequest {
+        CheckRequest {
+            collection: "foo".to_string(),
+            key: key.to_string(),
+            cost,
+        }
+    }
+
+    // testcase-based rather than macro-based to simplify state across cases
+    #[tokio::test]
+    async fn test_check_peek_refund() {
+        #[derive(Debug)]
+        enum Call {
+            Check,
+            Peek,
+            Refund,
+        }
+
+        struct TestCase {
+            name: &'static str,
+            call: Call,
+            cost: u64,
+            allowed: bool,
+            count: u64,
+        }
+
+        let mut client = serve(vec![config(cache::Algorithm::SlidingLog)]).await;
+        let testcases = vec![
+            TestCase {
+                name: "unset cost is 1",
+                call: Call::Check,
+                cost: 0,
+                allowed: true,
+                count: 1,
+            },
+            TestCase {
+                name: "peek does not count",
+                call: Call::Peek,
+                cost: 1,
+                allowed: true,
+                count: 1,
+            },
+            TestCase {
+                name: "cost over remaining",
+                call: Call::Check,
+                cost: 2,
+                allowed: false,
+                count: 1,
+            },
+            TestCase {
+                name: "refund",
+                call: Call::Refund,
+                cost: 1,
+                allowed: true,
+                count: 0,
+            },
+            TestCase {
+                name: "cost of limit after refund",
+                call: Call::Check,
+                cost: 2,
+                allowed: true,
+                count: 2,
+            },
+        ];
+
+        for tc in testcases {
+            let req = request("a", tc.cost);
+            let resp = match tc.call {
+                Call::Check => client.check(req).await,
+                Call::Peek => client.peek(req).await,
+                Call::Refund => client.refund(req).await,
+            }
+            .unwrap_or_else(|e| panic!("unexpected error for {}: {}", tc.name, e))
+            .into_inner();
+
+            assert_eq!(
+                (resp.allowed, resp.count, resp.limit),
+                (tc.allowed, tc.count, 2),
+                "response did not match for {} ({:?})",
+                tc.name,
+                tc.call
+            );
+        }
+    }
+
+    macro_rules! check_error_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[tokio::test]
+                async fn $name() {
+                    let (collection, key, cost, expected): (&str, &str, u64, tonic::Code) = $value;
+
+                    let mut client = serve(vec![config(cache::Algorithm::Counter)]).await;
+                    let err = client
+                        .check(CheckRequest {
+                            collection: collection.to_string(),
+                            key: key.to_string(),
+                            cost,
+                        })
+                        .await
+                        .expect_err("invalid check allowed");
+
+                    assert_eq!(err.code(), expected);
+                }
+            )*
+        }
+    }
+
+    check_error_tests! {
+        check_error_unknown_collection: ("bar", "a", 1, tonic::Code::InvalidArgument),
+        check_error_missing_key: ("foo", "", 1, tonic::Code::InvalidArgument),
+        check_error_cost_over_limit: ("foo", "a", 3, tonic::Code::InvalidArgument),
+    }
+
+    #[tokio::test]
+    async fn test_batch_check() {
+        let mut client = serve(vec![config(cache::Algorithm::SlidingLog)]).await;
+
+        let resp = client
+            .batch_check(BatchCheckRequest {
+                checks: vec![request("a", 1), request("b", 1)],
+            })
+            .await
+            .expect("unexpected error")
+            .into_inner();
+        assert_eq!((resp.allowed, resp.denied), (true, None));
+        assert_eq!(
+            resp.results.iter().map(|r| (r.allowed, r.remaining)).collect::<Vec<_>>(),
+            vec![(true, 1), (true, 1)]
+        );
+
+        // a denied check rolls back the rest of the batch
+        let resp = client
+            .batch_check(BatchCheckRequest {
+                checks: vec![request("b", 1), request("a", 2)],
+            })
+            .await
+            .expect("unexpected error")
+            .into_inner();
+        assert_eq!((resp.allowed, resp.denied), (false, Some(1)));
+        assert_eq!(
+            resp.results.iter().map(|r| (r.allowed, r.remaining)).collect::<Vec<_>>(),
+            vec![(true, 1), (false, 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_batch_check_invalid() {
+        let mut client = serve(vec![config(cache::Algorithm::SlidingLog)]).await;
+
+        for checks in [
+            vec![],
+            vec![request("a", 1), CheckRequest { collection: "bar".to_string(), key: "a".to_string(), cost: 1 }],
+            vec![request("a", 1), request("", 1)],
+            vec![request("a", 1), request("b", 3)],
+        ] {
+            let err = client
+                .batch_check(BatchCheckRequest { checks })
+                .await
+                .expect_err("invalid batch allowed");
+            assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        }
+
+        // none of the valid checks before the invalid ones were counted
+        let resp = client
+            .peek(request("a", 1))
+            .await
+            .expect("unexpected error")
+            .into_inner();
+        assert_eq!(resp.count, 0, "invalid batch counted");
+    }
+
+    #[tokio::test]
+    async fn test_stream_check() {
+        let mut client = serve(vec![config(cache::Algorithm::SlidingLog)]).await;
+
+        let requests = vec![request("a", 1), request("a", 1), request("a", 1), request("a", 3)];
+        let mut stream = client
+            .stream_check(tokio_stream::iter(requests))
+            .await
+            .expect("unexpected error")
+            .into_inner();
+
+        let mut allowed = Vec::new();
+        while let Some(Ok(resp)) = stream.next().await {
+            allowed.push(resp.allowed);
+        }
+
+        assert_eq!(allowed, vec![true, true, false], "responses did not match");
+    }
+
+    #[tokio::test]
+    async fn test_stream_check_error() {
+        let mut client = serve(vec![config(cache::Algorithm::SlidingLog)]).await;
+
+        let requests = vec![request("a", 1), request("a", 3), request("a", 1)];
+        let mut stream = client
+            .stream_check(tokio_stream::iter(requests))
+            .await
+            .expect("unexpected error")
+            .into_inner();
+
+        assert!(stream.next().await.expect("stream ended").is_ok(), "valid check failed");
+        let err = stream
+            .next()
+            .await
+            .expect("stream ended")
+            .expect_err("cost over limit allowed");
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        assert!(stream.next().await.is_none(), "stream continued after error");
+    }
+
+    #[tokio::test]
+    async fn test_check_release() {
+        let mut client = serve(vec![config(cache::Algorithm::Concurrency)]).await;
+
+        let lease = client
+            .check(request("a", 2))
+            .await
+            .expect("unexpected error")
+            .into_inner()
+            .lease
+            .expect("no lease");
+        assert!(!client.check(request("a", 1)).await.expect("unexpected error").into_inner().allowed);
+
+        let release = |lease| ReleaseRequest {
+            collection: "foo".to_string(),
+            key: "a".to_string(),
+            lease,
+        };
+        assert!(client.release(release(lease)).await.expect("unexpected error").into_inner().released);
+        assert!(!client.release(release(lease)).await.expect("unexpected error").into_inner().released);
+        assert!(client.check(request("a", 1)).await.expect("unexpected error").into_inner().allowed);
+
+        let err = client.refund(request("a", 1)).await.expect_err("refunded lease");
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    }
+}
@@ -1,14 +1,17 @@
-use std::io::{Error, ErrorKind};
+use std::{future::Future, io::Error, pin::Pin};
 use actix_web::{
     web::{self, Data},
     App, HttpServer,
 };
-use tracing;
-use tracing_subscriber;
 
+mod auth;
 mod cache;
 mod rest;
+mod rls;
 mod config;
+mod grpc;
+mod overrides;
+mod reload;
 
 #[actix_web::main]
 async fn main() -> Result<(), Box<Error>> {
@@ -22,28 +25,110 @@ async fn main() -> Result<(), Box<Error>> {
         .map_err(|err| eprintln!("Unable to set global default subscriber: {}", err))
         .unwrap();
 
-    let mut args = std::env::args().collect::<Vec<String>>();
-    let cfg = args.pop().ok_or(config::ConfigError{msg: "missing a config string".to_string()}).map_err(to_io_err)?;
-    let linker = cfg.try_into().map_err(to_io_err)?;
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let linker = config::Config::from_args(&args).map_err(to_io_err)?;
+    let listen = linker.listen.clone();
 
     let handler = rest::Handler::new(linker);
     let wrapper = Data::new(handler);
 
-    HttpServer::new(move || {
+    // the gRPC services share the REST API's caches
+    let router = tonic::transport::Server::builder()
+        .add_service(grpc::Service::new(wrapper.clone()).into_server())
+        .add_service(rls::Service::new(wrapper.clone()).into_server());
+    let grpc: Pin<Box<dyn Future<Output = Result<(), tonic::transport::Error>>>> = match &listen.grpc {
+        Some(config::Listener::Tcp(addr)) => Box::pin(router.serve(*addr)),
+        Some(config::Listener::Unix(path)) => {
+            let uds = config::Listener::bind_unix(path, listen.socket_mode)?;
+            uds.set_nonblocking(true)?;
+            let uds = tokio::net::UnixListener::from_std(uds)?;
+            Box::pin(router.serve_with_incoming(tokio_stream::wrappers::UnixListenerStream::new(uds)))
+        }
+        // the gRPC services are turned off, leaving only the REST API
+        None => Box::pin(std::future::ready(Ok(()))),
+    };
+
+    let watch = reload::watch(wrapper.clone(), args, listen.clone(), reload::POLL);
+
+    let mut server = HttpServer::new(move || {
         App::new()
             .wrap(tracing_actix_web::TracingLogger::default())
             .app_data(wrapper.clone())
+            .service(
+                web::resource("rate/{collection}/{key}")
+                    .route(web::get().to(rest::Handler::handle))
+                    .route(web::post().to(rest::Handler::handle)),
+            )
+            .service(
+                web::resource("peek/{collection}/{key}")
+                    .route(web::get().to(rest::Handler::peek))
+                    .route(web::post().to(rest::Handler::peek)),
+            )
+            .service(
+                web::resource("refund/{collection}/{key}")
+                    .route(web::get().to(rest::Handler::refund))
+                    .route(web::post().to(rest::Handler::refund)),
+            )
+            .service(
+                web::resource("acquire/{collection}/{key}")
+                    .route(web::get().to(rest::Handler::acquire))
+                    .route(web::post().to(rest::Handler::acquire)),
+            )
+            .route("batch", web::post().to(rest::Handler::batch))
             .route(
-                "rate/{collection}/{key}",
-                web::get().to(rest::Handler::handle),
-            )
-    })
-    .bind(("0.0.0.0", 8080))?
-    .run()
-    .await
-    .map_err(|e| Box::new(e))
+                "admin/collections",
+                web::get().to(rest::Handler::list_collections),
+            )
+            .service(
+                web::resource("admin/collections/{collection}")
+                    .route(web::get().to(rest::Handler::get_collection))
+                    .route(web::put().to(rest::Handler::put_collection))
+                    .route(web::post().to(rest::Handler::put_collection))
+                    .route(web::delete().to(rest::Handler::delete_collection)),
+            )
+            .route(
+                "admin/overrides/{collection}",
+                web::get().to(rest::Handler::overrides),
+            )
+            .service(
+                web::resource("admin/overrides/{collection}/{key}")
+                    .route(web::put().to(rest::Handler::set_override))
+                    .route(web::post().to(rest::Handler::set_override))
+                    .route(web::delete().to(rest::Handler::remove_override)),
+            )
+            .route("auth", web::route().to(rest::Handler::auth))
+            .route("auth/{collection}", web::route().to(rest::Handler::auth))
+            .route(
+                "release/{collection}/{key}/{lease}",
+                web::get().to(rest::Handler::release),
+            )
+    });
+    if let Some(workers) = listen.workers {
+        server = server.workers(workers);
+    }
+    // only applies to TCP listeners, as Unix domain sockets are bound with the system's backlog
+    if let Some(backlog) = listen.backlog {
+        server = server.backlog(backlog);
+    }
+    for listener in listen.http.iter() {
+        server = match listener {
+            config::Listener::Tcp(addr) => server.bind(addr)?,
+            config::Listener::Unix(path) => {
+                server.listen_uds(config::Listener::bind_unix(path, listen.socket_mode)?)?
+            }
+        };
+    }
+    let http = server.run();
+
+    tokio::try_join!(
+        async { http.await.map_err(Box::new) },
+        async { grpc.await.map_err(to_io_err) },
+        async { watch.await.map_err(Box::new) },
+    )?;
+
+    Ok(())
 }
 
 fn to_io_err<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> Box<std::io::Error> {
-    Box::new(std::io::Error::new(ErrorKind::Other, err))
+    Box::new(std::io::Error::other(err))
 }
\ No newline at end of file
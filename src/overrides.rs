@@ -0,0 +1,161 @@
+use crate::{
+    cache::Limit,
+    config::{Override, RateConfig},
+};
+use std::{collections::HashMap, sync::RwLock};
+
+/// PREFIX_WILDCARD ends override patterns that match every key with a prefix.
+pub const PREFIX_WILDCARD: char = '*';
+
+/// Overrides replace collections' counts and windows for particular keys, or every key with a
+/// prefix, such as customers on a higher plan. They start out from the config and can be changed
+/// while running. Exact keys win over prefixes, and longer prefixes over shorter ones.
+#[derive(Debug, Default)]
+pub struct Overrides {
+    // overrides by collection, then by key or prefix pattern
+    overrides: RwLock<HashMap<String, HashMap<String, Override>>>,
+}
+
+impl Overrides {
+    /// limit is the collection's limit for the key, with its count and window overridden if the
+    /// key has an override.
+    pub fn limit(&self, cfg: &RateConfig, key: &str) -> Limit {
+        let mut limit = cfg.limit();
+        if let Some(o) = self.get(&cfg.name, key) {
+            limit.count = o.count;
+            limit.window = o.window.as_millis() as u64;
+        }
+
+        limit
+    }
+
+    // get finds the override for the key, if any
+    fn get(&self, coll: &str, key: &str) -> Option<Override> {
+        // overrides are only written under the lock by set and remove, which cannot panic halfway,
+        // so a poisoned lock still holds consistent overrides
+        let overrides = self.overrides.read().unwrap_or_else(|e| e.into_inner());
+        let patterns = overrides.get(coll)?;
+        if let Some(o) = patterns.get(key) {
+            return Some(*o);
+        }
+
+        patterns
+            .iter()
+            .filter_map(|(pattern, o)| {
+                let prefix = pattern.strip_suffix(PREFIX_WILDCARD)?;
+                key.starts_with(prefix).then_some((prefix.len(), *o))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, o)| o)
+    }
+
+    /// list returns the collection's overrides by pattern.
+    pub fn list(&self, coll: &str) -> HashMap<String, Override> {
+        let overrides = self.overrides.read().unwrap_or_else(|e| e.into_inner());
+        overrides.get(coll).cloned().unwrap_or_default()
+    }
+
+    /// set adds or replaces the override for a key or prefix pattern.
+    pub fn set(&self, coll: &str, pattern: &str, o: Override) {
+        let mut overrides = self.overrides.write().unwrap_or_else(|e| e.into_inner());
+        overrides
+            .entry(coll.to_string())
+            .or_default()
+            .insert(pattern.to_string(), o);
+    }
+
+    /// replace swaps out every override for the collection, such as for those in a reloaded config.
+    pub fn replace(&self, coll: &str, patterns: HashMap<String, Override>) {
+        let mut overrides = self.overrides.write().unwrap_or_else(|e| e.into_inner());
+        match patterns.is_empty() {
+            true => overrides.remove(coll),
+            false => overrides.insert(coll.to_string(), patterns),
+        };
+    }
+
+    /// remove drops the override for a key or prefix pattern, returning false if there was none.
+    pub fn remove(&self, coll: &str, pattern: &str) -> bool {
+        let mut overrides = self.overrides.write().unwrap_or_else(|e| e.into_inner());
+        let Some(patterns) = overrides.get_mut(coll) else {
+            return false;
+        };
+
+        let removed = patterns.remove(pattern).is_some();
+        if patterns.is_empty() {
+            overrides.remove(coll);
+        }
+
+        removed
+    }
+}
+
+#[cfg(test)]
+mod overrides_tests {
+
+    use super::*;
+    use std::time::Duration;
+
+    macro_rules! overrides_limit_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                fn $name() {
+                    let (key, expected): (&str, (u64, u64)) = $value;
+
+                    let cfg = RateConfig {
+                        name: "foo".to_string(),
+                        count: 10,
+                        window: Duration::from_secs(1),
+                        overrides: HashMap::from([
+                            ("acme*".to_string(), Override { count: 100, window: Duration::from_secs(1) }),
+                            ("acme.labs*".to_string(), Override { count: 50, window: Duration::from_secs(1) }),
+                            ("acme.labs.bob".to_string(), Override { count: 5, window: Duration::from_secs(60) }),
+                        ]),
+                        ..Default::default()
+                    };
+                    let overrides = Overrides::default();
+                    overrides.replace(&cfg.name, cfg.overrides.clone());
+
+                    let limit = overrides.limit(&cfg, key);
+                    assert_eq!((limit.count, limit.window), expected);
+                }
+            )*
+        }
+    }
+
+    overrides_limit_tests! {
+        overrides_limit_none: ("bob", (10, 1_000)),
+        overrides_limit_prefix: ("acme.alice", (100, 1_000)),
+        overrides_limit_longest_prefix: ("acme.labs.alice", (50, 1_000)),
+        overrides_limit_exact: ("acme.labs.bob", (5, 60_000)),
+    }
+
+    #[test]
+    fn test_set_and_remove() {
+        let cfg = RateConfig {
+            name: "foo".to_string(),
+            count: 10,
+            window: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let overrides = Overrides::default();
+        let o = Override {
+            count: 100,
+            window: Duration::from_secs(60),
+        };
+
+        overrides.set("foo", "acme*", o);
+        assert_eq!(overrides.limit(&cfg, "acme.alice").count, 100, "override not set");
+        assert_eq!(overrides.list("foo"), HashMap::from([("acme*".to_string(), o)]));
+
+        assert!(overrides.remove("foo", "acme*"), "override not removed");
+        assert!(!overrides.remove("foo", "acme*"), "override removed twice");
+        assert_eq!(overrides.limit(&cfg, "acme.alice").count, 10, "override still applied");
+
+        overrides.set("foo", "acme*", o);
+        overrides.replace("foo", HashMap::from([("bob".to_string(), o)]));
+        assert_eq!(overrides.list("foo"), HashMap::from([("bob".to_string(), o)]), "overrides not replaced");
+        overrides.replace("foo", HashMap::new());
+        assert!(overrides.list("foo").is_empty(), "overrides not cleared");
+    }
+}
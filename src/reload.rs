@@ -0,0 +1,97 @@
+use crate::{
+    config::{Config, Listen},
+    rest::Handler,
+};
+use actix_web::web;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{event, Level};
+
+/// POLL is how often the config file is checked for changes.
+pub const POLL: Duration = Duration::from_secs(5);
+
+/// watch reloads the handler's collections from the config file given in the args on SIGHUP, or
+/// when the file's contents change. Configs that fail to load are logged and ignored, so the
+/// current collections stay in place until the file is fixed.
+pub async fn watch(
+    handler: web::Data<Handler>,
+    args: Vec<String>,
+    listen: Listen,
+    poll: Duration,
+) -> std::io::Result<()> {
+    // config strings cannot change, so only config files are watched
+    let Some(path) = Config::path(&args).ok().flatten().map(str::to_string) else {
+        return Ok(());
+    };
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut ticker = tokio::time::interval(poll);
+    let mut last = tokio::fs::read(&path).await.ok();
+
+    loop {
+        let signalled = tokio::select! {
+            _ = hangup.recv() => true,
+            _ = ticker.tick() => false,
+        };
+
+        let contents = tokio::fs::read(&path).await.ok();
+        if !signalled && contents == last {
+            continue;
+        }
+        last = contents;
+
+        reload(&handler, &path, &args, &listen);
+    }
+}
+
+// reload loads the config file into the handler, if it is valid, with the same flags as at startup
+fn reload(handler: &Handler, path: &str, args: &[String], listen: &Listen) {
+    let cfg = match Config::from_args(args) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            event!(Level::ERROR, message = "can't reload config", path, error = %e);
+            return;
+        }
+    };
+
+    // listeners are bound once at startup
+    if cfg.listen != *listen {
+        event!(Level::WARN, message = "listen addresses only change on restart", path);
+    }
+
+    handler.reload(cfg);
+    event!(Level::INFO, message = "reloaded config", path);
+}
+
+#[cfg(test)]
+mod reload_tests {
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_watch() {
+        let path = std::env::temp_dir().join(format!("pyre-reload-{}.yaml", std::process::id()));
+        let path = path.to_str().expect("invalid path").to_string();
+        std::fs::write(&path, "collections:\n  foo:\n    count: 10\n    window: 1 second\n")
+            .expect("unable to write config file");
+
+        let args = vec!["--config".to_string(), path.clone()];
+        let cfg = Config::from_args(&args).expect("invalid config");
+        let listen = cfg.listen.clone();
+        let handler = web::Data::new(Handler::new(cfg));
+        tokio::spawn(watch(handler.clone(), args, listen, Duration::from_millis(10)));
+        let poll = || tokio::time::sleep(Duration::from_millis(100));
+        poll().await;
+
+        std::fs::write(&path, "collections:\n  bar:\n    count: 10\n    window: 1 second\n")
+            .expect("unable to write config file");
+        poll().await;
+        assert!(handler.collections().find("bar").is_ok(), "new collection not loaded");
+        assert!(handler.collections().find("foo").is_err(), "removed collection still served");
+
+        // an invalid config leaves the current collections in place
+        std::fs::write(&path, "collections:\n  baz:\n    count: many\n").expect("unable to write config file");
+        poll().await;
+        std::fs::remove_file(&path).expect("unable to remove config file");
+        assert!(handler.collections().find("bar").is_ok(), "collections dropped by invalid config");
+    }
+}
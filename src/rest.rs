@@ -1,24 +1,176 @@
-use crate::{cache::local, config::{self, RateConfig}};
+use crate::{auth, cache::{self, local}, config::{self, RateConfig}, overrides::Overrides};
 use actix_web::{
     http::{self, header},
     web,
-    HttpRequest, HttpResponse, HttpResponseBuilder, ResponseError,
+    FromRequest, HttpRequest, HttpResponse, HttpResponseBuilder, ResponseError,
 };
 use derive_more::Display;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::{Arc, Mutex, RwLock}};
 use tracing::{event, instrument, Level};
 
+// draft-ietf-httpapi-ratelimit-headers fields, which proxies can pass straight through
+const RATELIMIT_LIMIT: &str = "RateLimit-Limit";
+const RATELIMIT_REMAINING: &str = "RateLimit-Remaining";
+const RATELIMIT_RESET: &str = "RateLimit-Reset";
+const RATELIMIT_POLICY: &str = "RateLimit-Policy";
+const RETRY_AFTER: &str = "Retry-After";
+
 #[derive(Debug)]
 pub struct Handler {
-    caches: HashMap<String, std::sync::Arc<local::Local>>,
+    // swapped out as a whole on reload, so each request sees a single config throughout
+    collections: RwLock<Arc<Collections>>,
+    overrides: Overrides,
+    // bearer token for the admin API, which is disabled without one
+    admin_token: Option<auth::Secret>,
+    // only locked while holding the collections' write lock
+    loaded: Mutex<Loaded>,
+}
+
+// Loaded is the config file's collections as last loaded, and the collections created, replaced or
+// deleted (None) through the admin API since, which reloads keep until the file changes them too
+#[derive(Debug, Default)]
+struct Loaded {
+    file: HashMap<String, RateConfig>,
+    admin: HashMap<String, Option<RateConfig>>,
+}
+
+/// Admin is a request to the admin API with the admin token, which admin routes take as an
+/// extractor so they can only be reached with it.
+#[derive(Debug)]
+pub struct Admin;
+
+impl FromRequest for Admin {
+    type Error = HTTPError;
+    type Future = std::future::Ready<Result<Admin, HTTPError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        let token = req
+            .app_data::<web::Data<Handler>>()
+            .and_then(|h| h.admin_token.as_ref());
+
+        std::future::ready(match (token, auth::bearer(req)) {
+            (None, _) => {
+                tracing::info!("admin request without an admin token configured");
+
+                Err(HTTPError {
+                    error: "the admin API is disabled, as no admin token is configured".to_string(),
+                    code: http::StatusCode::FORBIDDEN,
+                })
+            }
+            (Some(token), Some(given)) if token.matches(given) => Ok(Admin),
+            (Some(_), _) => {
+                tracing::info!("admin request without a valid admin token");
+
+                Err(HTTPError {
+                    error: "missing or invalid admin token".to_string(),
+                    code: http::StatusCode::UNAUTHORIZED,
+                })
+            }
+        })
+    }
+}
+
+/// Collections are the caches and configs requests are served from, along with the cache settings
+/// for collections that do not set their own.
+#[derive(Debug, Default)]
+pub struct Collections {
+    caches: HashMap<String, Arc<dyn cache::Cache>>,
     rates: HashMap<String, RateConfig>,
+    defaults: config::CacheSettings,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Response {
     pub allowed: bool,
+    pub limit: u64,
+    pub count: u64,
+    pub remaining: u64,
+    pub reset_at: u64,
+    pub retry_after_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub lease: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub delay_ms: Option<u64>,
+    // for collections with layers, the layer the response is for
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub layer: Option<String>,
+}
+
+/// Hit is the outcome of a hit against a collection and its layers, reported for the layer that
+/// denied it, or else the one with the fewest hits remaining.
+#[derive(Debug)]
+pub struct Hit<'a> {
+    pub cfg: &'a RateConfig,
+    // the collection's limit for the key, which may be overridden
+    pub limit: cache::Limit,
+    pub val: cache::Status,
+    // whether the collection has layers, so responses name the layer they are for
+    pub layered: bool,
+}
+
+// Check is a hit against one key of one collection
+struct Check<'a> {
+    key: String,
+    cache: &'a Arc<dyn cache::Cache>,
+    cfg: &'a RateConfig,
+    limit: cache::Limit,
+    cost: u64,
+}
+
+// CostParams is the cost of a hit, from either the query string or a JSON body
+#[derive(Deserialize)]
+struct CostParams {
+    cost: Option<u64>,
+}
+
+// RejectParams overrides whether the collection rejects denied hits with a 429
+#[derive(Deserialize)]
+struct RejectParams {
+    reject: Option<bool>,
+}
+
+// BatchCheck is one hit in a batch, with the cost defaulting to 1
+#[derive(Deserialize)]
+struct BatchCheck {
+    collection: String,
+    key: String,
+    cost: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BatchResponse {
+    pub allowed: bool,
+    // the index of the first check that was denied
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub denied: Option<usize>,
+    pub results: Vec<Response>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReleaseResponse {
+    pub released: bool,
+}
+
+// OverrideParams is the count and window of an override, with the window defaulting to the
+// collection's
+#[derive(Deserialize)]
+struct OverrideParams {
+    count: u64,
+    window_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct OverrideResponse {
+    pub key: String,
+    pub count: u64,
+    pub window_ms: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RemovedResponse {
+    pub removed: bool,
 }
 
 #[derive(Debug, Display, Serialize, Deserialize)]
@@ -44,39 +196,25 @@ impl ResponseError for HTTPError {
     }
 }
 
-impl Handler {
-
-    pub fn new(linker: config::Config) -> Handler {
-        let mut caches = HashMap::new();
+// collection is the collection URL parameter
+fn collection(req: &HttpRequest) -> Result<&str, HTTPError> {
+    req.match_info().get("collection").ok_or_else(|| {
+        tracing::error!("no collection URL parameter");
 
-        for  (key, rate) in linker.configs.iter() {
-            let local = std::sync::Arc::new(local::Local::new(
-                local::DEFAULT_PARTITIONS,
-                linker.ttl_seconds,
-                rate.window.as_secs(),
-                local::DEFAULT_SWEEP,
-            ));
-            local.start_lru();
-            local.start_clock();
-            caches.insert(key.clone(), local);
+        HTTPError {
+            error: "missing collection parameter".to_string(),
+            code: http::StatusCode::BAD_REQUEST,
         }
+    })
+}
 
-        Handler { caches, rates: linker.configs }
-    }
-
-    #[instrument]
-    pub async fn handle(
-        parent: web::Data<Handler>,
-        req: HttpRequest,
-    ) -> Result<HttpResponse, actix_web::Error> {
-        let coll = req.match_info().get("collection").ok_or_else(|| {
-            tracing::error!("no collection URL parameter");
-
-            HTTPError {
-                error: "missing collection parameter".to_string(),
-                code: http::StatusCode::BAD_REQUEST,
-            }
-        })?;
+impl Collections {
+    // lookup finds the key, cache and config for the collection and key URL parameters
+    fn lookup<'a>(
+        &'a self,
+        req: &'a HttpRequest,
+    ) -> Result<(&'a str, &'a Arc<dyn cache::Cache>, &'a RateConfig), HTTPError> {
+        let coll = collection(req)?;
 
         let key = req.match_info().get("key").ok_or_else(|| {
             tracing::info!("no key URL parameter");
@@ -87,7 +225,17 @@ impl Handler {
             }
         })?;
 
-        let cache = parent.caches.get(coll).ok_or_else(|| {
+        let (cache, cfg) = self.find(coll)?;
+
+        Ok((key, cache, cfg))
+    }
+
+    // find gets the cache and config for a collection
+    pub(crate) fn find(
+        &self,
+        coll: &str,
+    ) -> Result<(&Arc<dyn cache::Cache>, &RateConfig), HTTPError> {
+        let cache = self.caches.get(coll).ok_or_else(|| {
             event!(
                 Level::ERROR,
                 message = "no cache found for provided collection parameter",
@@ -100,16 +248,7 @@ impl Handler {
             }
         })?;
 
-        let val = cache.get_or_create(key, true).map_err(|e| {
-            event!(Level::ERROR, message = "can't get or create val", error = %e);
-
-            HTTPError {
-                error: format!("failed to get_or_create val: {}", e),
-                code: http::StatusCode::INTERNAL_SERVER_ERROR,
-            }
-        })?;
-
-        let cfg = parent.rates.get(coll).ok_or_else(|| {
+        let cfg = self.rates.get(coll).ok_or_else(|| {
             event!(
                 Level::INFO,
                 message = "no linker found for collection, even though cache was found",
@@ -122,184 +261,1177 @@ impl Handler {
             }
         })?;
 
-        let mut resp = HttpResponse::build(http::StatusCode::OK);
-        let resp = resp.insert_header(header::ContentType::json());
-
-        match val > cfg.count {
-            true => Ok(resp.body(json!(Response { allowed: false }).to_string())),
-            false => Ok(resp.body(json!(Response { allowed: true }).to_string())),
-        }
+        Ok((cache, cfg))
     }
 }
 
-#[cfg(test)]
-mod test {
-
-    use super::*;
-    use actix_web::{body::MessageBody, test};
+impl Handler {
 
-    #[test]
-    async fn test_new_handler() {
-        let mut linker = config::Config{ 
-            configs: HashMap::from([
-                ("foo".to_string(),
-                config::RateConfig{
-                    name: "foo".to_string(),
-                    count: 100,
-                    window: std::time::Duration::from_secs(60),
-                }),
-                ("bar".to_string(),
-                config::RateConfig{
-                    name: "bar".to_string(),
-                    count: 1000,
-                    window: std::time::Duration::from_secs(30),
-                }),
-            ]), 
-            ttl_seconds: config::HARDCODED_TTL,
+    pub fn new(linker: config::Config) -> Handler {
+        let handler = Handler {
+            collections: RwLock::new(Arc::new(Collections::default())),
+            overrides: Overrides::default(),
+            admin_token: linker.admin_token.clone().map(auth::Secret::new),
+            loaded: Mutex::default(),
         };
+        handler.reload(linker);
 
-        let handler = Handler::new(linker);
+        handler
+    }
 
-        assert_eq!(
-            handler
-                .caches
-                .get("foo")
-                .expect("no cache with key foo")
-                .ttl(),
-            30
-        );
+    /// reload swaps in the collections of a new config. New collections get caches, and removed
+    /// ones are dropped once in-flight requests are done with them. Changed collections keep their
+    /// caches, and so their counts, unless they need a different kind of cache: a new algorithm,
+    /// a different number of further windows, or new partitions. Collections changed through the
+    /// admin API are kept as they are, unless the new config changes them as well.
+    pub fn reload(&self, mut linker: config::Config) {
+        // held throughout, so reloads cannot interleave
+        let mut current = self.collections.write().unwrap_or_else(|e| e.into_inner());
+        let mut loaded = self.loaded.lock().unwrap_or_else(|e| e.into_inner());
+        let Loaded { file, admin } = &mut *loaded;
 
-        assert_eq!(
-            handler
-                .caches
-                .get("bar")
-                .expect("no cache with key foo")
-                .ttl(),
-            30
-        );
+        let mut replaced = Vec::new();
+        admin.retain(|coll, _| {
+            let kept = file.get(coll) == linker.configs.get(coll);
+            if !kept {
+                replaced.push(coll.clone());
+            }
+            kept
+        });
+
+        let mut configs = linker.configs.clone();
+        for (coll, rate) in admin.iter() {
+            match rate {
+                Some(rate) => configs.insert(coll.clone(), rate.clone()),
+                None => configs.remove(coll),
+            };
+        }
+        if let Err(e) = config::validate_layers(&configs) {
+            event!(Level::WARN, message = "admin changes no longer line up with the config", error = %e);
+            replaced.extend(admin.drain().map(|(coll, _)| coll));
+            configs = linker.configs.clone();
+        }
+        if !replaced.is_empty() {
+            replaced.sort();
+            event!(Level::WARN, message = "config replaced collections changed through the admin API", collections = ?replaced);
+        }
+
+        *file = std::mem::replace(&mut linker.configs, configs);
+        self.swap(&mut current, linker);
     }
 
-    macro_rules! handle_rate_tests {
-        ($($name:ident: $value:expr,)*) => {
-        $(
-            #[test]
-            async fn $name() {
+    // update changes the current collections' configs, checking their layers still line up
+    // before swapping them in as reload does
+    fn update<T>(
+        &self,
+        change: impl FnOnce(&mut HashMap<String, RateConfig>) -> Result<T, HTTPError>,
+    ) -> Result<T, HTTPError> {
+        let mut current = self.collections.write().unwrap_or_else(|e| e.into_inner());
+        let mut rates = current.rates.clone();
+        let changed = change(&mut rates)?;
+        config::validate_layers(&rates).map_err(|e| {
+            tracing::info!("invalid collection change");
+
+            HTTPError {
+                error: e.msg,
+                code: http::StatusCode::BAD_REQUEST,
+            }
+        })?;
 
-                let (count, allowed) = $value;
+        let mut loaded = self.loaded.lock().unwrap_or_else(|e| e.into_inner());
+        for coll in rates.keys().chain(current.rates.keys()) {
+            if rates.get(coll) != current.rates.get(coll) {
+                loaded.admin.insert(coll.clone(), rates.get(coll).cloned());
+            }
+        }
 
-                let allow_two_linker = config::Config{ 
-                    configs: HashMap::from([
-                        ("foo".to_string(), 
-                        config::RateConfig{
-                            name: "foo".to_string(),
-                            count: 2,
-                            window: std::time::Duration::from_secs(60),
-                        }),
-                    ]), 
-                    ttl_seconds: config::HARDCODED_TTL,
-                };
+        let linker = config::Config {
+            configs: rates,
+            ttl_seconds: current.defaults.ttl_seconds,
+            partitions: current.defaults.partitions,
+            sweep_seconds: current.defaults.sweep_seconds,
+            ..Default::default()
+        };
+        self.swap(&mut current, linker);
 
-                let handler = Handler::new(allow_two_linker);
-                let data = web::Data::new(handler);
+        Ok(changed)
+    }
 
-                let mut limited = true;
+    // swap replaces the current collections with the linker's, keeping what caches it can
+    fn swap(&self, current: &mut Arc<Collections>, linker: config::Config) {
+        let defaults = linker.cache_defaults();
 
-                for _ in 0..count {
-                    let req = test::TestRequest::with_uri("http://localhost")
-                        .param("key", "foobar")
-                        .param("collection", "foo")
-                        .method(http::Method::GET)
-                        .to_http_request();
-                    let resp = Handler::handle(data.clone(), req.clone())
-                        .await
-                        .expect("unexpected handler error");
-                    assert_eq!(resp.status(), http::StatusCode::OK);
+        let mut caches = HashMap::new();
+        for (key, rate) in linker.configs.iter() {
+            let settings = rate.cache(defaults);
+            let old = current.rates.get(key);
+            let kept = old
+                .filter(|old| {
+                    old.cache(current.defaults).partitions == settings.partitions
+                        && old.algorithm == rate.algorithm
+                        && old.windows.len() == rate.windows.len()
+                })
+                .and_then(|_| current.caches.get(key).cloned());
 
-                    let body = resp
-                        .into_body()
-                        .try_into_bytes()
-                        .expect("unable to ready body");
-                    let parsed: Response =
-                        serde_json::from_slice(&body[..]).expect("cannot parse as Response");
-                    limited = parsed.allowed;
+            let local = match kept {
+                Some(local) => {
+                    if old != Some(rate) {
+                        event!(Level::INFO, message = "updated collection", collection = key.as_str());
+                    }
+                    local.update(settings.ttl_seconds, settings.sweep_seconds);
+                    local
                 }
-                assert_eq!(allowed, limited);
+                None => {
+                    let local = cache::start(
+                        rate.algorithm,
+                        !rate.windows.is_empty(),
+                        settings.partitions,
+                        settings.ttl_seconds,
+                        settings.sweep_seconds,
+                    );
+                    event!(
+                        Level::INFO,
+                        message = "started cache for collection",
+                        collection = key.as_str(),
+                        algorithm = ?rate.algorithm,
+                        ttl = local.ttl(),
+                    );
+                    local
+                }
+            };
+            caches.insert(key.clone(), local);
+
+            // overrides made at runtime are kept until the config's own overrides change
+            if old.map(|o| &o.overrides) != Some(&rate.overrides) {
+                self.overrides.replace(key, rate.overrides.clone());
             }
-        )*
         }
-    }
 
-    handle_rate_tests! {
-        handle_rate_one_request: (1, true),
-        handle_rate_two_requests: (2, true),
-        handle_rate_three_requests: (3, false),
+        for key in current.rates.keys().filter(|k| !linker.configs.contains_key(*k)) {
+            self.overrides.replace(key, HashMap::new());
+            event!(Level::INFO, message = "removed collection", collection = key.as_str());
+        }
+
+        *current = Arc::new(Collections {
+            caches,
+            rates: linker.configs,
+            defaults,
+        });
     }
 
+    // collections are the current caches and configs, which stay the same for as long as they are
+    // held, even if they are reloaded meanwhile
+    pub(crate) fn collections(&self) -> Arc<Collections> {
+        self.collections.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
 
-    macro_rules! handle_errors_tests {
-        ($($name:ident: $value:expr,)*) => {
-            $(
-                #[test]
-                async fn $name() {
-                    let (code, err, collection, key) = $value; 
+    #[instrument]
+    pub async fn handle(
+        parent: web::Data<Handler>,
+        req: HttpRequest,
+        body: web::Bytes,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let cols = parent.collections();
+        let (key, cache, cfg) = cols.lookup(&req)?;
+        let cost = Handler::cost(&req, &body)?;
+        let reject = Handler::reject(&req, cfg)?;
 
-                    let linker = config::Config{ 
-                        configs: HashMap::from([
-                            ("foo".to_string(),
-                            config::RateConfig{
-                                name: "foo".to_string(),
-                                count: 100,
-                                window: std::time::Duration::from_secs(60),
-                            }),
-                            ("bar".to_string(),
-                            config::RateConfig{
-                                name: "bar".to_string(),
-                                count: 1000,
-                                window: std::time::Duration::from_secs(30),
-                            }),
-                        ]), 
-                        ttl_seconds: config::HARDCODED_TTL,
-                    };
+        let hit = parent.hit(&cols, cache, cfg, key, cost, true)?;
 
-                    let handler = Handler::new(linker);
-                    let data = web::Data::new(handler);
+        Ok(Handler::respond(&hit, reject))
+    }
 
-                    let resp = do_test_request("http://localhost", key, collection, data.clone())
-                    .await
-                    .expect_err("did not error as expected")
-                    .error_response();
+    // auth is the forward-auth route for proxies, which finds the collection and key in the request
+    // rather than the URL. Denied hits are always rejected, as proxies only look at the status.
+    #[instrument]
+    pub async fn auth(
+        parent: web::Data<Handler>,
+        req: HttpRequest,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let coll = match req.match_info().get("collection") {
+            Some(c) => c.to_string(),
+            None => auth::collection(&req).ok_or_else(|| {
+                tracing::info!("no collection for forward-auth request");
 
-                    assert_eq!(
-                        resp.status(),
-                        code,
-                        "unexpected status code",
-                    );
-        
-                    let body = resp
-                        .into_body()
-                        .try_into_bytes()
-                        .expect("unable to ready body");
-                    let parsed: HTTPError =
-                        serde_json::from_slice(&body[..]).expect("cannot parse as HTTPError");
-                    assert!(
-                        parsed.error.contains(err),
-                        "body does not match expected: {}",
-                        parsed.error
-                    );
+                HTTPError {
+                    error: format!("missing collection parameter or {} header", auth::COLLECTION_HEADER),
+                    code: http::StatusCode::BAD_REQUEST,
                 }
-    
-            )*
-        }
+            })?,
+        };
+        let cols = parent.collections();
+        let (cache, cfg) = cols.find(&coll)?;
+
+        let key = cfg.key.key(&req).ok_or_else(|| {
+            event!(
+                Level::INFO,
+                message = "no key for forward-auth request",
+                collection = coll.as_str(),
+                source = ?cfg.key,
+            );
+
+            HTTPError {
+                error: format!("missing key from {:?} for collection {}", cfg.key, coll),
+                code: http::StatusCode::BAD_REQUEST,
+            }
+        })?;
+        // proxies forward the original body, if any, so cost can only come from the query
+        let cost = Handler::cost(&req, &web::Bytes::new())?;
+
+        let hit = parent.hit(&cols, cache, cfg, &key, cost, true)?;
+
+        Ok(Handler::respond(&hit, true))
     }
 
-    handle_errors_tests! {
-        handle_missing_collection_parameter: (
-            http::StatusCode::BAD_REQUEST,
-            "missing collection parameter",
-            None,
-            Some("foo"),
-        ),
-        handle_missing_key_parameter: (
+    #[instrument]
+    pub async fn peek(
+        parent: web::Data<Handler>,
+        req: HttpRequest,
+        body: web::Bytes,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let cols = parent.collections();
+        let (key, cache, cfg) = cols.lookup(&req)?;
+        let cost = Handler::cost(&req, &body)?;
+        let reject = Handler::reject(&req, cfg)?;
+
+        let hit = parent.hit(&cols, cache, cfg, key, cost, false)?;
+
+        Ok(Handler::respond(&hit, reject))
+    }
+
+    #[instrument]
+    pub async fn refund(
+        parent: web::Data<Handler>,
+        req: HttpRequest,
+        body: web::Bytes,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let cols = parent.collections();
+        let (key, cache, cfg) = cols.lookup(&req)?;
+        if cfg.algorithm == cache::Algorithm::Concurrency {
+            return Err(HTTPError {
+                error: format!("collection {} uses leases, which are released rather than refunded", cfg.name),
+                code: http::StatusCode::BAD_REQUEST,
+            }
+            .into());
+        }
+        let cost = Handler::cost(&req, &body)?;
+
+        let hit = parent.refund_cost(&cols, cache, cfg, key, cost)?;
+
+        Ok(Handler::respond(&hit, false))
+    }
+
+    #[instrument]
+    pub async fn acquire(
+        parent: web::Data<Handler>,
+        req: HttpRequest,
+        body: web::Bytes,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let cols = parent.collections();
+        let (key, cache, cfg) = cols.lookup(&req)?;
+        Handler::leased(cfg)?;
+        let cost = Handler::cost(&req, &body)?;
+        let reject = Handler::reject(&req, cfg)?;
+
+        let hit = parent.hit(&cols, cache, cfg, key, cost, true)?;
+
+        Ok(Handler::respond(&hit, reject))
+    }
+
+    #[instrument]
+    pub async fn release(
+        parent: web::Data<Handler>,
+        req: HttpRequest,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let cols = parent.collections();
+        let (key, cache, cfg) = cols.lookup(&req)?;
+        Handler::leased(cfg)?;
+
+        let lease = req
+            .match_info()
+            .get("lease")
+            .and_then(|l| l.parse::<u64>().ok())
+            .ok_or_else(|| {
+                tracing::info!("missing or invalid lease URL parameter");
+
+                HTTPError {
+                    error: "missing or invalid lease parameter".to_string(),
+                    code: http::StatusCode::BAD_REQUEST,
+                }
+            })?;
+
+        let released = cache.release(key, lease).map_err(|e| {
+            event!(Level::ERROR, message = "can't release lease", error = %e);
+
+            HTTPError {
+                error: format!("failed to release lease: {}", e),
+                code: http::StatusCode::INTERNAL_SERVER_ERROR,
+            }
+        })?;
+
+        let mut resp = HttpResponse::build(http::StatusCode::OK);
+        let resp = resp.insert_header(header::ContentType::json());
+
+        Ok(resp.body(json!(ReleaseResponse { released }).to_string()))
+    }
+
+    // batch counts hits against several keys, keeping them only if every one is allowed, along
+    // with the layers of each. Denied batches report the hit that was denied, and where every other
+    // key stands after the rollback.
+    #[instrument]
+    pub async fn batch(
+        parent: web::Data<Handler>,
+        req: HttpRequest,
+        body: web::Bytes,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let batch = serde_json::from_slice::<Vec<BatchCheck>>(&body).map_err(|e| {
+            tracing::info!("invalid batch body");
+
+            HTTPError {
+                error: format!("invalid batch: {}", e),
+                code: http::StatusCode::BAD_REQUEST,
+            }
+        })?;
+
+        let cols = parent.collections();
+        let (hits, denied) = parent.batch_hits(
+            &cols,
+            &batch
+                .iter()
+                .map(|c| (c.collection.as_str(), c.key.as_str(), c.cost.unwrap_or(1)))
+                .collect::<Vec<_>>(),
+        )?;
+
+        // the headers, and any rejection, are for the check that denied the batch, or else the one
+        // with the fewest hits remaining
+        let i = denied.unwrap_or_else(|| {
+            (0..hits.len())
+                .min_by_key(|i| hits[*i].val.remaining)
+                .unwrap_or_default()
+        });
+        if denied.is_some() && Handler::reject(&req, cols.find(&batch[i].collection)?.1)? {
+            return Ok(Handler::respond(&hits[i], true));
+        }
+
+        let mut resp = HttpResponse::build(http::StatusCode::OK);
+        let resp = resp.insert_header(header::ContentType::json());
+        Handler::headers(resp, &hits[i].limit, &hits[i].val);
+
+        Ok(resp.body(
+            json!(BatchResponse {
+                allowed: denied.is_none(),
+                denied,
+                results: hits.iter().map(Handler::response).collect(),
+            })
+            .to_string(),
+        ))
+    }
+
+    // overrides lists the collection's overrides, by key or prefix pattern
+    #[instrument]
+    pub async fn overrides(
+        parent: web::Data<Handler>,
+        _: Admin,
+        req: HttpRequest,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let coll = collection(&req)?;
+        let cols = parent.collections();
+        let (_, cfg) = cols.find(coll)?;
+
+        let mut overrides = parent
+            .overrides
+            .list(&cfg.name)
+            .into_iter()
+            .map(|(key, o)| OverrideResponse {
+                key,
+                count: o.count,
+                window_ms: o.window.as_millis() as u64,
+            })
+            .collect::<Vec<_>>();
+        overrides.sort_by(|a, b| a.key.cmp(&b.key));
+
+        let mut resp = HttpResponse::build(http::StatusCode::OK);
+        let resp = resp.insert_header(header::ContentType::json());
+
+        Ok(resp.body(json!(overrides).to_string()))
+    }
+
+    // set_override replaces the collection's count, and optionally its window, for the key, or for
+    // every key with a prefix if it ends in overrides::PREFIX_WILDCARD
+    #[instrument]
+    pub async fn set_override(
+        parent: web::Data<Handler>,
+        _: Admin,
+        req: HttpRequest,
+        body: web::Bytes,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let cols = parent.collections();
+        let (key, _, cfg) = cols.lookup(&req)?;
+        let invalid = |error: String| {
+            tracing::info!("invalid override");
+
+            HTTPError {
+                error,
+                code: http::StatusCode::BAD_REQUEST,
+            }
+        };
+
+        let params = serde_json::from_slice::<OverrideParams>(&body)
+            .map_err(|e| invalid(format!("invalid override: {}", e)))?;
+        let window = params.window_ms.unwrap_or(cfg.window.as_millis() as u64);
+        if params.count == 0 || window == 0 {
+            return Err(invalid("override count and window_ms must be at least 1".to_string()).into());
+        }
+        // the cache only keeps keys for its ttl, so a longer window would lose history
+        let ttl_seconds = cfg.cache(cols.defaults).ttl_seconds;
+        if window > ttl_seconds * 1000 {
+            return Err(invalid(format!(
+                "override window_ms must be at most the collection's ttl of {} seconds",
+                ttl_seconds
+            ))
+            .into());
+        }
+
+        parent.overrides.set(
+            &cfg.name,
+            key,
+            config::Override {
+                count: params.count,
+                window: std::time::Duration::from_millis(window),
+            },
+        );
+        event!(
+            Level::INFO,
+            message = "set override",
+            collection = cfg.name.as_str(),
+            key,
+            count = params.count,
+            window_ms = window,
+        );
+
+        let mut resp = HttpResponse::build(http::StatusCode::OK);
+        let resp = resp.insert_header(header::ContentType::json());
+
+        Ok(resp.body(
+            json!(OverrideResponse {
+                key: key.to_string(),
+                count: params.count,
+                window_ms: window,
+            })
+            .to_string(),
+        ))
+    }
+
+    // remove_override drops the override for the key or prefix pattern, so it is back on the
+    // collection's limit
+    #[instrument]
+    pub async fn remove_override(
+        parent: web::Data<Handler>,
+        _: Admin,
+        req: HttpRequest,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let cols = parent.collections();
+        let (key, _, cfg) = cols.lookup(&req)?;
+        let removed = parent.overrides.remove(&cfg.name, key);
+        event!(
+            Level::INFO,
+            message = "removed override",
+            collection = cfg.name.as_str(),
+            key,
+            removed,
+        );
+
+        let mut resp = HttpResponse::build(http::StatusCode::OK);
+        let resp = resp.insert_header(header::ContentType::json());
+
+        Ok(resp.body(json!(RemovedResponse { removed }).to_string()))
+    }
+
+    // list_collections lists every collection's config, in the same shape as a config file
+    #[instrument]
+    pub async fn list_collections(
+        parent: web::Data<Handler>,
+        _: Admin,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let cols = parent.collections();
+        let collections = cols
+            .rates
+            .iter()
+            .map(|(name, cfg)| (name.as_str(), config::FileCollection::from(cfg)))
+            .collect::<std::collections::BTreeMap<_, _>>();
+
+        let mut resp = HttpResponse::build(http::StatusCode::OK);
+        let resp = resp.insert_header(header::ContentType::json());
+
+        Ok(resp.body(json!(collections).to_string()))
+    }
+
+    #[instrument]
+    pub async fn get_collection(
+        parent: web::Data<Handler>,
+        _: Admin,
+        req: HttpRequest,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let cols = parent.collections();
+        let (_, cfg) = cols.find(collection(&req)?)?;
+
+        let mut resp = HttpResponse::build(http::StatusCode::OK);
+        let resp = resp.insert_header(header::ContentType::json());
+
+        Ok(resp.body(json!(config::FileCollection::from(cfg)).to_string()))
+    }
+
+    // put_collection creates a collection, or replaces its config, from a JSON body in the same
+    // shape as a config file's collections. Replaced collections keep their counts as they would
+    // on reload.
+    #[instrument]
+    pub async fn put_collection(
+        parent: web::Data<Handler>,
+        _: Admin,
+        req: HttpRequest,
+        body: web::Bytes,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let coll = collection(&req)?;
+        let invalid = |error: String| {
+            tracing::info!("invalid collection");
+
+            HTTPError {
+                error,
+                code: http::StatusCode::BAD_REQUEST,
+            }
+        };
+
+        let cfg = serde_json::from_slice::<config::FileCollection>(&body)
+            .map_err(|e| invalid(format!("invalid collection: {}", e)))?
+            .into_rate(coll.to_string())
+            .map_err(|e| invalid(e.msg))?;
+        let written = config::FileCollection::from(&cfg);
+        let created = parent.update(|rates| Ok(rates.insert(coll.to_string(), cfg).is_none()))?;
+        event!(Level::INFO, message = "put collection", collection = coll, created);
+
+        let mut resp = HttpResponse::build(match created {
+            true => http::StatusCode::CREATED,
+            false => http::StatusCode::OK,
+        });
+        let resp = resp.insert_header(header::ContentType::json());
+
+        Ok(resp.body(json!(written).to_string()))
+    }
+
+    // delete_collection removes a collection. Its cache is dropped, stopping its tasks, once
+    // in-flight requests are done with it.
+    #[instrument]
+    pub async fn delete_collection(
+        parent: web::Data<Handler>,
+        _: Admin,
+        req: HttpRequest,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let coll = collection(&req)?;
+        let removed = parent.update(|rates| Ok(rates.remove(coll).is_some()))?;
+        event!(Level::INFO, message = "deleted collection", collection = coll, removed);
+
+        let mut resp = HttpResponse::build(http::StatusCode::OK);
+        let resp = resp.insert_header(header::ContentType::json());
+
+        Ok(resp.body(json!(RemovedResponse { removed }).to_string()))
+    }
+
+    // hit counts a hit against the key, and the keys of the collection's layers, keeping them only
+    // if every one is allowed, or only peeks at them if inc is false
+    pub(crate) fn hit<'a>(
+        &self,
+        cols: &'a Collections,
+        cache: &'a Arc<dyn cache::Cache>,
+        cfg: &'a RateConfig,
+        key: &str,
+        cost: u64,
+        inc: bool,
+    ) -> Result<Hit<'a>, HTTPError> {
+        let checks = self.checks(cols, cache, cfg, key, cost)?;
+        let (vals, denied) = Handler::hit_all(&checks, inc)?;
+
+        Ok(Handler::report(&checks, &vals, denied, cfg))
+    }
+
+    // batch_hits counts hits against several collections and keys as one, only keeping them if every
+    // one is allowed. Every check is validated before any are counted, and expanded into its layers,
+    // which are counted with the rest of the batch. It returns a hit for each check, and the index
+    // of the check that was denied, if any.
+    pub(crate) fn batch_hits<'a>(
+        &self,
+        cols: &'a Collections,
+        batch: &[(&str, &str, u64)],
+    ) -> Result<(Vec<Hit<'a>>, Option<usize>), HTTPError> {
+        if batch.is_empty() {
+            return Err(HTTPError {
+                error: "batch must have at least one check".to_string(),
+                code: http::StatusCode::BAD_REQUEST,
+            });
+        }
+
+        let mut groups = Vec::with_capacity(batch.len());
+        let mut checks = Vec::new();
+        for (coll, key, cost) in batch.iter() {
+            let (cache, cfg) = cols.find(coll)?;
+            let layered = self.checks(cols, cache, cfg, key, *cost)?;
+
+            groups.push((cfg, checks.len()..checks.len() + layered.len()));
+            checks.extend(layered);
+        }
+
+        let (vals, denied) = Handler::hit_all(&checks, true)?;
+        let hits = groups
+            .iter()
+            .map(|(cfg, group)| {
+                let denied = denied.filter(|d| group.contains(d)).map(|d| d - group.start);
+                Handler::report(&checks[group.clone()], &vals[group.clone()], denied, cfg)
+            })
+            .collect::<Vec<_>>();
+        let denied = denied.and_then(|d| groups.iter().position(|(_, g)| g.contains(&d)));
+
+        Ok((hits, denied))
+    }
+
+    // refund_cost gives back the cost of a hit to the key, and the keys of the collection's layers
+    pub(crate) fn refund_cost<'a>(
+        &self,
+        cols: &'a Collections,
+        cache: &'a Arc<dyn cache::Cache>,
+        cfg: &'a RateConfig,
+        key: &str,
+        cost: u64,
+    ) -> Result<Hit<'a>, HTTPError> {
+        let checks = self.checks(cols, cache, cfg, key, cost)?;
+        let vals = checks
+            .iter()
+            .map(|c| {
+                c.cache.refund(&c.key, &c.limit, c.cost).map_err(|e| {
+                    event!(Level::ERROR, message = "can't refund val", error = %e);
+
+                    HTTPError {
+                        error: format!("failed to refund val: {}", e),
+                        code: http::StatusCode::INTERNAL_SERVER_ERROR,
+                    }
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Handler::report(&checks, &vals, None, cfg))
+    }
+
+    // checks is the hit against the key, followed by one for each of the collection's layers, each
+    // against its limit for its key. The cost is checked against the key's own limit.
+    fn checks<'a>(
+        &self,
+        cols: &'a Collections,
+        cache: &'a Arc<dyn cache::Cache>,
+        cfg: &'a RateConfig,
+        key: &str,
+        cost: u64,
+    ) -> Result<Vec<Check<'a>>, HTTPError> {
+        let limit = self.limit(cfg, key);
+        let cost = Handler::check_cost(cfg, &limit, cost)?;
+        let mut checks = vec![Check {
+            key: key.to_string(),
+            cache,
+            cfg,
+            limit,
+            cost,
+        }];
+
+        for layer in cfg.layers.iter() {
+            let (cache, cfg) = cols.find(&layer.collection)?;
+            let key = layer.key(key);
+            checks.push(Check {
+                key: key.to_string(),
+                cache,
+                cfg,
+                limit: self.limit(cfg, key),
+                cost,
+            });
+        }
+
+        Ok(checks)
+    }
+
+    // hit_all counts hits in order, returning their statuses and the index of the first that was
+    // denied, if any. Peeks never need rolling back, so they are all checked. A lone hit is left as
+    // its algorithm counted it, while several hits counted together are peeked at first, so that
+    // ones that would be denied are not counted at all. They are still counted one at a time,
+    // though, so another caller can get in between the peeks and the hits and have the hits denied
+    // after all. Such a denial rolls back every hit, and other callers can briefly see them
+    // counted. The statuses are then where each key stands after the rollback, apart from the
+    // denied hit's own.
+    fn hit_all(checks: &[Check], inc: bool) -> Result<(Vec<cache::Status>, Option<usize>), HTTPError> {
+        if !inc || checks.len() > 1 {
+            let peeked = Handler::peek_all(checks)?;
+            let denied = peeked.iter().position(|v| !v.allowed);
+            if !inc || denied.is_some() {
+                return Ok((peeked, denied));
+            }
+        }
+
+        let mut vals = Vec::with_capacity(checks.len());
+        for c in checks.iter() {
+            let val = c.cache.get_or_create(&c.key, &c.limit, c.cost, true).map_err(|e| {
+                event!(Level::ERROR, message = "can't get or create val", error = %e);
+
+                HTTPError {
+                    error: format!("failed to get_or_create val: {}", e),
+                    code: http::StatusCode::INTERNAL_SERVER_ERROR,
+                }
+            })?;
+
+            let allowed = val.allowed;
+            vals.push(val);
+            if !allowed {
+                break;
+            }
+        }
+
+        let denied = vals.iter().position(|v| !v.allowed);
+        if let (true, Some(i)) = (checks.len() > 1, denied) {
+            for (c, val) in checks.iter().zip(vals.iter()) {
+                Handler::rollback(c, val)?;
+            }
+
+            let denial = vals.pop();
+            vals = Handler::peek_all(checks)?;
+            vals[i] = denial.unwrap_or_default();
+        }
+
+        Ok((vals, denied))
+    }
+
+    // peek_all is where each check's key stands, without counting anything
+    fn peek_all(checks: &[Check]) -> Result<Vec<cache::Status>, HTTPError> {
+        checks
+            .iter()
+            .map(|c| {
+                c.cache.get_or_create(&c.key, &c.limit, c.cost, false).map_err(|e| {
+                    event!(Level::ERROR, message = "can't get val", error = %e);
+
+                    HTTPError {
+                        error: format!("failed to get val: {}", e),
+                        code: http::StatusCode::INTERNAL_SERVER_ERROR,
+                    }
+                })
+            })
+            .collect()
+    }
+
+    // report picks the hit a response is for: the one that was denied, or else the one with the
+    // fewest hits remaining, with the layer named if the collection has any
+    fn report<'a>(
+        checks: &[Check<'a>],
+        vals: &[cache::Status],
+        denied: Option<usize>,
+        cfg: &RateConfig,
+    ) -> Hit<'a> {
+        let i = denied.unwrap_or_else(|| {
+            (0..vals.len())
+                .min_by_key(|i| vals[*i].remaining)
+                .unwrap_or_default()
+        });
+
+        Hit {
+            cfg: checks[i].cfg,
+            limit: checks[i].limit.clone(),
+            val: vals[i],
+            layered: !cfg.layers.is_empty(),
+        }
+    }
+
+    // rollback undoes a hit, refunding its cost or releasing its lease. Of the denied hits, only
+    // the counter algorithm's are counted, and only without further windows, as windows::Windows
+    // peeks before hitting, so only they are refunded.
+    fn rollback(c: &Check, val: &cache::Status) -> Result<(), HTTPError> {
+        let counted = c.cfg.algorithm == cache::Algorithm::Counter && c.cfg.windows.is_empty();
+        let undone = match val.lease {
+            Some(lease) => c.cache.release(&c.key, lease).map(|_| ()),
+            None if val.allowed || counted => {
+                c.cache.refund(&c.key, &c.limit, c.cost).map(|_| ())
+            }
+            None => Ok(()),
+        };
+
+        undone.map_err(|e| {
+            event!(Level::ERROR, message = "can't roll back val", error = %e);
+
+            HTTPError {
+                error: format!("failed to roll back val: {}", e),
+                code: http::StatusCode::INTERNAL_SERVER_ERROR,
+            }
+        })
+    }
+
+    // limit is the collection's limit for the key, taking any override into account
+    pub(crate) fn limit(&self, cfg: &RateConfig, key: &str) -> cache::Limit {
+        self.overrides.limit(cfg, key)
+    }
+
+    // leased checks that the collection hands out leases, as only concurrency collections can
+    // acquire and release them
+    fn leased(cfg: &RateConfig) -> Result<(), HTTPError> {
+        match cfg.algorithm {
+            cache::Algorithm::Concurrency => Ok(()),
+            _ => Err(HTTPError {
+                error: format!("collection {} does not use leases", cfg.name),
+                code: http::StatusCode::BAD_REQUEST,
+            }),
+        }
+    }
+
+    // cost reads the cost of a hit from the cost query parameter, falling back to a cost field in
+    // a JSON body, and defaulting to 1
+    fn cost(req: &HttpRequest, body: &web::Bytes) -> Result<u64, HTTPError> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            tracing::info!("invalid cost parameter");
+
+            HTTPError {
+                error: format!("invalid cost parameter: {}", e),
+                code: http::StatusCode::BAD_REQUEST,
+            }
+        };
+
+        let query = web::Query::<CostParams>::from_query(req.query_string()).map_err(|e| invalid(&e))?;
+        match query.cost {
+            Some(c) => Ok(c),
+            None if body.is_empty() => Ok(1),
+            None => Ok(serde_json::from_slice::<CostParams>(body)
+                .map_err(|e| invalid(&e))?
+                .cost
+                .unwrap_or(1)),
+        }
+    }
+
+    // check_cost rejects costs of 0, and costs over the key's limit, which could never be allowed
+    // and so are rejected outright rather than counted
+    pub(crate) fn check_cost(cfg: &RateConfig, limit: &cache::Limit, cost: u64) -> Result<u64, HTTPError> {
+        let capacity = limit.windows.iter().fold(limit.capacity(), |c, (count, _)| c.min(*count));
+        match cost {
+            0 => {
+                tracing::info!("invalid cost parameter");
+
+                Err(HTTPError {
+                    error: "invalid cost parameter: cost must be at least 1".to_string(),
+                    code: http::StatusCode::BAD_REQUEST,
+                })
+            }
+            c if c > capacity => {
+                event!(
+                    Level::INFO,
+                    message = "cost exceeds limit",
+                    collection = cfg.name.as_str(),
+                    cost = c,
+                );
+
+                Err(HTTPError {
+                    error: format!(
+                        "cost {} exceeds the limit of {} for collection {}",
+                        c, capacity, cfg.name
+                    ),
+                    code: http::StatusCode::BAD_REQUEST,
+                })
+            }
+            c => Ok(c),
+        }
+    }
+
+    // reject reads whether a denied hit is rejected with a 429 from the reject query parameter,
+    // defaulting to the collection's config
+    fn reject(req: &HttpRequest, cfg: &RateConfig) -> Result<bool, HTTPError> {
+        let query = web::Query::<RejectParams>::from_query(req.query_string()).map_err(|e| {
+            tracing::info!("invalid reject parameter");
+
+            HTTPError {
+                error: format!("invalid reject parameter: {}", e),
+                code: http::StatusCode::BAD_REQUEST,
+            }
+        })?;
+
+        Ok(query.reject.unwrap_or(cfg.reject))
+    }
+
+    // respond builds the rate limit response, or for rejected denials a 429 in the same shape as
+    // HTTPError, so that auth subrequests need no parsing. Both carry the RateLimit headers.
+    fn respond(hit: &Hit, reject: bool) -> HttpResponse {
+        if reject && !hit.val.allowed {
+            let mut resp = HttpResponse::build(http::StatusCode::TOO_MANY_REQUESTS);
+            let resp = resp.insert_header(header::ContentType::json());
+            Handler::headers(resp, &hit.limit, &hit.val);
+
+            return resp.body(
+                json!(HTTPError {
+                    error: format!("rate limit exceeded for collection {}", hit.cfg.name),
+                    code: http::StatusCode::TOO_MANY_REQUESTS,
+                })
+                .to_string(),
+            );
+        }
+
+        let mut resp = HttpResponse::build(http::StatusCode::OK);
+        let resp = resp.insert_header(header::ContentType::json());
+        Handler::headers(resp, &hit.limit, &hit.val);
+
+        resp.body(json!(Handler::response(hit)).to_string())
+    }
+
+    fn response(hit: &Hit) -> Response {
+        Response {
+            allowed: hit.val.allowed,
+            limit: hit.limit.nth(hit.val.window).capacity(),
+            count: hit.val.count,
+            remaining: hit.val.remaining,
+            reset_at: hit.val.reset,
+            retry_after_ms: hit.val.retry_after,
+            lease: hit.val.lease,
+            delay_ms: hit.val.delay,
+            layer: hit.layered.then(|| hit.cfg.name.clone()),
+        }
+    }
+
+    // headers sets the RateLimit headers for the key, and Retry-After if the hit was denied
+    fn headers(resp: &mut HttpResponseBuilder, limit: &cache::Limit, val: &cache::Status) {
+        for h in Handler::header_values(limit, val) {
+            resp.insert_header(h);
+        }
+    }
+
+    // header_values are the RateLimit and Retry-After headers for a hit, shared with the gRPC
+    // services. Both are in whole seconds, rounded up so clients never retry early.
+    pub(crate) fn header_values(limit: &cache::Limit, val: &cache::Status) -> Vec<(&'static str, String)> {
+        let mut policy = format!("{};w={}", limit.count, limit.window.div_ceil(1000));
+        if limit.burst > 0 {
+            policy.push_str(&format!(";burst={}", limit.burst));
+        }
+        for (count, window) in limit.windows.iter() {
+            policy.push_str(&format!(", {};w={}", count, window.div_ceil(1000)));
+        }
+
+        let mut headers = vec![
+            (RATELIMIT_LIMIT, limit.nth(val.window).capacity().to_string()),
+            (RATELIMIT_REMAINING, val.remaining.to_string()),
+            (
+                RATELIMIT_RESET,
+                val.reset.saturating_sub(local::now()).div_ceil(1000).to_string(),
+            ),
+            (RATELIMIT_POLICY, policy),
+        ];
+
+        if !val.allowed {
+            headers.push((RETRY_AFTER, val.retry_after.div_ceil(1000).to_string()));
+        }
+
+        headers
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use actix_web::{body::MessageBody, test};
+
+    #[test]
+    async fn test_new_handler() {
+        let linker = config::Config{ 
+            configs: HashMap::from([
+                ("foo".to_string(),
+                config::RateConfig{
+                    name: "foo".to_string(),
+                    count: 100,
+                    window: std::time::Duration::from_secs(60),
+                    ..Default::default()
+                }),
+                ("bar".to_string(),
+                config::RateConfig{
+                    name: "bar".to_string(),
+                    count: 1000,
+                    window: std::time::Duration::from_secs(30),
+                    ..Default::default()
+                }),
+                ("baz".to_string(),
+                config::RateConfig{
+                    name: "baz".to_string(),
+                    count: 10,
+                    window: std::time::Duration::from_secs(1),
+                    ttl: Some(std::time::Duration::from_secs(120)),
+                    ..Default::default()
+                }),
+            ]), 
+            ttl_seconds: config::HARDCODED_TTL,
+            ..Default::default()
+        };
+
+        let handler = Handler::new(linker);
+
+        // raised from the config's ttl to cover the window
+        assert_eq!(
+            handler
+                .collections()
+                .caches
+                .get("foo")
+                .expect("no cache with key foo")
+                .ttl(),
+            60
+        );
+
+        assert_eq!(
+            handler
+                .collections()
+                .caches
+                .get("bar")
+                .expect("no cache with key foo")
+                .ttl(),
+            30
+        );
+
+        assert_eq!(
+            handler
+                .collections()
+                .caches
+                .get("baz")
+                .expect("no cache with key baz")
+                .ttl(),
+            120
+        );
+    }
+
+    macro_rules! handle_rate_tests {
+        ($($name:ident: $value:expr,)*) => {
+        $(
+            #[test]
+            async fn $name() {
+
+                let (count, allowed, remaining) = $value;
+
+                let allow_two_linker = config::Config{ 
+                    configs: HashMap::from([
+                        ("foo".to_string(), 
+                        config::RateConfig{
+                            name: "foo".to_string(),
+                            count: 2,
+                            window: std::time::Duration::from_secs(60),
+                            ..Default::default()
+                        }),
+                    ]), 
+                    ttl_seconds: config::HARDCODED_TTL,
+                    ..Default::default()
+                };
+
+                let handler = Handler::new(allow_two_linker);
+                let data = web::Data::new(handler);
+
+                let mut limited = true;
+                let mut left = 0;
+                let mut retry = 0;
+
+                for _ in 0..count {
+                    let req = test::TestRequest::with_uri("http://localhost")
+                        .param("key", "foobar")
+                        .param("collection", "foo")
+                        .method(http::Method::GET)
+                        .to_http_request();
+                    let resp = Handler::handle(data.clone(), req.clone(), web::Bytes::new())
+                        .await
+                        .expect("unexpected handler error");
+                    assert_eq!(resp.status(), http::StatusCode::OK);
+
+                    let body = resp
+                        .into_body()
+                        .try_into_bytes()
+                        .expect("unable to ready body");
+                    let parsed: Response =
+                        serde_json::from_slice(&body[..]).expect("cannot parse as Response");
+                    assert_eq!(parsed.limit, 2);
+                    limited = parsed.allowed;
+                    left = parsed.remaining;
+                    retry = parsed.retry_after_ms;
+                }
+                assert_eq!(allowed, limited);
+                assert_eq!(remaining, left);
+                assert_eq!(allowed, retry == 0, "unexpected retry after {}", retry);
+                assert!(retry <= 60_000, "retry after past window: {}", retry);
+            }
+        )*
+        }
+    }
+
+    handle_rate_tests! {
+        handle_rate_one_request: (1, true, 1),
+        handle_rate_two_requests: (2, true, 0),
+        handle_rate_three_requests: (3, false, 0),
+    }
+
+
+    macro_rules! handle_errors_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                async fn $name() {
+                    let (code, err, collection, key) = $value; 
+
+                    let linker = config::Config{ 
+                        configs: HashMap::from([
+                            ("foo".to_string(),
+                            config::RateConfig{
+                                name: "foo".to_string(),
+                                count: 100,
+                                window: std::time::Duration::from_secs(60),
+                                ..Default::default()
+                            }),
+                            ("bar".to_string(),
+                            config::RateConfig{
+                                name: "bar".to_string(),
+                                count: 1000,
+                                window: std::time::Duration::from_secs(30),
+                                ..Default::default()
+                            }),
+                        ]), 
+                        ttl_seconds: config::HARDCODED_TTL,
+                        ..Default::default()
+                    };
+
+                    let handler = Handler::new(linker);
+                    let data = web::Data::new(handler);
+
+                    let resp = do_test_request("http://localhost", key, collection, data.clone())
+                    .await
+                    .expect_err("did not error as expected")
+                    .error_response();
+
+                    assert_eq!(
+                        resp.status(),
+                        code,
+                        "unexpected status code",
+                    );
+        
+                    let body = resp
+                        .into_body()
+                        .try_into_bytes()
+                        .expect("unable to ready body");
+                    let parsed: HTTPError =
+                        serde_json::from_slice(&body[..]).expect("cannot parse as HTTPError");
+                    assert!(
+                        parsed.error.contains(err),
+                        "body does not match expected: {}",
+                        parsed.error
+                    );
+                }
+    
+            )*
+        }
+    }
+
+    handle_errors_tests! {
+        handle_missing_collection_parameter: (
+            http::StatusCode::BAD_REQUEST,
+            "missing collection parameter",
+            None,
+            Some("foo"),
+        ),
+        handle_missing_key_parameter: (
             http::StatusCode::BAD_REQUEST,
             "missing key parameter",
             Some("foo"),
@@ -313,6 +1445,1309 @@ mod test {
         ),
     }
 
+    #[test]
+    async fn test_acquire_release() {
+        let linker = config::Config{
+            configs: HashMap::from([
+                ("foo".to_string(),
+                config::RateConfig{
+                    name: "foo".to_string(),
+                    count: 1,
+                    window: std::time::Duration::from_secs(60),
+                    algorithm: cache::Algorithm::Concurrency,
+                    ..Default::default()
+                }),
+                ("bar".to_string(),
+                config::RateConfig{
+                    name: "bar".to_string(),
+                    count: 1,
+                    window: std::time::Duration::from_secs(60),
+                    ..Default::default()
+                }),
+            ]),
+            ttl_seconds: config::HARDCODED_TTL,
+            ..Default::default()
+        };
+
+        let data = web::Data::new(Handler::new(linker));
+        let acquire = |collection: &'static str| {
+            let req = test::TestRequest::with_uri("http://localhost")
+                .param("collection", collection)
+                .param("key", "foobar")
+                .to_http_request();
+            Handler::acquire(data.clone(), req, web::Bytes::new())
+        };
+        let release = |lease: String| {
+            let req = test::TestRequest::with_uri("http://localhost")
+                .param("collection", "foo")
+                .param("key", "foobar")
+                .param("lease", lease)
+                .to_http_request();
+            Handler::release(data.clone(), req)
+        };
+
+        let first: Response = parse_body(acquire("foo").await);
+        assert!(first.allowed, "first lease not allowed");
+        let lease = first.lease.expect("no lease for first acquire");
+
+        let second: Response = parse_body(acquire("foo").await);
+        assert!(!second.allowed, "second lease allowed while first held");
+        assert_eq!(second.lease, None);
+
+        for (lease, expected) in [(lease, true), (lease, false)] {
+            let parsed: ReleaseResponse = parse_body(release(lease.to_string()).await);
+            assert_eq!(parsed.released, expected, "unexpected release of lease {}", lease);
+        }
+
+        let third: Response = parse_body(acquire("foo").await);
+        assert!(third.allowed, "lease not allowed after release");
+
+        let err = release("notalease".to_string())
+            .await
+            .expect_err("did not error as expected")
+            .error_response();
+        assert_eq!(err.status(), http::StatusCode::BAD_REQUEST, "invalid lease");
+
+        let err = acquire("bar")
+            .await
+            .expect_err("did not error as expected")
+            .error_response();
+        assert_eq!(err.status(), http::StatusCode::BAD_REQUEST, "collection without leases");
+    }
+
+    #[test]
+    async fn test_handle_delay() {
+        let linker = config::Config{
+            configs: HashMap::from([
+                ("foo".to_string(),
+                config::RateConfig{
+                    name: "foo".to_string(),
+                    count: 1,
+                    window: std::time::Duration::from_secs(60),
+                    algorithm: cache::Algorithm::LeakyBucket,
+                    max_delay: std::time::Duration::from_secs(60),
+                    ..Default::default()
+                }),
+            ]),
+            ttl_seconds: config::HARDCODED_TTL,
+            ..Default::default()
+        };
+
+        let data = web::Data::new(Handler::new(linker));
+        let mut delays = Vec::new();
+        for _ in 0..3 {
+            let parsed: Response =
+                parse_body(do_test_request("http://localhost", Some("foobar"), Some("foo"), data.clone()).await);
+            delays.push(parsed.delay_ms);
+        }
+
+        assert_eq!(delays[0], Some(0), "first hit delayed");
+        assert!(
+            delays[1].is_some_and(|d| d > 59_000 && d <= 60_000),
+            "second hit not delayed by a window: {:?}",
+            delays[1]
+        );
+        assert_eq!(delays[2], None, "third hit not rejected");
+    }
+
+    macro_rules! handle_cost_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                async fn $name() {
+                    let (uri, body, expected): (&str, &'static [u8], Result<u64, &str>) = $value;
+
+                    let linker = config::Config{
+                        configs: HashMap::from([
+                            ("foo".to_string(),
+                            config::RateConfig{
+                                name: "foo".to_string(),
+                                count: 10,
+                                window: std::time::Duration::from_secs(60),
+                                algorithm: cache::Algorithm::FixedWindow,
+                                ..Default::default()
+                            }),
+                        ]),
+                        ttl_seconds: config::HARDCODED_TTL,
+                        ..Default::default()
+                    };
+
+                    let data = web::Data::new(Handler::new(linker));
+                    let req = test::TestRequest::with_uri(uri)
+                        .param("collection", "foo")
+                        .param("key", "foobar")
+                        .to_http_request();
+
+                    let resp = Handler::handle(data, req, web::Bytes::from_static(body)).await;
+                    match expected {
+                        Ok(remaining) => {
+                            let parsed: Response = parse_body(resp);
+                            assert!(parsed.allowed, "hit not allowed");
+                            assert_eq!(parsed.remaining, remaining, "unexpected remaining");
+                        }
+                        Err(err) => {
+                            let resp = resp.expect_err("did not error as expected").error_response();
+                            assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+
+                            let parsed: HTTPError = parse_body(Ok(resp.map_into_boxed_body()));
+                            assert!(
+                                parsed.error.contains(err),
+                                "body does not match expected: {}",
+                                parsed.error
+                            );
+                        }
+                    }
+                }
+            )*
+        }
+    }
+
+    handle_cost_tests! {
+        handle_cost_default: ("http://localhost", b"", Ok(9)),
+        handle_cost_query: ("http://localhost?cost=4", b"", Ok(6)),
+        handle_cost_body: ("http://localhost", br#"{"cost": 3}"#, Ok(7)),
+        handle_cost_query_over_body: ("http://localhost?cost=4", br#"{"cost": 3}"#, Ok(6)),
+        handle_cost_whole_limit: ("http://localhost?cost=10", b"", Ok(0)),
+        handle_cost_over_limit: ("http://localhost?cost=11", b"", Err("cost 11 exceeds the limit of 10")),
+        handle_cost_zero: ("http://localhost?cost=0", b"", Err("cost must be at least 1")),
+        handle_cost_invalid_query: ("http://localhost?cost=lots", b"", Err("invalid cost parameter")),
+        handle_cost_invalid_body: ("http://localhost", b"lots", Err("invalid cost parameter")),
+    }
+
+    #[test]
+    async fn test_handle_cost_accumulates() {
+        let linker = config::Config{
+            configs: HashMap::from([
+                ("foo".to_string(),
+                config::RateConfig{
+                    name: "foo".to_string(),
+                    count: 10,
+                    window: std::time::Duration::from_secs(60),
+                    ..Default::default()
+                }),
+            ]),
+            ttl_seconds: config::HARDCODED_TTL,
+            ..Default::default()
+        };
+
+        let data = web::Data::new(Handler::new(linker));
+        let hit = |cost: u64| {
+            let req = test::TestRequest::with_uri(&format!("http://localhost?cost={}", cost))
+                .param("collection", "foo")
+                .param("key", "foobar")
+                .to_http_request();
+            Handler::handle(data.clone(), req, web::Bytes::new())
+        };
+
+        for (cost, allowed, remaining) in [(6, true, 4), (4, true, 0), (1, false, 0)] {
+            let parsed: Response = parse_body(hit(cost).await);
+            assert_eq!(
+                (parsed.allowed, parsed.remaining),
+                (allowed, remaining),
+                "unexpected response for cost {}",
+                cost
+            );
+        }
+    }
+
+    #[test]
+    async fn test_peek() {
+        let linker = config::Config{
+            configs: HashMap::from([
+                ("foo".to_string(),
+                config::RateConfig{
+                    name: "foo".to_string(),
+                    count: 3,
+                    window: std::time::Duration::from_secs(60),
+                    ..Default::default()
+                }),
+            ]),
+            ttl_seconds: config::HARDCODED_TTL,
+            ..Default::default()
+        };
+
+        let data = web::Data::new(Handler::new(linker));
+        let peek = |uri: &'static str| {
+            let req = test::TestRequest::with_uri(uri)
+                .param("collection", "foo")
+                .param("key", "foobar")
+                .to_http_request();
+            Handler::peek(data.clone(), req, web::Bytes::new())
+        };
+
+        for _ in 0..2 {
+            let parsed: Response = parse_body(peek("http://localhost").await);
+            assert_eq!(
+                (parsed.allowed, parsed.count, parsed.remaining),
+                (true, 0, 3),
+                "peek counted a hit"
+            );
+        }
+
+        for _ in 0..2 {
+            do_test_request("http://localhost", Some("foobar"), Some("foo"), data.clone())
+                .await
+                .expect("unexpected handler error");
+        }
+
+        let parsed: Response = parse_body(peek("http://localhost").await);
+        assert_eq!((parsed.allowed, parsed.count, parsed.remaining), (true, 2, 1), "after hits");
+
+        let parsed: Response = parse_body(peek("http://localhost?cost=2").await);
+        assert_eq!((parsed.allowed, parsed.count, parsed.remaining), (false, 2, 1), "cost over remaining");
+    }
+
+    #[test]
+    async fn test_refund() {
+        let linker = config::Config{
+            configs: HashMap::from([
+                ("foo".to_string(),
+                config::RateConfig{
+                    name: "foo".to_string(),
+                    count: 10,
+                    window: std::time::Duration::from_secs(60),
+                    ..Default::default()
+                }),
+                ("bar".to_string(),
+                config::RateConfig{
+                    name: "bar".to_string(),
+                    count: 1,
+                    window: std::time::Duration::from_secs(60),
+                    algorithm: cache::Algorithm::Concurrency,
+                    ..Default::default()
+                }),
+            ]),
+            ttl_seconds: config::HARDCODED_TTL,
+            ..Default::default()
+        };
+
+        let data = web::Data::new(Handler::new(linker));
+        let request = |collection: &'static str, cost: u64| {
+            test::TestRequest::with_uri(&format!("http://localhost?cost={}", cost))
+                .param("collection", collection)
+                .param("key", "foobar")
+                .to_http_request()
+        };
+
+        let parsed: Response =
+            parse_body(Handler::handle(data.clone(), request("foo", 10), web::Bytes::new()).await);
+        assert_eq!((parsed.allowed, parsed.remaining), (true, 0), "spent limit");
+
+        for (cost, remaining) in [(4, 4), (8, 10)] {
+            let parsed: Response =
+                parse_body(Handler::refund(data.clone(), request("foo", cost), web::Bytes::new()).await);
+            assert_eq!(
+                (parsed.allowed, parsed.count, parsed.remaining),
+                (true, 10 - remaining, remaining),
+                "unexpected response for refund of {}",
+                cost
+            );
+        }
+
+        let err = Handler::refund(data.clone(), request("bar", 1), web::Bytes::new())
+            .await
+            .expect_err("did not error as expected")
+            .error_response();
+        assert_eq!(err.status(), http::StatusCode::BAD_REQUEST, "collection with leases");
+    }
+
+    fn batch_handler() -> web::Data<Handler> {
+        let configs = [
+            ("user", 2, cache::Algorithm::Counter),
+            ("org", 1, cache::Algorithm::SlidingLog),
+            ("conc", 1, cache::Algorithm::Concurrency),
+        ];
+
+        web::Data::new(Handler::new(config::Config {
+            configs: configs
+                .into_iter()
+                .map(|(name, count, algorithm)| {
+                    (
+                        name.to_string(),
+                        config::RateConfig {
+                            name: name.to_string(),
+                            count,
+                            window: std::time::Duration::from_secs(60),
+                            algorithm,
+                            ..Default::default()
+                        },
+                    )
+                })
+                .collect(),
+            ttl_seconds: config::HARDCODED_TTL,
+            ..Default::default()
+        }))
+    }
+
+    // testcase-based rather than macro-based to simplify state across cases
+    #[test]
+    async fn test_batch() {
+        struct TestCase {
+            name: &'static str,
+            body: &'static str,
+            allowed: bool,
+            denied: Option<usize>,
+            counts: Vec<u64>,
+        }
+
+        let data = batch_handler();
+        let testcases = vec![
+            TestCase {
+                name: "all allowed",
+                body: r#"[{"collection": "user", "key": "a"}, {"collection": "conc", "key": "a"}, {"collection": "org", "key": "a"}]"#,
+                allowed: true,
+                denied: None,
+                counts: vec![1, 1, 1],
+            },
+            TestCase {
+                name: "last denied rolls back the rest",
+                body: r#"[{"collection": "user", "key": "a"}, {"collection": "conc", "key": "b"}, {"collection": "org", "key": "a"}]"#,
+                allowed: false,
+                denied: Some(2),
+                counts: vec![1, 0, 1],
+            },
+            TestCase {
+                name: "denied counter hit not counted",
+                body: r#"[{"collection": "org", "key": "b"}, {"collection": "user", "key": "a", "cost": 2}]"#,
+                allowed: false,
+                denied: Some(1),
+                // the hits were only peeked at
+                counts: vec![0, 1],
+            },
+            TestCase {
+                name: "denial after peeks rolls back",
+                body: r#"[{"collection": "user", "key": "c", "cost": 2}, {"collection": "user", "key": "c", "cost": 2}]"#,
+                allowed: false,
+                denied: Some(1),
+                // each peek allowed its hit on its own, and the counter counted the denied hit
+                // before it was rolled back
+                counts: vec![0, 4],
+            },
+            TestCase {
+                name: "denied counter hit no longer counted",
+                body: r#"[{"collection": "user", "key": "a"}]"#,
+                allowed: true,
+                denied: None,
+                counts: vec![2],
+            },
+        ];
+
+        for tc in testcases {
+            let req = test::TestRequest::with_uri("http://localhost/batch").to_http_request();
+            let parsed: BatchResponse =
+                parse_body(Handler::batch(data.clone(), req, web::Bytes::from(tc.body)).await);
+
+            assert_eq!(
+                (parsed.allowed, parsed.denied),
+                (tc.allowed, tc.denied),
+                "unexpected response for {}",
+                tc.name
+            );
+            assert_eq!(
+                parsed.results.iter().map(|r| r.count).collect::<Vec<_>>(),
+                tc.counts,
+                "unexpected counts for {}",
+                tc.name
+            );
+        }
+    }
+
+    #[test]
+    async fn test_batch_reject() {
+        let data = batch_handler();
+        let body = r#"[{"collection": "org", "key": "a"}, {"collection": "org", "key": "a"}]"#;
+        let req = test::TestRequest::with_uri("http://localhost/batch?reject=true").to_http_request();
+
+        let resp = Handler::batch(data, req, web::Bytes::from(body))
+            .await
+            .expect("unexpected handler error");
+        assert_eq!(resp.status(), http::StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key(header::RETRY_AFTER), "no Retry-After");
+    }
+
+    #[test]
+    async fn test_layers() {
+        struct TestCase {
+            name: &'static str,
+            key: &'static str,
+            inc: bool,
+            allowed: bool,
+            layer: &'static str,
+            count: u64,
+        }
+
+        let configs = [
+            "tenant=3:1 minute:sliding_log",
+            "api=2:1 minute:sliding_log:layer/tenant/.",
+        ];
+        let data = web::Data::new(Handler::new(configs.join(",").try_into().expect("invalid config")));
+        let testcases = vec![
+            TestCase {
+                name: "first hit reports the tightest layer",
+                key: "acme.a",
+                inc: true,
+                allowed: true,
+                layer: "api",
+                count: 1,
+            },
+            TestCase {
+                name: "key limit spent",
+                key: "acme.a",
+                inc: true,
+                allowed: true,
+                layer: "api",
+                count: 2,
+            },
+            TestCase {
+                name: "denied by key limit",
+                key: "acme.a",
+                inc: true,
+                allowed: false,
+                layer: "api",
+                count: 2,
+            },
+            TestCase {
+                name: "tenant limit spent by another key",
+                key: "acme.b",
+                inc: true,
+                allowed: true,
+                layer: "tenant",
+                count: 3,
+            },
+            TestCase {
+                name: "peek denied by tenant limit",
+                key: "acme.c",
+                inc: false,
+                allowed: false,
+                layer: "tenant",
+                count: 3,
+            },
+            TestCase {
+                name: "denied by tenant limit",
+                key: "acme.c",
+                inc: true,
+                allowed: false,
+                layer: "tenant",
+                count: 3,
+            },
+            TestCase {
+                name: "denied hit rolled back from key",
+                key: "acme.c",
+                inc: false,
+                allowed: false,
+                layer: "tenant",
+                count: 3,
+            },
+            TestCase {
+                name: "other tenant",
+                key: "other.c",
+                inc: true,
+                allowed: true,
+                layer: "api",
+                count: 1,
+            },
+        ];
+
+        for tc in testcases {
+            let req = test::TestRequest::with_uri("http://localhost")
+                .param("collection", "api")
+                .param("key", tc.key)
+                .to_http_request();
+            let parsed: Response = match tc.inc {
+                true => parse_body(Handler::handle(data.clone(), req, web::Bytes::new()).await),
+                false => parse_body(Handler::peek(data.clone(), req, web::Bytes::new()).await),
+            };
+
+            assert_eq!(
+                (parsed.allowed, parsed.layer.as_deref(), parsed.count),
+                (tc.allowed, Some(tc.layer), tc.count),
+                "unexpected response for {}",
+                tc.name
+            );
+        }
+
+        // the denied hit was not counted against its own key either
+        let cols = data.collections();
+        let (cache, cfg) = cols.find("api").expect("no api collection");
+        let val = cache.get_or_create("acme.c", &cfg.limit(), 1, false).expect("unable to peek");
+        assert_eq!(val.count, 0, "denied hit counted");
+    }
+
+    #[test]
+    async fn test_batch_layers() {
+        let configs = [
+            "tenant=2:1 minute:sliding_log",
+            "api=5:1 minute:sliding_log:layer/tenant/.",
+            "user=5:1 minute:sliding_log",
+        ];
+        let data = web::Data::new(Handler::new(configs.join(",").try_into().expect("invalid config")));
+        let body = r#"[{"collection": "user", "key": "a"}, {"collection": "api", "key": "acme.a", "cost": 2}, {"collection": "api", "key": "acme.b"}]"#;
+
+        let req = test::TestRequest::with_uri("http://localhost/batch").to_http_request();
+        let parsed: BatchResponse = parse_body(Handler::batch(data.clone(), req, web::Bytes::from(body)).await);
+        assert_eq!((parsed.allowed, parsed.denied), (false, Some(2)), "batch not denied by layer");
+        assert_eq!(parsed.results[2].layer.as_deref(), Some("tenant"), "denying layer not reported");
+        assert_eq!(
+            parsed.results.iter().map(|r| r.count).collect::<Vec<_>>(),
+            vec![0, 0, 2],
+            "batch not rolled back"
+        );
+    }
+
+    #[test]
+    async fn test_handle_windows() {
+        let data = web::Data::new(Handler::new(
+            "foo=10:1 second:sliding_log:window/4/1 minute".to_string().try_into().expect("invalid config"),
+        ));
+        let request = |cost: u64| {
+            test::TestRequest::with_uri(&format!("http://localhost?cost={}", cost))
+                .param("collection", "foo")
+                .param("key", "foobar")
+                .to_http_request()
+        };
+
+        let resp = Handler::handle(data.clone(), request(2), web::Bytes::new())
+            .await
+            .expect("unexpected handler error");
+        assert_eq!(
+            resp.headers().get(RATELIMIT_POLICY).and_then(|h| h.to_str().ok()),
+            Some("10;w=1, 4;w=60"),
+            "policy did not list every window"
+        );
+        let parsed: Response = parse_body(Ok(resp));
+        assert_eq!((parsed.allowed, parsed.limit, parsed.remaining), (true, 4, 2), "minute not tightest");
+
+        // a cost over the minute window could never be allowed
+        let err = Handler::handle(data.clone(), request(5), web::Bytes::new())
+            .await
+            .expect_err("did not error as expected")
+            .error_response();
+        assert_eq!(err.status(), http::StatusCode::BAD_REQUEST, "cost over minute window");
+
+        let parsed: Response = parse_body(Handler::handle(data, request(3), web::Bytes::new()).await);
+        assert_eq!((parsed.allowed, parsed.limit), (false, 4), "not denied by minute window");
+    }
+
+    #[test]
+    async fn test_handle_counts_denied() {
+        let data = web::Data::new(Handler::new("foo=2:1 minute".to_string().try_into().expect("invalid config")));
+
+        let mut hits = Vec::new();
+        for _ in 0..4 {
+            let req = test::TestRequest::default()
+                .param("collection", "foo")
+                .param("key", "foobar")
+                .to_http_request();
+            let parsed: Response = parse_body(Handler::handle(data.clone(), req, web::Bytes::new()).await);
+            hits.push((parsed.allowed, parsed.count));
+        }
+        assert_eq!(hits, vec![(true, 1), (true, 2), (false, 3), (false, 4)], "denied counter hits not counted");
+    }
+
+    #[test]
+    async fn test_handle_windows_counter() {
+        let data = web::Data::new(Handler::new(
+            "foo=2:1 minute:window/100/1 hour".to_string().try_into().expect("invalid config"),
+        ));
+
+        let mut allowed = 0;
+        for _ in 0..20 {
+            let req = test::TestRequest::default()
+                .param("collection", "foo")
+                .param("key", "foobar")
+                .to_http_request();
+            let parsed: Response = parse_body(Handler::handle(data.clone(), req, web::Bytes::new()).await);
+            allowed += parsed.allowed as u64;
+        }
+        assert_eq!(allowed, 2, "denied hits refunded earlier ones");
+    }
+
+    #[test]
+    async fn test_overrides() {
+        let data = web::Data::new(Handler::new(
+            "foo=1:1 minute:sliding_log:override/acme*/3/1 minute".to_string().try_into().expect("invalid config"),
+        ));
+        let request = |key: &str| {
+            test::TestRequest::default()
+                .param("collection", "foo")
+                .param("key", key.to_string())
+                .to_http_request()
+        };
+        let limit = |key: &'static str| {
+            let data = data.clone();
+            async move {
+                let parsed: Response = parse_body(Handler::handle(data, request(key), web::Bytes::new()).await);
+                (parsed.limit, parsed.remaining)
+            }
+        };
+
+        assert_eq!(limit("bob").await, (1, 0), "default limit not used");
+        assert_eq!(limit("acme.alice").await, (3, 2), "config override not used");
+
+        let resp = Handler::set_override(
+            data.clone(),
+            Admin,
+            request("acme.alice"),
+            web::Bytes::from_static(br#"{"count": 5}"#),
+        )
+        .await;
+        let parsed: OverrideResponse = parse_body(resp);
+        assert_eq!(
+            parsed,
+            OverrideResponse { key: "acme.alice".to_string(), count: 5, window_ms: 60_000 },
+            "window not defaulted to the collection's"
+        );
+        assert_eq!(limit("acme.alice").await, (5, 3), "exact override not preferred over prefix");
+        assert_eq!(limit("acme.carol").await, (3, 2), "prefix override not kept");
+
+        let listed: Vec<OverrideResponse> = parse_body(
+            Handler::overrides(
+                data.clone(),
+                Admin,
+                test::TestRequest::default().param("collection", "foo").to_http_request(),
+            )
+            .await,
+        );
+        assert_eq!(
+            listed.iter().map(|o| (o.key.as_str(), o.count)).collect::<Vec<_>>(),
+            vec![("acme*", 3), ("acme.alice", 5)],
+            "overrides not listed"
+        );
+
+        let removed: RemovedResponse =
+            parse_body(Handler::remove_override(data.clone(), Admin, request("acme*")).await);
+        assert!(removed.removed, "override not removed");
+        assert_eq!(limit("acme.dave").await, (1, 0), "removed override still used");
+    }
+
+    // testcase-based rather than macro-based to simplify state across cases
+    #[test]
+    async fn test_reload() {
+        struct TestCase {
+            name: &'static str,
+            config: &'static str,
+            collection: &'static str,
+            // the limit and remaining after a hit, or None if the collection is gone
+            expected: Option<(u64, u64)>,
+        }
+
+        let data = web::Data::new(Handler::new(
+            "foo=5:1 minute:sliding_log:override/acme/10/1 minute,bar=5:1 minute"
+                .to_string()
+                .try_into()
+                .expect("invalid config"),
+        ));
+        let testcases = vec![
+            TestCase {
+                name: "first hit",
+                config: "foo=5:1 minute:sliding_log:override/acme/10/1 minute,bar=5:1 minute",
+                collection: "foo",
+                expected: Some((5, 4)),
+            },
+            TestCase {
+                name: "changed limit keeps counts",
+                config: "foo=3:1 minute:sliding_log:override/acme/10/1 minute,bar=5:1 minute",
+                collection: "foo",
+                expected: Some((3, 1)),
+            },
+            TestCase {
+                name: "changed window keeps counts",
+                config: "foo=3:2 minutes:sliding_log:override/acme/10/1 minute,bar=5:1 minute",
+                collection: "foo",
+                expected: Some((3, 0)),
+            },
+            TestCase {
+                name: "new collection",
+                config: "foo=3:1 minute:sliding_log:override/acme/10/1 minute,baz=2:1 minute",
+                collection: "baz",
+                expected: Some((2, 1)),
+            },
+            TestCase {
+                name: "removed collection",
+                config: "foo=3:1 minute:sliding_log:override/acme/10/1 minute,baz=2:1 minute",
+                collection: "bar",
+                expected: None,
+            },
+            TestCase {
+                name: "changed algorithm starts over",
+                config: "foo=3:1 minute:gcra",
+                collection: "foo",
+                expected: Some((3, 2)),
+            },
+            TestCase {
+                name: "changed ttl keeps counts",
+                config: "foo=3:1 minute:gcra:ttl/2 minutes",
+                collection: "foo",
+                expected: Some((3, 1)),
+            },
+            TestCase {
+                name: "gaining a window starts over",
+                config: "foo=3:1 minute:gcra:window/10/1 hour",
+                collection: "foo",
+                expected: Some((3, 2)),
+            },
+            TestCase {
+                name: "another window starts over and is enforced",
+                config: "foo=3:1 minute:gcra:window/10/1 hour:window/2/10 minutes",
+                collection: "foo",
+                expected: Some((2, 1)),
+            },
+        ];
+
+        for tc in testcases {
+            data.reload(tc.config.to_string().try_into().expect("invalid config"));
+            let req = test::TestRequest::default()
+                .param("collection", tc.collection)
+                .param("key", "foobar")
+                .to_http_request();
+
+            let resp = Handler::handle(data.clone(), req, web::Bytes::new()).await;
+            match tc.expected {
+                Some(expected) => {
+                    let parsed: Response = parse_body(resp);
+                    assert_eq!((parsed.limit, parsed.remaining), expected, "response did not match for {}", tc.name);
+                }
+                None => assert!(resp.is_err(), "removed collection served for {}", tc.name),
+            }
+        }
+
+        // overrides go with the config's own, and runtime ones are dropped along with them
+        data.overrides.set("foo", "bob", config::Override { count: 7, window: std::time::Duration::from_secs(60) });
+        data.reload("foo=3:1 minute:gcra".to_string().try_into().expect("invalid config"));
+        assert_eq!(data.overrides.list("foo").len(), 1, "runtime override dropped by unchanged config");
+        data.reload("foo=3:1 minute:gcra:override/acme/5/1 minute".to_string().try_into().expect("invalid config"));
+        assert_eq!(
+            data.overrides.list("foo").into_keys().collect::<Vec<_>>(),
+            vec!["acme".to_string()],
+            "overrides not replaced by the config's"
+        );
+    }
+
+    #[test]
+    async fn test_reload_admin_changes() {
+        const FILE: &str = "foo=5:1 minute,baz=5:1 minute";
+        let data = web::Data::new(Handler::new(FILE.to_string().try_into().expect("invalid config")));
+        let coll = |name: &str| {
+            test::TestRequest::default()
+                .param("collection", name.to_string())
+                .to_http_request()
+        };
+        let put = |name: &str, body: &'static str| {
+            Handler::put_collection(data.clone(), Admin, coll(name), web::Bytes::from_static(body.as_bytes()))
+        };
+        let counts = || {
+            let mut counts = data
+                .collections()
+                .rates
+                .values()
+                .map(|c| (c.name.clone(), c.count))
+                .collect::<Vec<_>>();
+            counts.sort();
+            counts
+        };
+
+        put("foo", r#"{"count": 3, "window": "1 minute"}"#).await.expect("unexpected handler error");
+        put("bar", r#"{"count": 2, "window": "1 minute"}"#).await.expect("unexpected handler error");
+        Handler::delete_collection(data.clone(), Admin, coll("baz")).await.expect("unexpected handler error");
+
+        data.reload(FILE.to_string().try_into().expect("invalid config"));
+        assert_eq!(
+            counts(),
+            vec![("bar".to_string(), 2), ("foo".to_string(), 3)],
+            "admin changes dropped by unchanged config"
+        );
+
+        data.reload("foo=10:1 minute,baz=5:1 minute".to_string().try_into().expect("invalid config"));
+        assert_eq!(
+            counts(),
+            vec![("bar".to_string(), 2), ("foo".to_string(), 10)],
+            "admin change kept over the config's own change"
+        );
+
+        // an admin collection layered on one the config removes cannot be kept
+        put("bar", r#"{"count": 2, "window": "1 minute", "layers": ["foo"]}"#).await.expect("unexpected handler error");
+        data.reload("qux=5:1 minute".to_string().try_into().expect("invalid config"));
+        assert_eq!(counts(), vec![("qux".to_string(), 5)], "admin changes kept with a missing layer");
+    }
+
+    macro_rules! admin_auth_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                async fn $name() {
+                    let (token, authorization, expected): (Option<&str>, Option<&str>, http::StatusCode) = $value;
+
+                    let data = web::Data::new(Handler::new(config::Config {
+                        admin_token: token.map(str::to_string),
+                        ..config::Config::try_from("foo=1:1 minute".to_string()).expect("invalid config")
+                    }));
+                    let app = test::init_service(
+                        actix_web::App::new()
+                            .app_data(data)
+                            .route("admin/collections", web::get().to(Handler::list_collections)),
+                    )
+                    .await;
+
+                    let mut req = test::TestRequest::get().uri("/admin/collections");
+                    if let Some(a) = authorization {
+                        req = req.insert_header((header::AUTHORIZATION, a));
+                    }
+                    let resp = test::call_service(&app, req.to_request()).await;
+                    assert_eq!(resp.status(), expected);
+                }
+            )*
+        }
+    }
+
+    admin_auth_tests! {
+        admin_auth_disabled: (None, Some("Bearer s3cret"), http::StatusCode::FORBIDDEN),
+        admin_auth_missing: (Some("s3cret"), None, http::StatusCode::UNAUTHORIZED),
+        admin_auth_wrong: (Some("s3cret"), Some("Bearer s3crex"), http::StatusCode::UNAUTHORIZED),
+        admin_auth_basic: (Some("s3cret"), Some("Basic czNjcmV0"), http::StatusCode::UNAUTHORIZED),
+        admin_auth_allowed: (Some("s3cret"), Some("Bearer s3cret"), http::StatusCode::OK),
+    }
+
+    // every override route is routed as in main, as they change limits for any key
+    macro_rules! override_auth_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                async fn $name() {
+                    let (method, uri): (http::Method, &str) = $value;
+
+                    let data = web::Data::new(Handler::new(config::Config {
+                        admin_token: Some("s3cret".to_string()),
+                        ..config::Config::try_from("foo=1:1 minute:override/acme*/3/1 minute".to_string())
+                            .expect("invalid config")
+                    }));
+                    let app = test::init_service(
+                        actix_web::App::new()
+                            .app_data(data.clone())
+                            .route("admin/overrides/{collection}", web::get().to(Handler::overrides))
+                            .service(
+                                web::resource("admin/overrides/{collection}/{key}")
+                                    .route(web::put().to(Handler::set_override))
+                                    .route(web::post().to(Handler::set_override))
+                                    .route(web::delete().to(Handler::remove_override)),
+                            ),
+                    )
+                    .await;
+
+                    let req = test::TestRequest::default()
+                        .method(method)
+                        .uri(uri)
+                        .set_payload(r#"{"count": 100}"#)
+                        .to_request();
+                    let resp = test::call_service(&app, req).await;
+                    assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+                    assert_eq!(
+                        data.overrides.list("foo").into_iter().map(|(k, o)| (k, o.count)).collect::<Vec<_>>(),
+                        vec![("acme*".to_string(), 3)],
+                        "overrides changed without the admin token"
+                    );
+                }
+            )*
+        }
+    }
+
+    override_auth_tests! {
+        override_auth_list: (http::Method::GET, "/admin/overrides/foo"),
+        override_auth_put: (http::Method::PUT, "/admin/overrides/foo/bob"),
+        override_auth_post: (http::Method::POST, "/admin/overrides/foo/bob"),
+        override_auth_delete: (http::Method::DELETE, "/admin/overrides/foo/acme*"),
+    }
+
+    #[test]
+    async fn test_collections_admin() {
+        let data = web::Data::new(Handler::new(
+            "tenant=100:1 minute".to_string().try_into().expect("invalid config"),
+        ));
+        let coll = |name: &str| {
+            test::TestRequest::default()
+                .param("collection", name.to_string())
+                .to_http_request()
+        };
+        let put = |name: &str, body: &'static str| {
+            Handler::put_collection(data.clone(), Admin, coll(name), web::Bytes::from_static(body.as_bytes()))
+        };
+        let hit = |name: &'static str| {
+            let data = data.clone();
+            async move {
+                let req = test::TestRequest::default()
+                    .param("collection", name)
+                    .param("key", "acme.alice")
+                    .to_http_request();
+                let parsed: Response = parse_body(Handler::handle(data, req, web::Bytes::new()).await);
+                (parsed.limit, parsed.remaining)
+            }
+        };
+
+        let created = put("api", r#"{"count": 5, "window": "1 minute", "algorithm": "sliding_log", "layers": ["tenant/."]}"#)
+            .await
+            .expect("unexpected handler error");
+        assert_eq!(created.status(), http::StatusCode::CREATED, "collection not created");
+        assert_eq!(hit("api").await, (5, 4), "created collection not served");
+
+        let updated = put("api", r#"{"count": 3, "window": "1 minute", "algorithm": "sliding_log", "layers": ["tenant/."]}"#)
+            .await
+            .expect("unexpected handler error");
+        assert_eq!(updated.status(), http::StatusCode::OK, "collection not updated");
+        assert_eq!(hit("api").await, (3, 1), "counts not kept on update");
+
+        let got: config::FileCollection =
+            parse_body(Handler::get_collection(data.clone(), Admin, coll("api")).await);
+        assert_eq!(
+            got.into_rate("api".to_string()).map(|c| c.count),
+            Ok(3),
+            "collection did not match"
+        );
+        let listed: HashMap<String, serde_json::Value> =
+            parse_body(Handler::list_collections(data.clone(), Admin).await);
+        assert_eq!(listed.len(), 2, "collections not listed");
+
+        // tenant is still a layer of api
+        let err = Handler::delete_collection(data.clone(), Admin, coll("tenant"))
+            .await
+            .expect_err("deleted a layer in use")
+            .error_response();
+        assert_eq!(err.status(), http::StatusCode::BAD_REQUEST);
+
+        let removed: RemovedResponse = parse_body(Handler::delete_collection(data.clone(), Admin, coll("api")).await);
+        assert!(removed.removed, "collection not removed");
+        let removed: RemovedResponse = parse_body(Handler::delete_collection(data.clone(), Admin, coll("api")).await);
+        assert!(!removed.removed, "collection removed twice");
+        assert!(data.collections().find("api").is_err(), "deleted collection still served");
+    }
+
+    macro_rules! put_collection_error_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                async fn $name() {
+                    let (body, expected) = $value;
+
+                    let data = web::Data::new(Handler::new(
+                        "foo=1:1 minute".to_string().try_into().expect("invalid config"),
+                    ));
+                    let req = test::TestRequest::default().param("collection", "bar").to_http_request();
+
+                    let err = Handler::put_collection(data.clone(), Admin, req, web::Bytes::from_static(body))
+                        .await
+                        .expect_err("did not error as expected")
+                        .error_response();
+                    assert_eq!(err.status(), http::StatusCode::BAD_REQUEST);
+                    assert!(data.collections().find("bar").is_err(), "invalid collection created");
+
+                    let parsed: HTTPError = parse_body(Ok(err));
+                    assert!(parsed.error.contains(expected), "error does not match expected: {}", parsed.error);
+                }
+            )*
+        }
+    }
+
+    put_collection_error_tests! {
+        put_collection_not_json: (b"count=5", "invalid collection"),
+        put_collection_unknown_field: (br#"{"count": 5, "window": "1 minute", "rate": 5}"#, "unknown field `rate`"),
+        put_collection_bad_window: (br#"{"count": 5, "window": "soon"}"#, "collections.bar.window"),
+        put_collection_unknown_layer: (br#"{"count": 5, "window": "1 minute", "layers": ["baz"]}"#, "unknown layer baz in collection bar"),
+    }
+
+    macro_rules! set_override_error_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                async fn $name() {
+                    let (collection, body, expected) = $value;
+
+                    let data = web::Data::new(Handler::new(
+                        "foo=1:1 minute".to_string().try_into().expect("invalid config"),
+                    ));
+                    let req = test::TestRequest::default()
+                        .param("collection", collection)
+                        .param("key", "acme")
+                        .to_http_request();
+
+                    let err = Handler::set_override(data, Admin, req, web::Bytes::from_static(body))
+                        .await
+                        .expect_err("did not error as expected")
+                        .error_response();
+                    assert_eq!(err.status(), http::StatusCode::BAD_REQUEST);
+
+                    let parsed: HTTPError = parse_body(Ok(err));
+                    assert!(parsed.error.contains(expected), "error does not match expected: {}", parsed.error);
+                }
+            )*
+        }
+    }
+
+    set_override_error_tests! {
+        set_override_unknown_collection: ("bar", br#"{"count": 5}"#, "cannot find cache for collection parameter bar"),
+        set_override_no_count: ("foo", br#"{"window_ms": 1000}"#, "invalid override"),
+        set_override_zero_count: ("foo", br#"{"count": 0}"#, "must be at least 1"),
+        set_override_zero_window: ("foo", br#"{"count": 5, "window_ms": 0}"#, "must be at least 1"),
+        set_override_past_ttl: ("foo", br#"{"count": 5, "window_ms": 3600000}"#, "ttl of 60 seconds"),
+    }
+
+    macro_rules! batch_error_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                async fn $name() {
+                    let body: &'static str = $value;
+
+                    let data = batch_handler();
+                    let req = test::TestRequest::with_uri("http://localhost/batch").to_http_request();
+                    let err = Handler::batch(data.clone(), req, web::Bytes::from(body))
+                        .await
+                        .expect_err("did not error as expected")
+                        .error_response();
+                    assert_eq!(err.status(), http::StatusCode::BAD_REQUEST);
+
+                    // invalid batches are not counted at all
+                    let req = test::TestRequest::with_uri("http://localhost")
+                        .param("collection", "user")
+                        .param("key", "a")
+                        .to_http_request();
+                    let parsed: Response = parse_body(Handler::peek(data, req, web::Bytes::new()).await);
+                    assert_eq!(parsed.count, 0, "invalid batch counted");
+                }
+            )*
+        }
+    }
+
+    batch_error_tests! {
+        batch_error_empty: "[]",
+        batch_error_invalid: r#"{"collection": "user", "key": "a"}"#,
+        batch_error_unknown_collection: r#"[{"collection": "user", "key": "a"}, {"collection": "bar", "key": "a"}]"#,
+        batch_error_zero_cost: r#"[{"collection": "user", "key": "a"}, {"collection": "org", "key": "a", "cost": 0}]"#,
+        batch_error_cost_over_limit: r#"[{"collection": "user", "key": "a"}, {"collection": "org", "key": "a", "cost": 2}]"#,
+    }
+
+    macro_rules! handle_headers_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                async fn $name() {
+                    let (algorithm, burst, hits, expected) = $value;
+
+                    let linker = config::Config{
+                        configs: HashMap::from([
+                            ("foo".to_string(),
+                            config::RateConfig{
+                                name: "foo".to_string(),
+                                count: 2,
+                                window: std::time::Duration::from_secs(60),
+                                algorithm,
+                                burst,
+                                ..Default::default()
+                            }),
+                        ]),
+                        ttl_seconds: config::HARDCODED_TTL,
+                        ..Default::default()
+                    };
+
+                    let data = web::Data::new(Handler::new(linker));
+                    let mut resp = None;
+                    for _ in 0..hits {
+                        resp = Some(
+                            do_test_request("http://localhost", Some("foobar"), Some("foo"), data.clone())
+                                .await
+                                .expect("unexpected handler error"),
+                        );
+                    }
+
+                    let resp = resp.expect("no hits made");
+                    let headers = resp.headers();
+                    for (name, value) in expected {
+                        let actual = headers.get(name).map(|v| v.to_str().expect("invalid header"));
+                        assert_eq!(actual, value, "unexpected value for header {}", name);
+                    }
+                }
+            )*
+        }
+    }
+
+    handle_headers_tests! {
+        handle_headers_allowed: (cache::Algorithm::SlidingLog, 0, 1, [
+            (RATELIMIT_LIMIT, Some("2")),
+            (RATELIMIT_REMAINING, Some("1")),
+            (RATELIMIT_RESET, Some("60")),
+            (RATELIMIT_POLICY, Some("2;w=60")),
+            (header::RETRY_AFTER.as_str(), None),
+        ]),
+        handle_headers_denied: (cache::Algorithm::SlidingLog, 0, 3, [
+            (RATELIMIT_LIMIT, Some("2")),
+            (RATELIMIT_REMAINING, Some("0")),
+            (RATELIMIT_RESET, Some("60")),
+            (RATELIMIT_POLICY, Some("2;w=60")),
+            (header::RETRY_AFTER.as_str(), Some("60")),
+        ]),
+        handle_headers_burst: (cache::Algorithm::TokenBucket, 5, 1, [
+            (RATELIMIT_LIMIT, Some("5")),
+            (RATELIMIT_REMAINING, Some("4")),
+            (RATELIMIT_RESET, Some("30")),
+            (RATELIMIT_POLICY, Some("2;w=60;burst=5")),
+            (header::RETRY_AFTER.as_str(), None),
+        ]),
+    }
+
+    macro_rules! handle_reject_tests {
+        ($($name:ident: $value:expr,)*) => {
+            $(
+                #[test]
+                async fn $name() {
+                    let (reject, uri, codes) = $value;
+
+                    let linker = config::Config{
+                        configs: HashMap::from([
+                            ("foo".to_string(),
+                            config::RateConfig{
+                                name: "foo".to_string(),
+                                count: 1,
+                                window: std::time::Duration::from_secs(60),
+                                reject,
+                                ..Default::default()
+                            }),
+                        ]),
+                        ttl_seconds: config::HARDCODED_TTL,
+                        ..Default::default()
+                    };
+
+                    let data = web::Data::new(Handler::new(linker));
+                    let mut resps = Vec::new();
+                    for _ in 0..2 {
+                        let resp = do_test_request(uri, Some("foobar"), Some("foo"), data.clone())
+                            .await
+                            .expect("unexpected handler error");
+                        resps.push(resp);
+                    }
+
+                    let actual = resps.iter().map(|r| r.status()).collect::<Vec<_>>();
+                    assert_eq!(actual, codes, "unexpected status codes");
+
+                    let denied = resps.pop().expect("no responses");
+                    if denied.status() == http::StatusCode::TOO_MANY_REQUESTS {
+                        assert!(denied.headers().contains_key(header::RETRY_AFTER), "no Retry-After");
+                        let parsed: HTTPError = parse_body(Ok(denied));
+                        assert!(
+                            parsed.error.contains("rate limit exceeded for collection foo"),
+                            "body does not match expected: {}",
+                            parsed.error
+                        );
+                    }
+                }
+            )*
+        }
+    }
+
+    handle_reject_tests! {
+        handle_reject_default: (false, "http://localhost", [http::StatusCode::OK, http::StatusCode::OK]),
+        handle_reject_collection: (true, "http://localhost", [http::StatusCode::OK, http::StatusCode::TOO_MANY_REQUESTS]),
+        handle_reject_request: (false, "http://localhost?reject=true", [http::StatusCode::OK, http::StatusCode::TOO_MANY_REQUESTS]),
+        handle_reject_request_override: (true, "http://localhost?reject=false", [http::StatusCode::OK, http::StatusCode::OK]),
+    }
+
+    #[test]
+    async fn test_reject_invalid() {
+        let linker = config::Config{
+            configs: HashMap::from([
+                ("foo".to_string(),
+                config::RateConfig{
+                    name: "foo".to_string(),
+                    count: 1,
+                    window: std::time::Duration::from_secs(60),
+                    ..Default::default()
+                }),
+            ]),
+            ttl_seconds: config::HARDCODED_TTL,
+            ..Default::default()
+        };
+
+        let data = web::Data::new(Handler::new(linker));
+        let err = do_test_request("http://localhost?reject=maybe", Some("foobar"), Some("foo"), data)
+            .await
+            .expect_err("did not error as expected")
+            .error_response();
+        assert_eq!(err.status(), http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    async fn test_auth() {
+        let linker = config::Config{
+            configs: HashMap::from([
+                ("foo".to_string(),
+                config::RateConfig{
+                    name: "foo".to_string(),
+                    count: 1,
+                    window: std::time::Duration::from_secs(60),
+                    key: auth::KeySource::Header("X-Api-Key".to_string()),
+                    ..Default::default()
+                }),
+                ("bar".to_string(),
+                config::RateConfig{
+                    name: "bar".to_string(),
+                    count: 1,
+                    window: std::time::Duration::from_secs(60),
+                    ..Default::default()
+                }),
+            ]),
+            ttl_seconds: config::HARDCODED_TTL,
+            ..Default::default()
+        };
+
+        let data = web::Data::new(Handler::new(linker));
+        let auth = |collection: Option<&'static str>, headers: Vec<(&'static str, &'static str)>| {
+            let mut req = test::TestRequest::with_uri("http://localhost/auth");
+            if let Some(c) = collection {
+                req = req.param("collection", c);
+            }
+            for h in headers {
+                req = req.insert_header(h);
+            }
+            Handler::auth(data.clone(), req.to_http_request())
+        };
+
+        let status = |resp: Result<HttpResponse, actix_web::Error>| match resp {
+            Ok(r) => r.status(),
+            Err(e) => e.error_response().status(),
+        };
+
+        let cases = [
+            ("header key", Some("foo"), vec![("X-Api-Key", "abc")], http::StatusCode::OK),
+            ("header key limited", Some("foo"), vec![("X-Api-Key", "abc")], http::StatusCode::TOO_MANY_REQUESTS),
+            ("other header key", Some("foo"), vec![("X-Api-Key", "def")], http::StatusCode::OK),
+            ("missing header key", Some("foo"), vec![], http::StatusCode::BAD_REQUEST),
+            (
+                "collection from original URI",
+                None,
+                vec![("X-Original-URI", "/bar/baz"), ("X-Forwarded-For", "192.168.0.1")],
+                http::StatusCode::OK,
+            ),
+            (
+                "collection from header",
+                None,
+                vec![(auth::COLLECTION_HEADER, "bar"), ("X-Forwarded-For", "192.168.0.1")],
+                http::StatusCode::TOO_MANY_REQUESTS,
+            ),
+            ("unknown collection", None, vec![("X-Original-URI", "/baz")], http::StatusCode::BAD_REQUEST),
+        ];
+
+        for (name, collection, headers, code) in cases {
+            assert_eq!(status(auth(collection, headers).await), code, "unexpected status for {}", name);
+        }
+    }
+
+    fn parse_body<T: serde::de::DeserializeOwned>(
+        resp: Result<HttpResponse, actix_web::Error>,
+    ) -> T {
+        let body = resp
+            .expect("unexpected handler error")
+            .into_body()
+            .try_into_bytes()
+            .expect("unable to read body");
+        serde_json::from_slice(&body[..]).expect("cannot parse body")
+    }
+
     async fn do_test_request(
         uri: &'static str,
         key: Option<&'static str>,
@@ -328,6 +2763,6 @@ mod test {
             req = req.param("key", k);
         }
 
-        Handler::handle(data, req.to_http_request()).await
+        Handler::handle(data, req.to_http_request(), web::Bytes::new()).await
     }
 }
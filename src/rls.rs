@@ -0,0 +1,466 @@
+use crate::{
+    cache,
+    config::RateConfig,
+    rest::{Collections, Handler, Hit},
+};
+use actix_web::web;
+use std::sync::Arc;
+use tracing::{event, instrument, Level};
+
+pub mod pb {
+    tonic::include_proto!("envoy.service.ratelimit.v3");
+}
+
+use pb::{
+    rate_limit_response::{rate_limit::Unit, Code, DescriptorStatus, RateLimit},
+    rate_limit_service_server::{RateLimitService, RateLimitServiceServer},
+    HeaderValue, RateLimitDescriptor, RateLimitRequest, RateLimitResponse,
+};
+
+// joins descriptor entry values into a key
+const KEY_SEPARATOR: &str = ":";
+
+/// Service answers Envoy's ShouldRateLimit checks against the same caches as the REST API.
+///
+/// Each descriptor is checked on its own: the key of its first entry names the collection, and the
+/// values of all its entries are joined into the key, so [("api_key", "abc"), ("method", "GET")]
+/// counts against key abc:GET in collection api_key. Descriptors for unknown collections are OK,
+/// as Envoy expects for descriptors without a limit.
+#[derive(Debug)]
+pub struct Service {
+    handler: web::Data<Handler>,
+}
+
+impl Service {
+    pub fn new(handler: web::Data<Handler>) -> Service {
+        Service { handler }
+    }
+
+    pub fn into_server(self) -> RateLimitServiceServer<Service> {
+        RateLimitServiceServer::new(self)
+    }
+
+    // resolve finds the descriptor's collection and key without counting it, returning None if it
+    // has no limit
+    fn resolve<'a>(
+        &self,
+        cols: &'a Collections,
+        descriptor: &RateLimitDescriptor,
+        hits: u64,
+    ) -> Result<Option<Resolved<'a>>, tonic::Status> {
+        let Some(coll) = descriptor.entries.first().map(|e| e.key.as_str()) else {
+            return Ok(None);
+        };
+        let Ok((cache, cfg)) = cols.find(coll) else {
+            return Ok(None);
+        };
+
+        // Envoy has no way to release leases, so they would be held until they time out
+        if cfg.algorithm == cache::Algorithm::Concurrency {
+            return Err(tonic::Status::invalid_argument(format!(
+                "collection {} uses leases, which cannot be released over RLS",
+                cfg.name
+            )));
+        }
+
+        let key = descriptor
+            .entries
+            .iter()
+            .map(|e| e.value.as_str())
+            .collect::<Vec<_>>()
+            .join(KEY_SEPARATOR);
+        let cost = descriptor.hits_addend.unwrap_or(hits).max(1);
+        let limit = self.handler.limit(cfg, &key);
+
+        // a cost over the limit could never be allowed, so it is denied without being counted, and
+        // reported with where the key stands
+        let over = Handler::check_cost(cfg, &limit, cost).is_err();
+
+        Ok(Some(Resolved {
+            cache,
+            cfg,
+            key,
+            cost,
+            over,
+        }))
+    }
+
+    // check hits a resolved descriptor's key
+    fn check<'a>(&self, cols: &'a Collections, r: &Resolved<'a>) -> Result<Hit<'a>, tonic::Status> {
+        let (cost, inc) = match r.over {
+            true => (1, false),
+            false => (r.cost, true),
+        };
+        let mut hit = self
+            .handler
+            .hit(cols, r.cache, r.cfg, &r.key, cost, inc)
+            .map_err(|e| {
+                event!(Level::ERROR, message = "can't hit descriptor", error = %e);
+
+                tonic::Status::internal(e.to_string())
+            })?;
+        hit.val.allowed &= !r.over;
+
+        Ok(hit)
+    }
+}
+
+// Resolved is a descriptor's collection and key, ready to be counted
+struct Resolved<'a> {
+    cache: &'a Arc<dyn cache::Cache>,
+    cfg: &'a RateConfig,
+    key: String,
+    cost: u64,
+    over: bool,
+}
+
+#[tonic::async_trait]
+impl RateLimitService for Service {
+    #[instrument(skip(self))]
+    async fn should_rate_limit(
+        &self,
+        request: tonic::Request<RateLimitRequest>,
+    ) -> Result<tonic::Response<RateLimitResponse>, tonic::Status> {
+        let req = request.into_inner();
+        let hits = u64::from(req.hits_addend);
+
+        // every descriptor is checked against the same collections, even if they are reloaded, and
+        // resolved before any is counted so that an invalid one leaves the rest uncounted
+        let cols = self.handler.collections();
+        let resolved = req
+            .descriptors
+            .iter()
+            .map(|d| self.resolve(&cols, d, hits))
+            .collect::<Result<Vec<_>, _>>()?;
+        let checked = resolved
+            .iter()
+            .map(|r| r.as_ref().map(|r| self.check(&cols, r)).transpose())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let statuses = checked
+            .iter()
+            .map(|c| match c {
+                Some(hit) => descriptor_status(hit),
+                None => DescriptorStatus {
+                    code: Code::Ok.into(),
+                    ..Default::default()
+                },
+            })
+            .collect::<Vec<_>>();
+
+        // headers are for the tightest limit, preferring one that denied the request
+        let response_headers_to_add = checked
+            .iter()
+            .flatten()
+            .min_by_key(|hit| (hit.val.allowed, hit.val.remaining))
+            .map(|hit| {
+                Handler::header_values(&hit.limit, &hit.val)
+                    .into_iter()
+                    .map(|(key, value)| HeaderValue {
+                        key: key.to_string(),
+                        value,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let overall_code = match checked.iter().flatten().all(|hit| hit.val.allowed) {
+            true => Code::Ok,
+            false => Code::OverLimit,
+        };
+
+        Ok(tonic::Response::new(RateLimitResponse {
+            overall_code: overall_code.into(),
+            statuses,
+            response_headers_to_add,
+            request_headers_to_add: Vec::new(),
+        }))
+    }
+}
+
+fn descriptor_status(hit: &Hit) -> DescriptorStatus {
+    let (cfg, val) = (hit.cfg, &hit.val);
+    let limit = hit.limit.nth(val.window);
+    let until_reset = val.reset.saturating_sub(cache::local::now());
+
+    DescriptorStatus {
+        code: match val.allowed {
+            true => Code::Ok,
+            false => Code::OverLimit,
+        }
+        .into(),
+        current_limit: Some(RateLimit {
+            name: cfg.name.clone(),
+            requests_per_unit: u32::try_from(limit.capacity()).unwrap_or(u32::MAX),
+            unit: unit(limit.window).into(),
+        }),
+        limit_remaining: u32::try_from(val.remaining).unwrap_or(u32::MAX),
+        duration_until_reset: Some(prost_types::Duration {
+            seconds: (until_reset / 1000) as i64,
+            nanos: ((until_reset % 1000) * 1_000_000) as i32,
+        }),
+    }
+}
+
+// unit is the Envoy unit for a window in milliseconds, which is unknown for windows that are not
+// exactly one unit long
+fn unit(window: u64) -> Unit {
+    match window {
+        1_000 => Unit::Second,
+        60_000 => Unit::Minute,
+        3_600_000 => Unit::Hour,
+        86_400_000 => Unit::Day,
+        604_800_000 => Unit::Week,
+        _ => Unit::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod rls_tests {
+
+    use super::*;
+    use crate::config::{self, RateConfig};
+    use pb::{rate_limit_descriptor::Entry, rate_limit_service_client::RateLimitServiceClient};
+    use std::collections::HashMap;
+    use tonic::transport::Channel;
+
+    // serve starts the service on a random local port, returning a client for it
+    async fn serve(configs: Vec<RateConfig>) -> RateLimitServiceClient<Channel> {
+        let handler = Handler::new(config::Config {
+            configs: configs.into_iter().map(|c| (c.name.clone(), c)).collect::<HashMap<_, _>>(),
+            ttl_seconds: config::HARDCODED_TTL,
+            ..Default::default()
+        });
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("unable to bind listener");
+        let addr = listener.local_addr().expect("no listener address");
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(Service::new(web::Data::new(handler)).into_server())
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+
+        RateLimitServiceClient::connect(format!("http://{}", addr))
+            .await
+            .expect("unable to connect to server")
+    }
+
+    fn descriptor(entries: &[(&str, &str)]) -> RateLimitDescriptor {
+        RateLimitDescriptor {
+            entries: entries
+                .iter()
+                .map(|(k, v)| Entry {
+                    key: k.to_string(),
+                    value: v.to_string(),
+                })
+                .collect(),
+            hits_addend: None,
+        }
+    }
+
+    // testcase-based rather than macro-based to simplify state across cases
+    #[tokio::test]
+    async fn test_should_rate_limit() {
+        struct TestCase {
+            name: &'static str,
+            descriptors: Vec<RateLimitDescriptor>,
+            hits_addend: u32,
+            overall: Code,
+            codes: Vec<Code>,
+            remaining: &'static str,
+        }
+
+        let mut client = serve(vec![RateConfig {
+            name: "foo".to_string(),
+            count: 2,
+            window: std::time::Duration::from_secs(60),
+            algorithm: cache::Algorithm::SlidingLog,
+            ..Default::default()
+        }])
+        .await;
+
+        let testcases = vec![
+            TestCase {
+                name: "first hit",
+                descriptors: vec![descriptor(&[("foo", "a")])],
+                hits_addend: 0,
+                overall: Code::Ok,
+                codes: vec![Code::Ok],
+                remaining: "1",
+            },
+            TestCase {
+                name: "hits addend over remaining",
+                descriptors: vec![descriptor(&[("foo", "a")])],
+                hits_addend: 2,
+                overall: Code::OverLimit,
+                codes: vec![Code::OverLimit],
+                remaining: "1",
+            },
+            TestCase {
+                name: "entries joined into key",
+                descriptors: vec![descriptor(&[("foo", "a"), ("method", "GET")])],
+                hits_addend: 1,
+                overall: Code::Ok,
+                codes: vec![Code::Ok],
+                remaining: "1",
+            },
+            TestCase {
+                name: "tightest descriptor in headers",
+                descriptors: vec![descriptor(&[("foo", "b")]), descriptor(&[("foo", "a")])],
+                hits_addend: 1,
+                overall: Code::Ok,
+                codes: vec![Code::Ok, Code::Ok],
+                remaining: "0",
+            },
+            TestCase {
+                name: "one descriptor over limit",
+                descriptors: vec![descriptor(&[("foo", "b")]), descriptor(&[("foo", "a")])],
+                hits_addend: 1,
+                overall: Code::OverLimit,
+                codes: vec![Code::Ok, Code::OverLimit],
+                remaining: "0",
+            },
+        ];
+
+        for tc in testcases {
+            let resp = client
+                .should_rate_limit(RateLimitRequest {
+                    domain: "pyre".to_string(),
+                    descriptors: tc.descriptors,
+                    hits_addend: tc.hits_addend,
+                })
+                .await
+                .unwrap_or_else(|e| panic!("unexpected error for {}: {}", tc.name, e))
+                .into_inner();
+
+            assert_eq!(resp.overall_code(), tc.overall, "overall code did not match for {}", tc.name);
+            assert_eq!(
+                resp.statuses.iter().map(|s| s.code()).collect::<Vec<_>>(),
+                tc.codes,
+                "descriptor codes did not match for {}",
+                tc.name
+            );
+
+            let header = |name: &str| {
+                resp.response_headers_to_add
+                    .iter()
+                    .find(|h| h.key == name)
+                    .map(|h| h.value.as_str())
+            };
+            assert_eq!(header("RateLimit-Remaining"), Some(tc.remaining), "remaining did not match for {}", tc.name);
+            assert_eq!(
+                header("Retry-After").is_some(),
+                tc.overall == Code::OverLimit,
+                "Retry-After did not match for {}",
+                tc.name
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_descriptor_status() {
+        let mut client = serve(vec![RateConfig {
+            name: "foo".to_string(),
+            count: 5,
+            window: std::time::Duration::from_secs(60),
+            algorithm: cache::Algorithm::SlidingLog,
+            ..Default::default()
+        }])
+        .await;
+
+        let mut over = descriptor(&[("foo", "b")]);
+        over.hits_addend = Some(6);
+        let resp = client
+            .should_rate_limit(RateLimitRequest {
+                domain: "pyre".to_string(),
+                descriptors: vec![descriptor(&[("foo", "a")]), descriptor(&[("bar", "a")]), over],
+                hits_addend: 2,
+            })
+            .await
+            .expect("unexpected error")
+            .into_inner();
+
+        let limited = &resp.statuses[0];
+        let limit = limited.current_limit.as_ref().expect("no current limit");
+        assert_eq!(
+            (limit.name.as_str(), limit.requests_per_unit, limit.unit(), limited.limit_remaining),
+            ("foo", 5, Unit::Minute, 3)
+        );
+        assert!(
+            limited.duration_until_reset.expect("no duration until reset").seconds <= 60,
+            "reset after window"
+        );
+
+        assert_eq!(resp.statuses[1].code(), Code::Ok, "unknown collection limited");
+        assert_eq!(resp.statuses[1].current_limit, None, "unknown collection has a limit");
+
+        assert_eq!(resp.statuses[2].code(), Code::OverLimit, "cost over limit allowed");
+        assert_eq!(resp.statuses[2].limit_remaining, 5, "cost over limit counted");
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_rejected() {
+        let mut client = serve(vec![RateConfig {
+            name: "foo".to_string(),
+            count: 5,
+            window: std::time::Duration::from_secs(60),
+            algorithm: cache::Algorithm::Concurrency,
+            ..Default::default()
+        }])
+        .await;
+
+        let err = client
+            .should_rate_limit(RateLimitRequest {
+                domain: "pyre".to_string(),
+                descriptors: vec![descriptor(&[("foo", "a")])],
+                hits_addend: 1,
+            })
+            .await
+            .expect_err("concurrency collection allowed");
+
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_descriptor_counts_none() {
+        let mut client = serve(vec![
+            RateConfig {
+                name: "foo".to_string(),
+                count: 5,
+                window: std::time::Duration::from_secs(60),
+                ..Default::default()
+            },
+            RateConfig {
+                name: "bar".to_string(),
+                count: 5,
+                window: std::time::Duration::from_secs(60),
+                algorithm: cache::Algorithm::Concurrency,
+                ..Default::default()
+            },
+        ])
+        .await;
+
+        let err = client
+            .should_rate_limit(RateLimitRequest {
+                domain: "pyre".to_string(),
+                descriptors: vec![descriptor(&[("foo", "a")]), descriptor(&[("bar", "a")])],
+                hits_addend: 1,
+            })
+            .await
+            .expect_err("concurrency collection allowed");
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+        let resp = client
+            .should_rate_limit(RateLimitRequest {
+                domain: "pyre".to_string(),
+                descriptors: vec![descriptor(&[("foo", "a")])],
+                hits_addend: 1,
+            })
+            .await
+            .expect("unable to check")
+            .into_inner();
+        assert_eq!(resp.statuses[0].limit_remaining, 4, "rejected batch counted");
+    }
+}